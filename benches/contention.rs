@@ -0,0 +1,91 @@
+//! Measures how requests to many unrelated hosts contend on the middleware's single state map.
+//!
+//! Every request goes to a host of its own and is answered in place with a `200`, so the time
+//! spent is the middleware's: the lookups that miss, and recording that nothing is stored. The
+//! same requests are sent from one worker thread and from several, with and without the
+//! middleware. If the map's lock serialized unrelated requests, the middleware's throughput
+//! would stop scaling with the threads while the bare client's keeps growing.
+//!
+//! Run with `cargo bench --bench contention`.
+
+use std::time::{Duration, Instant};
+
+use http::Extensions;
+use reqwest_middleware::{ClientBuilder, ClientWithMiddleware, Middleware, Next, Result};
+use reqwest_retry_after::RetryAfterMiddleware;
+
+const TASKS: usize = 64;
+const HOSTS: usize = 1_000;
+const REQUESTS: usize = 200;
+
+/// Answers every request with a `200`, without sending it.
+struct Ok200;
+
+#[async_trait::async_trait]
+impl Middleware for Ok200 {
+    async fn handle(
+        &self,
+        _: reqwest::Request,
+        _: &mut Extensions,
+        _: Next<'_>,
+    ) -> Result<reqwest::Response> {
+        Ok(http::Response::new(reqwest::Body::from("")).into())
+    }
+}
+
+fn client(with_middleware: bool) -> ClientWithMiddleware {
+    let mut client = ClientBuilder::new(reqwest::Client::new());
+    if with_middleware {
+        client = client.with(RetryAfterMiddleware::new());
+    }
+    client.with(Ok200).build()
+}
+
+fn run(threads: usize, with_middleware: bool) -> Duration {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(threads)
+        .build()
+        .unwrap();
+    let client = client(with_middleware);
+    runtime.block_on(async {
+        let start = Instant::now();
+        let tasks: Vec<_> = (0..TASKS)
+            .map(|task| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    for request in 0..REQUESTS {
+                        let host = (task * REQUESTS + request) % HOSTS;
+                        let url = format!("http://host-{host}.example/");
+                        client.get(url).send().await.unwrap();
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        start.elapsed()
+    })
+}
+
+fn main() {
+    let max_threads = std::thread::available_parallelism().map_or(4, usize::from);
+    let total = (TASKS * REQUESTS) as f64;
+
+    println!("threads  bare client (req/s)  with middleware (req/s)  ratio");
+    let mut threads = 1;
+    loop {
+        // warm up the runtime and the allocator before measuring
+        run(threads, true);
+        let bare = total / run(threads, false).as_secs_f64();
+        let middleware = total / run(threads, true).as_secs_f64();
+        println!(
+            "{threads:>7}  {bare:>19.0}  {middleware:>23.0}  {:>5.2}",
+            middleware / bare
+        );
+        if threads >= max_threads {
+            break;
+        }
+        threads = (threads * 2).min(max_threads);
+    }
+}
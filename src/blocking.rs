@@ -0,0 +1,162 @@
+//! `Retry-After` support for [`reqwest::blocking`] clients.
+//!
+//! Middlewares only wrap async clients, so [`RetryAfterClient`] wraps a blocking one instead,
+//! and blocks the calling thread for as long as a `Retry-After` asks. It takes its
+//! configuration and state from a [`RetryAfterMiddleware`], so that blocking and async clients
+//! of the same API can honor each other's `Retry-After`s. Requires the `blocking` feature.
+//!
+//! Only the parts of the configuration that need no waiting apply: stores, coordinators,
+//! detectors, observers, the sleeper, retries, recovery probes and dry-run mode are left out.
+
+use std::{sync::Arc, thread, time::Duration};
+
+use reqwest::{
+    blocking::{Client, Request, Response},
+    IntoUrl,
+};
+
+use crate::RetryAfterMiddleware;
+
+/// A [`reqwest::blocking::Client`] that honors `Retry-After`.
+///
+/// ```no_run
+/// use reqwest_retry_after::{blocking::RetryAfterClient, RetryAfterMiddleware, Scope};
+///
+/// let middleware = RetryAfterMiddleware::builder()
+///     .with_scope(Scope::Host)
+///     .build();
+/// let client = RetryAfterClient::with_middleware(reqwest::blocking::Client::new(), middleware);
+/// let res = client.get("https://example.com")?;
+/// # Ok::<_, reqwest_middleware::Error>(())
+/// ```
+#[derive(Clone)]
+pub struct RetryAfterClient {
+    client: Client,
+    middleware: RetryAfterMiddleware,
+    sleep: Arc<dyn Fn(Duration) + Send + Sync>,
+}
+
+impl RetryAfterClient {
+    /// Wraps `client`, with the default configuration.
+    pub fn new(client: Client) -> Self {
+        Self::with_middleware(client, RetryAfterMiddleware::new())
+    }
+
+    /// Wraps `client`, with the configuration and the state of `middleware`.
+    pub fn with_middleware(client: Client, middleware: RetryAfterMiddleware) -> Self {
+        Self {
+            client,
+            middleware,
+            sleep: Arc::new(thread::sleep),
+        }
+    }
+
+    /// Waits with `sleep` instead of [`std::thread::sleep`], for example to advance a
+    /// [`ManualClock`](crate::testing::ManualClock) in tests.
+    pub fn with_sleep(mut self, sleep: impl Fn(Duration) + Send + Sync + 'static) -> Self {
+        self.sleep = Arc::new(sleep);
+        self
+    }
+
+    /// Returns the middleware the client takes its configuration and state from, to query that
+    /// state.
+    pub fn middleware(&self) -> &RetryAfterMiddleware {
+        &self.middleware
+    }
+
+    /// Sends a `GET` request to `url`.
+    pub fn get(&self, url: impl IntoUrl) -> reqwest_middleware::Result<Response> {
+        self.execute(self.client.get(url).build()?)
+    }
+
+    /// Sends `req`, after blocking until any `Retry-After` that applies to it has passed.
+    ///
+    /// Fails like the middleware does, with an [`ExcessiveDelay`](crate::ExcessiveDelay) if
+    /// the wait is longer than a bound set to fail, and with a
+    /// [`RetryAfterViolation`](crate::RetryAfterViolation) if the response breaks strict
+    /// parsing set to fail.
+    pub fn execute(&self, req: Request) -> reqwest_middleware::Result<Response> {
+        let middleware = &self.middleware;
+        let tracker = &middleware.tracker;
+        // key extractors take async requests
+        let mut probe = reqwest::Request::new(req.method().clone(), req.url().clone());
+        *probe.headers_mut() = req.headers().clone();
+        let key = tracker.key_for(&probe);
+
+        let wait = tracker.bounded_wait(&key, tracker.clock.now(), None);
+        if let Some((wait, _)) = wait.map_err(reqwest_middleware::Error::middleware)? {
+            (self.sleep)(wait);
+            middleware.waited(req.url(), wait);
+        }
+
+        let res = self.client.execute(req)?;
+        let recorded = tracker
+            .observe_headers(
+                &key,
+                res.status(),
+                res.headers(),
+                tracker.clock.now(),
+                |violation| middleware.violated(res.url(), violation),
+            )
+            .map_err(reqwest_middleware::Error::middleware)?;
+        middleware.recorded(res.url(), &recorded);
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{Arc, Mutex},
+        time::Duration,
+    };
+
+    use httpmock::{Method::GET, MockServer};
+
+    use super::RetryAfterClient;
+    use crate::{testing::ManualClock, RetryAfterMiddleware};
+
+    #[test]
+    fn blocking_client() {
+        let server = MockServer::start();
+        let mut throttled = server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "30").body("");
+        });
+
+        let clock = ManualClock::new();
+        let middleware = RetryAfterMiddleware::builder()
+            .with_clock(clock.clone())
+            .build();
+        let sleeps = Arc::new(Mutex::new(Vec::new()));
+        let client =
+            RetryAfterClient::with_middleware(reqwest::blocking::Client::new(), middleware)
+                .with_sleep({
+                    let sleeps = sleeps.clone();
+                    move |duration| {
+                        sleeps.lock().unwrap().push(duration);
+                        clock.advance(duration);
+                    }
+                });
+
+        let url = server.url("/");
+        client.get(&url).unwrap();
+        assert_eq!(
+            client.middleware().wait_remaining(&url.parse().unwrap()),
+            Some(Duration::from_secs(30))
+        );
+
+        throttled.delete();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(200).body("");
+        });
+        client.get(&url).unwrap();
+        client.get(&url).unwrap();
+        assert_eq!(*sleeps.lock().unwrap(), [Duration::from_secs(30)]);
+        assert_eq!(
+            client.middleware().wait_remaining(&url.parse().unwrap()),
+            None
+        );
+    }
+}
@@ -0,0 +1,745 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, SystemTime},
+};
+
+use http::{HeaderName, StatusCode};
+use reqwest::Url;
+
+use crate::{
+    clock::{Clock, Sleeper},
+    coordinator::Coordinator,
+    detect::ThrottleDetector,
+    group::KeyGroup,
+    observer::RetryAfterObserver,
+    rate_limit::RateLimitSource,
+    store::RetryAfterStore,
+    Backoff, DeprecationNotice, HeaderFormat, Jitter, KeyExtractor, OnConflict, OnExcessiveDelay,
+    OnMultipleFields, OnViolation, RetryAfterMiddleware, RetryAfterState, RetryAfterViolation,
+    Scope, State,
+};
+
+/// Configures a [`RetryAfterMiddleware`], created with [`RetryAfterMiddleware::builder`].
+pub struct RetryAfterMiddlewareBuilder {
+    middleware: RetryAfterMiddleware,
+}
+
+impl RetryAfterMiddlewareBuilder {
+    pub(crate) fn from_state(state: Arc<State>) -> Self {
+        Self {
+            middleware: RetryAfterMiddleware::from_state(state),
+        }
+    }
+
+    /// Builds the configured middleware.
+    pub fn build(self) -> RetryAfterMiddleware {
+        self.middleware
+    }
+
+    /// Shares the `Retry-After` state of the middleware with every other middleware built with
+    /// the same `name`, anywhere in the process.
+    ///
+    /// Independent subsystems that each build their own client can use this to coordinate
+    /// when they talk to the same upstream, without passing the middleware around. Only the
+    /// state is shared; every instance keeps its own configuration.
+    ///
+    /// ```
+    /// use reqwest_retry_after::RetryAfterMiddleware;
+    ///
+    /// let uploads = RetryAfterMiddleware::builder().named("github").build();
+    /// let issues = RetryAfterMiddleware::builder().named("github").build();
+    /// ```
+    pub fn named(mut self, name: &str) -> Self {
+        static REGISTRY: OnceLock<Mutex<HashMap<String, Arc<State>>>> = OnceLock::new();
+
+        self.middleware.tracker.state = REGISTRY
+            .get_or_init(Default::default)
+            .lock()
+            .unwrap()
+            .entry(name.to_owned())
+            .or_default()
+            .clone();
+        self
+    }
+
+    /// Configures the middleware for a Kubernetes API server.
+    ///
+    /// Under API Priority and Fairness, the API server answers requests over their priority
+    /// level's share with `429 Too Many Requests` and `Retry-After: 1`, and may send a
+    /// `Retry-After` along with server errors, which clients such as `client-go` honor too.
+    /// The preset:
+    ///
+    /// - stores values per [host](Scope::Host), so that one cluster doesn't hold back another;
+    /// - honors the `Retry-After` of `429` responses and of every `5xx`;
+    /// - backs off exponentially from one second up to ten when a throttled response has no
+    ///   `Retry-After`;
+    /// - retries throttled requests up to three times, so that controllers only see the
+    ///   throttle if it lasts.
+    ///
+    /// Later calls to the builder override any of these.
+    ///
+    /// ```
+    /// use reqwest_retry_after::RetryAfterMiddleware;
+    ///
+    /// let middleware = RetryAfterMiddleware::builder().kubernetes().build();
+    /// ```
+    pub fn kubernetes(self) -> Self {
+        self.with_scope(Scope::Host)
+            .with_retry_after_statuses(|status: StatusCode| {
+                status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+            })
+            .with_fallback_backoff(Backoff::Exponential {
+                base: Duration::from_secs(1),
+                max: Duration::from_secs(10),
+            })
+            .with_retries(3)
+    }
+
+    /// Uses `state` instead of a state of its own, sharing it with every other middleware that
+    /// does.
+    ///
+    /// See [`RetryAfterState`](crate::RetryAfterState).
+    pub fn with_state(mut self, state: RetryAfterState) -> Self {
+        self.middleware.tracker.state = state.0;
+        self
+    }
+
+    /// Uses `clock` as the source of the current time, instead of
+    /// [`SystemClock`](crate::clock::SystemClock).
+    ///
+    /// [`MonotonicClock`](crate::clock::MonotonicClock) keeps waits from being stretched or
+    /// skipped when the system time is adjusted.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.middleware.tracker.clock = Arc::new(clock);
+        self
+    }
+
+    /// Uses `sleeper` to wait out `Retry-After` values, instead of the default
+    /// [`TokioSleeper`](crate::clock::TokioSleeper), or `FuturesTimerSleeper` with the
+    /// `futures-timer` feature.
+    pub fn with_sleeper(mut self, sleeper: impl Sleeper + 'static) -> Self {
+        self.middleware.sleeper = Arc::new(sleeper);
+        self
+    }
+
+    /// Bounds how long a request may wait for a stored `Retry-After`, and decides what happens
+    /// to requests that would wait longer.
+    ///
+    /// A misbehaving server can ask for a day-long wait. With a bound, such requests are sent
+    /// early or fail with an [`ExcessiveDelay`](crate::ExcessiveDelay) error instead of
+    /// stalling, as `policy` says.
+    /// A [`MaxWaitOverride`](crate::MaxWaitOverride) on the request takes the place of `max`
+    /// for that request. Its wait is truncated, unless `policy` is
+    /// [`OnExcessiveDelay::Error`](crate::OnExcessiveDelay::Error), in which case it fails.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use reqwest_retry_after::{OnExcessiveDelay, RetryAfterMiddleware};
+    ///
+    /// let middleware = RetryAfterMiddleware::builder()
+    ///     .with_max_wait(Duration::from_secs(60), OnExcessiveDelay::Error)
+    ///     .build();
+    /// ```
+    pub fn with_max_wait(self, max: Duration, policy: OnExcessiveDelay) -> Self {
+        self.middleware.tracker.config.set_max_wait(max, policy);
+        self
+    }
+
+    /// Fails requests with a stored `Retry-After` right away, instead of waiting for it.
+    ///
+    /// The error is an [`ExcessiveDelay`](crate::ExcessiveDelay) that carries the remaining
+    /// wait, so that interactive callers can decide for themselves whether and when to try
+    /// again. This is a shorthand for a [`with_max_wait`](Self::with_max_wait) of zero with
+    /// [`OnExcessiveDelay::Error`].
+    ///
+    /// ```no_run
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// use reqwest_middleware::ClientBuilder;
+    /// use reqwest_retry_after::{ExcessiveDelay, RetryAfterMiddleware};
+    ///
+    /// let client = ClientBuilder::new(reqwest::Client::new())
+    ///     .with(RetryAfterMiddleware::builder().with_fail_fast().build())
+    ///     .build();
+    ///
+    /// match client.get("https://example.com").send().await {
+    ///     Err(reqwest_middleware::Error::Middleware(e)) => {
+    ///         if let Some(ExcessiveDelay { delay, .. }) = e.downcast_ref() {
+    ///             println!("try again in {delay:?}");
+    ///         }
+    ///     }
+    ///     _ => {}
+    /// }
+    /// # }
+    /// ```
+    pub fn with_fail_fast(self) -> Self {
+        self.with_max_wait(Duration::ZERO, OnExcessiveDelay::Error)
+    }
+
+    /// Randomizes waits according to `jitter`.
+    ///
+    /// When many clients receive the same `Retry-After`, they all come back at the same
+    /// instant, and are throttled again. Jitter spreads them out. A wait is jittered before
+    /// it is bounded by [`with_max_wait`](Self::with_max_wait).
+    ///
+    /// ```
+    /// use reqwest_retry_after::{Jitter, RetryAfterMiddleware};
+    ///
+    /// let middleware = RetryAfterMiddleware::builder()
+    ///     .with_jitter(Jitter::Full)
+    ///     .build();
+    /// ```
+    pub fn with_jitter(self, jitter: Jitter) -> Self {
+        self.middleware.tracker.config.set_jitter(jitter);
+        self
+    }
+
+    /// Re-sends throttled requests up to `attempts` times, so callers only see the response
+    /// after the last one.
+    ///
+    /// By default, only later requests wait for a `Retry-After`, and the throttled response is
+    /// returned as is. With retries, a `429 Too Many Requests` or `503 Service Unavailable`
+    /// response that stores a `Retry-After` is dropped, and the request is sent again once the
+    /// wait is over. Requests with a streaming body can't be cloned, and are only re-sent if
+    /// they carry a [`RebuildRequest`](crate::RebuildRequest) to build them again. Waits longer
+    /// than [`with_max_wait`](Self::with_max_wait) allows are not retried.
+    pub fn with_retries(mut self, attempts: u32) -> Self {
+        self.middleware.retries = attempts;
+        self
+    }
+
+    /// Adds `margin` to every stored delay.
+    ///
+    /// Servers often measure their windows slightly differently than clients, so a request
+    /// sent right at the end of a `Retry-After` can still be throttled. The margin is added
+    /// after the [delay factor](Self::with_delay_factor), and before the
+    /// [TTL](Self::with_max_ttl) is enforced.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use reqwest_retry_after::RetryAfterMiddleware;
+    ///
+    /// let middleware = RetryAfterMiddleware::builder()
+    ///     .with_safety_margin(Duration::from_millis(500))
+    ///     .with_delay_factor(1.1)
+    ///     .build();
+    /// ```
+    pub fn with_safety_margin(mut self, margin: Duration) -> Self {
+        self.middleware.tracker.safety_margin = margin;
+        self
+    }
+
+    /// Multiplies every stored delay by `factor`, which defaults to 1.
+    ///
+    /// See [`with_safety_margin`](Self::with_safety_margin).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `factor` is negative or not finite.
+    pub fn with_delay_factor(mut self, factor: f64) -> Self {
+        assert!(
+            factor.is_finite() && factor >= 0.0,
+            "delay factor must be finite and non-negative, got {factor}"
+        );
+        self.middleware.tracker.delay_factor = factor;
+        self
+    }
+
+    /// Never keeps more than `max` stored values.
+    ///
+    /// With a narrow [scope](Self::with_scope), a server that sends `Retry-After` on many
+    /// distinct URLs, or a crawler visiting millions of pages, would otherwise grow the state
+    /// without bound. Once there are too many values, those that have passed are dropped
+    /// first, then those expiring earliest. The value being stored is always kept.
+    pub fn with_max_entries(mut self, max: usize) -> Self {
+        self.middleware.tracker.max_entries = Some(max);
+        self
+    }
+
+    /// Never stores a `Retry-After` for longer than `ttl`, whatever the server advertised.
+    ///
+    /// This protects against corrupt far-future dates, which would otherwise stall every
+    /// request until they pass. Longer values are cut down to `ttl` from the moment they are
+    /// received.
+    pub fn with_max_ttl(mut self, ttl: Duration) -> Self {
+        self.middleware.tracker.max_ttl = Some(ttl);
+        self
+    }
+
+    /// Releases the requests waiting for the same stored value one by one, `gap` apart, once
+    /// it has passed, instead of all at once.
+    ///
+    /// Requests are released in the order they started waiting. Every key releases its own
+    /// requests from when its value passes, so that when the values of several keys pass
+    /// around the same time, their requests take turns, rather than the backlog of one host
+    /// taking up the connection pool before the others get a chance.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use reqwest_retry_after::{RetryAfterMiddleware, Scope};
+    ///
+    /// let middleware = RetryAfterMiddleware::builder()
+    ///     .with_scope(Scope::Host)
+    ///     .with_release_stagger(Duration::from_millis(50))
+    ///     .build();
+    /// ```
+    pub fn with_release_stagger(mut self, gap: Duration) -> Self {
+        self.middleware.release_stagger = Some(gap).filter(|gap| !gap.is_zero());
+        self
+    }
+
+    /// Waits out `Retry-After` values in sleeps of at most `chunk`, checking the stored value
+    /// again between them.
+    ///
+    /// By default a request sleeps for the whole remaining wait at once, and only checks
+    /// whether a later response pushed the stored value back once it wakes up. With chunking, a
+    /// waiting request is also released early when a later response clears the stored value,
+    /// at the cost of waking up more often.
+    pub fn with_sleep_chunk(mut self, chunk: Duration) -> Self {
+        self.middleware.sleep_chunk = Some(chunk).filter(|chunk| !chunk.is_zero());
+        self
+    }
+
+    /// Verifies that the upstream accepts traffic again before releasing requests that waited
+    /// out a `Retry-After`, by sending a `HEAD` request to the same URL with `client`.
+    ///
+    /// Only one waiting request per key probes at a time. If the probe comes back with another
+    /// `Retry-After`, it is stored and every waiting request keeps waiting; otherwise the stored
+    /// value is cleared, or left in place when the probe's response is ignored, and they are all
+    /// released. A probe failing [strict validation](Self::with_strict_validation) set to
+    /// [`OnViolation::Error`](crate::OnViolation::Error) fails the request. Requests carrying a
+    /// [`MaxWaitOverride`](crate::MaxWaitOverride) never probe, and neither does dry-run mode.
+    ///
+    /// `client` should be a plain [`reqwest::Client`], not one wrapped with this middleware.
+    pub fn with_recovery_probe(mut self, client: reqwest::Client) -> Self {
+        self.middleware.recovery_probe = Some(client);
+        self
+    }
+
+    /// Delays requests by up to `delay` when their key is about to be throttled again, judging
+    /// by how often it has been before.
+    ///
+    /// The middleware keeps a smoothed average of the time between two `Retry-After`s stored
+    /// for every key, along the lines of the per-host [`HostStats::smoothed_interval`]. While
+    /// no `Retry-After` is stored and the time since the key was last throttled is within 20%
+    /// of that average, requests with it wait for `delay`, or for the key's smoothed window,
+    /// like [`HostStats::smoothed_window`], if that is shorter. Bursty jobs that keep tripping
+    /// the same limit provoke fewer rejections this way.
+    ///
+    /// [`HostStats::smoothed_interval`]: crate::stats::HostStats::smoothed_interval
+    /// [`HostStats::smoothed_window`]: crate::stats::HostStats::smoothed_window
+    pub fn with_predictive_delay(mut self, delay: Duration) -> Self {
+        self.middleware.predictive_delay = Some(delay);
+        self
+    }
+
+    /// Keeps the last `capacity` throttling events in memory, to be retrieved with
+    /// [`recent_events`](RetryAfterMiddleware::recent_events).
+    ///
+    /// This answers "what throttling happened lately" on a running service without any
+    /// external telemetry. No events are kept by default.
+    pub fn with_event_history(mut self, capacity: usize) -> Self {
+        self.middleware.event_history = capacity;
+        self
+    }
+
+    /// Validates every `Retry-After` strictly against [RFC 9110], and handles the ones that
+    /// don't conform as `policy` says.
+    ///
+    /// A conforming field is a single delay-seconds or IMF-fixdate value. By default the
+    /// middleware is lenient, and accepts any non-negative integer or date it can parse.
+    /// Strict validation is mostly useful for conformance tests of one's own gateways; register
+    /// [`on_violation`](Self::on_violation) to see the details of every violation.
+    ///
+    /// [RFC 9110]: https://www.rfc-editor.org/rfc/rfc9110#section-10.2.3
+    pub fn with_strict_validation(mut self, policy: OnViolation) -> Self {
+        self.middleware.tracker.strict = Some(policy);
+        self
+    }
+
+    /// Calls `f` with the response URL whenever a `Retry-After` fails
+    /// [strict validation](Self::with_strict_validation).
+    pub fn on_violation(
+        mut self,
+        f: impl Fn(&Url, &RetryAfterViolation) + Send + Sync + 'static,
+    ) -> Self {
+        self.middleware.on_violation = Some(Arc::new(f));
+        self
+    }
+
+    /// Reads a response with several `Retry-After` fields as `on_multiple_fields` says.
+    ///
+    /// Proxies can append a `Retry-After` of their own to that of the server. By default the
+    /// field that ends later is used, so that no layer is retried too early. Fields that can't
+    /// be parsed are skipped, unless none can.
+    pub fn with_multiple_fields(mut self, on_multiple_fields: OnMultipleFields) -> Self {
+        self.middleware.tracker.on_multiple_fields = on_multiple_fields;
+        self
+    }
+
+    /// Resolves a response's value for a key that still holds another one as `on_conflict`
+    /// says.
+    ///
+    /// Concurrent requests with the same key can come back with different values, and by
+    /// default the latest response wins, even if that shortens a longer cool-down. Passed
+    /// values are always replaced.
+    pub fn with_conflict_resolution(mut self, on_conflict: OnConflict) -> Self {
+        self.middleware.tracker.on_conflict = on_conflict;
+        self
+    }
+
+    /// Sets which requests share a stored `Retry-After`. Defaults to [`Scope::Global`].
+    ///
+    /// ```
+    /// use reqwest_retry_after::{RetryAfterMiddleware, Scope};
+    ///
+    /// // a 429 on `/api/items?page=2` also delays `/api/users` on the same host
+    /// let middleware = RetryAfterMiddleware::builder().with_scope(Scope::Host).build();
+    /// ```
+    pub fn with_scope(self, scope: Scope) -> Self {
+        self.with_key_extractor(scope)
+    }
+
+    /// Derives the key a request's `Retry-After` is stored under with `keys`, instead of a
+    /// [`Scope`].
+    ///
+    /// Requests with the same key share a stored value, which models APIs whose rate limits
+    /// are bucketed by something other than the URL. Closures taking a
+    /// [`Request`](reqwest::Request) work too:
+    ///
+    /// ```
+    /// use reqwest_retry_after::RetryAfterMiddleware;
+    ///
+    /// // GitHub limits every endpoint family separately
+    /// let middleware = RetryAfterMiddleware::builder()
+    ///     .with_key_extractor(|req: &reqwest::Request| {
+    ///         let url = req.url();
+    ///         let segment = url.path_segments().and_then(|mut s| s.next()).unwrap_or_default();
+    ///         format!("{}/{segment}", url.host_str().unwrap_or_default())
+    ///     })
+    ///     .build();
+    /// ```
+    ///
+    /// The extractor is kept behind dynamic dispatch rather than as a type parameter of
+    /// [`RetryAfterMiddleware`], so that the middleware stays one nameable type however its
+    /// keys are derived, and can be stored and shared without spelling out the extractor. A
+    /// key is derived once per request, so the virtual call costs nothing next to the request.
+    pub fn with_key_extractor(mut self, keys: impl KeyExtractor + 'static) -> Self {
+        self.middleware.tracker.keys = Arc::new(keys);
+        self
+    }
+
+    /// Writes stored `Retry-After` values through to `store`, and reads them back from it
+    /// before each request.
+    ///
+    /// The middleware keeps its own copy of every value, so a value in the store only ever
+    /// extends the current wait, and [`insert`](RetryAfterMiddleware::insert) and friends
+    /// only change the copy. When a response clears a value, the store is also
+    /// [purged](crate::store::RetryAfterStore::purge) of any that have passed.
+    ///
+    /// ```
+    /// use std::sync::Arc;
+    /// use reqwest_retry_after::{store::MemoryStore, RetryAfterMiddleware};
+    ///
+    /// // two middlewares backed by the same store
+    /// let store = Arc::new(MemoryStore::new());
+    /// let a = RetryAfterMiddleware::builder().with_store(store.clone()).build();
+    /// let b = RetryAfterMiddleware::builder().with_store(store).build();
+    /// ```
+    pub fn with_store(mut self, store: impl RetryAfterStore + 'static) -> Self {
+        self.middleware.store = Some(Arc::new(store));
+        self
+    }
+
+    /// Shares stored `Retry-After` values with other processes through `coordinator`.
+    ///
+    /// Every stored value is published, and values published by peers are picked up before
+    /// each request. A peer's value only ever extends the current wait.
+    pub fn with_coordinator(mut self, coordinator: impl Coordinator + 'static) -> Self {
+        self.middleware.coordinator = Some(Arc::new(coordinator));
+        self
+    }
+
+    /// Inspects the bodies of responses without a `Retry-After` header with `detector`, and
+    /// stores the delay it detects as if it had been sent in a `Retry-After`.
+    ///
+    /// Detectors are consulted in the order they were registered, and the first delay found
+    /// wins. See the [`detect`](crate::detect) module for the available detectors.
+    pub fn with_detector(mut self, detector: impl ThrottleDetector + 'static) -> Self {
+        self.middleware.detectors.push(Arc::new(detector));
+        self
+    }
+
+    /// Also holds back the requests in the group `group` puts a request in whenever one of
+    /// them is throttled for the whole group, see the [`group`](crate::group) module.
+    ///
+    /// Several groupings can be registered, and a request waits for the values of all the
+    /// groups it belongs to.
+    pub fn with_key_group(mut self, group: impl KeyGroup + 'static) -> Self {
+        self.middleware.groups.push(Arc::new(group));
+        self
+    }
+
+    /// Tells `observer` whenever a throttle is recorded, and whenever a request starts and
+    /// finishes waiting for one.
+    ///
+    /// Observers are told in the order they were registered. See the
+    /// [`observer`](crate::observer) module for an example.
+    pub fn with_observer(mut self, observer: impl RetryAfterObserver + 'static) -> Self {
+        self.middleware.observers.push(Arc::new(observer));
+        self
+    }
+
+    /// Waits according to `backoff` after a throttled response without a `Retry-After`.
+    ///
+    /// Many APIs answer `429 Too Many Requests` or `503 Service Unavailable` without saying for
+    /// how long. Such responses then store a delay as if the server had sent it, unless a
+    /// [detector](Self::with_detector) recognized them first. Consecutive throttled responses
+    /// are counted per [key](Self::with_key_extractor), and the count starts over with the
+    /// first response that isn't throttled.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use reqwest_retry_after::{Backoff, RetryAfterMiddleware};
+    ///
+    /// let middleware = RetryAfterMiddleware::builder()
+    ///     .with_fallback_backoff(Backoff::Exponential {
+    ///         base: Duration::from_secs(1),
+    ///         max: Duration::from_secs(60),
+    ///     })
+    ///     .build();
+    /// ```
+    pub fn with_fallback_backoff(mut self, backoff: Backoff) -> Self {
+        self.middleware.tracker.fallback = Some(backoff);
+        self
+    }
+
+    /// Only honors the `Retry-After` of responses whose status passes `filter`.
+    ///
+    /// By default, only `429 Too Many Requests`, `503 Service Unavailable` and the statuses
+    /// added with [`with_throttle_status`](Self::with_throttle_status) are honored, so that a
+    /// buggy backend sending `Retry-After` on successful responses can't stall later requests.
+    /// A response whose `Retry-After` isn't honored is treated as if it had none.
+    ///
+    /// ```
+    /// use http::StatusCode;
+    /// use reqwest_retry_after::RetryAfterMiddleware;
+    ///
+    /// // also honor the `Retry-After` of redirects
+    /// let middleware = RetryAfterMiddleware::builder()
+    ///     .with_retry_after_statuses(|status: StatusCode| {
+    ///         status.is_redirection() || status == StatusCode::TOO_MANY_REQUESTS
+    ///     })
+    ///     .build();
+    /// ```
+    pub fn with_retry_after_statuses(
+        mut self,
+        filter: impl Fn(StatusCode) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.middleware.tracker.retry_after_statuses = Some(Arc::new(filter));
+        self
+    }
+
+    /// Also reads the delay from the `name` header, given in `format`.
+    ///
+    /// Some gateways use bespoke headers like `X-Cooldown-Seconds` instead of `Retry-After`.
+    /// Headers are scanned in the order they were added, and the first one that parses wins
+    /// over a `Retry-After`. They are only honored for the statuses a `Retry-After` is, see
+    /// [`with_retry_after_statuses`](Self::with_retry_after_statuses).
+    ///
+    /// ```
+    /// use http::HeaderName;
+    /// use reqwest_retry_after::{HeaderFormat, RetryAfterMiddleware};
+    ///
+    /// let middleware = RetryAfterMiddleware::builder()
+    ///     .with_retry_after_header(
+    ///         HeaderName::from_static("x-cooldown-seconds"),
+    ///         HeaderFormat::Seconds,
+    ///     )
+    ///     .build();
+    /// ```
+    pub fn with_retry_after_header(mut self, name: HeaderName, format: HeaderFormat) -> Self {
+        self.middleware
+            .tracker
+            .retry_after_headers
+            .push((name, format));
+        self
+    }
+
+    /// Also waits for the quota advertised by `source` headers to reset, once a response says
+    /// it is exhausted.
+    ///
+    /// More and more APIs send the IETF `RateLimit` headers instead of a `Retry-After`. With
+    /// [`RateLimitSource::RateLimit`](crate::rate_limit::RateLimitSource::RateLimit), a
+    /// response with no quota remaining stores its `RateLimit-Reset` like a `Retry-After`,
+    /// whatever its status. A `Retry-After` still takes precedence, and is always honored.
+    ///
+    /// GitHub-style APIs instead send `X-RateLimit-Remaining: 0` with an `X-RateLimit-Reset`
+    /// Unix timestamp, and Discord an `X-RateLimit-Reset-After` delay, both of which
+    /// [`RateLimitSource::XRateLimit`](crate::rate_limit::RateLimitSource::XRateLimit) honors.
+    /// Reset values are read as timestamps or delays depending on their size, as described on
+    /// [`RateLimitInfo`](crate::rate_limit::RateLimitInfo).
+    ///
+    /// ```
+    /// use reqwest_retry_after::{rate_limit::RateLimitSource, RetryAfterMiddleware};
+    ///
+    /// let middleware = RetryAfterMiddleware::builder()
+    ///     .with_rate_limit_source(RateLimitSource::RateLimit)
+    ///     .with_rate_limit_source(RateLimitSource::XRateLimit)
+    ///     .build();
+    /// ```
+    pub fn with_rate_limit_source(mut self, source: RateLimitSource) -> Self {
+        self.middleware.tracker.rate_limit_sources.push(source);
+        self
+    }
+
+    /// Treats responses with `status` exactly like `429 Too Many Requests`.
+    ///
+    /// Some legacy APIs throttle with a non-standard status, such as Twitter's
+    /// `420 Enhance Your Calm`. Their `Retry-After` is then honored, and
+    /// [detectors](Self::with_detector) look at them like they would at a `429`.
+    ///
+    /// ```
+    /// use http::StatusCode;
+    /// use reqwest_retry_after::RetryAfterMiddleware;
+    ///
+    /// let middleware = RetryAfterMiddleware::builder()
+    ///     .with_throttle_status(StatusCode::from_u16(420).unwrap())
+    ///     .build();
+    /// ```
+    pub fn with_throttle_status(mut self, status: StatusCode) -> Self {
+        self.middleware.tracker.throttle_statuses.push(status);
+        self
+    }
+
+    /// Enables or disables dry-run mode.
+    ///
+    /// In dry-run mode `Retry-After` values are still recorded, but requests are never
+    /// delayed. Instead, each response that would have been held back carries a
+    /// [`DryRunOutcome`](crate::DryRunOutcome) extension describing what the middleware would
+    /// have done, which makes it possible to validate a configuration against live traffic.
+    ///
+    /// ```
+    /// # async fn run(server: &str) -> reqwest_middleware::Result<()> {
+    /// use reqwest_middleware::ClientBuilder;
+    /// use reqwest_retry_after::{DryRunOutcome, RetryAfterMiddleware};
+    ///
+    /// let client = ClientBuilder::new(reqwest::Client::new())
+    ///     .with(RetryAfterMiddleware::builder().dry_run(true).build())
+    ///     .build();
+    ///
+    /// let res = client.get(server).send().await?;
+    /// if let Some(DryRunOutcome::Wait(duration)) = res.extensions().get::<DryRunOutcome>() {
+    ///     println!("would have waited {duration:?}");
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn dry_run(self, enabled: bool) -> Self {
+        self.middleware.tracker.config.set_dry_run(enabled);
+        self
+    }
+
+    /// Calls `f` with the response URL and the parsed instant whenever a `Retry-After` is
+    /// stored.
+    ///
+    /// Together with [`on_unpersist`](Self::on_unpersist), this lets applications mirror the
+    /// middleware's state to their own storage.
+    pub fn on_persist(mut self, f: impl Fn(&Url, SystemTime) + Send + Sync + 'static) -> Self {
+        self.middleware.on_persist = Some(Arc::new(f));
+        self
+    }
+
+    /// Calls `f` with the response URL whenever a stored `Retry-After` is removed.
+    pub fn on_unpersist(mut self, f: impl Fn(&Url) + Send + Sync + 'static) -> Self {
+        self.middleware.on_unpersist = Some(Arc::new(f));
+        self
+    }
+
+    /// Calls `f` with the response URL and the parsed instant whenever a response from `host`
+    /// stores a `Retry-After`.
+    ///
+    /// Unlike [`on_persist`](Self::on_persist), callbacks registered here only see their own
+    /// host, so alerting can be attached to one vendor on a client shared with many others.
+    /// Any number of callbacks can be registered, for the same or different hosts.
+    ///
+    /// ```
+    /// use reqwest_retry_after::RetryAfterMiddleware;
+    ///
+    /// let middleware = RetryAfterMiddleware::builder()
+    ///     .on_limited("api.stripe.com", |url, until| {
+    ///         eprintln!("{url} is rate limited until {until:?}");
+    ///     })
+    ///     .build();
+    /// ```
+    pub fn on_limited(
+        mut self,
+        host: impl Into<String>,
+        f: impl Fn(&Url, SystemTime) + Send + Sync + 'static,
+    ) -> Self {
+        self.middleware.on_limited.push((host.into(), Arc::new(f)));
+        self
+    }
+
+    /// Calls `f` with the response URL and the length of the window whenever a stored
+    /// `Retry-After` holds requests back for at least `threshold`.
+    ///
+    /// Keeping idle connections open to a host that won't accept traffic for minutes wastes
+    /// resources on both ends. The middleware can't reach into reqwest's connection pool, but
+    /// this hook is the place to do so, for example by swapping in a freshly built
+    /// [`reqwest::Client`] or one with keep-alive disabled.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use reqwest_retry_after::RetryAfterMiddleware;
+    ///
+    /// let middleware = RetryAfterMiddleware::builder()
+    ///     .on_long_window(Duration::from_secs(120), |url, window| {
+    ///         eprintln!("closing connections to {url} for {window:?}")
+    ///     })
+    ///     .build();
+    /// ```
+    pub fn on_long_window(
+        mut self,
+        threshold: Duration,
+        f: impl Fn(&Url, Duration) + Send + Sync + 'static,
+    ) -> Self {
+        self.middleware.on_long_window = Some((threshold, Arc::new(f)));
+        self
+    }
+
+    /// Logs a warning for every `Retry-After` longer than `threshold`, instead of one minute.
+    ///
+    /// Requires the `log` feature.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use reqwest_retry_after::RetryAfterMiddleware;
+    ///
+    /// let middleware = RetryAfterMiddleware::builder()
+    ///     .with_log_threshold(Duration::from_secs(10))
+    ///     .build();
+    /// ```
+    #[cfg(feature = "log")]
+    pub fn with_log_threshold(mut self, threshold: Duration) -> Self {
+        self.middleware.log_threshold = threshold;
+        self
+    }
+
+    /// Calls `f` with the response URL whenever a response announces a deprecation or sunset
+    /// through the `Deprecation` ([RFC 9745]) or `Sunset` ([RFC 8594]) headers.
+    ///
+    /// These headers never delay requests, and are only parsed when a callback is registered.
+    /// `Sunset` carries an HTTP date, so it is ignored without the `http-date` feature.
+    ///
+    /// [RFC 9745]: https://www.rfc-editor.org/rfc/rfc9745
+    /// [RFC 8594]: https://www.rfc-editor.org/rfc/rfc8594
+    pub fn on_deprecation(
+        mut self,
+        f: impl Fn(&Url, &DeprecationNotice) + Send + Sync + 'static,
+    ) -> Self {
+        self.middleware.on_deprecation = Some(Arc::new(f));
+        self
+    }
+}
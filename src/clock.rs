@@ -0,0 +1,244 @@
+//! Time sources and sleep implementations used by [`RetryAfterMiddleware`].
+//!
+//! On `wasm32` targets, where the standard library can't tell the time and tokio has no
+//! timer, the time is read from the JavaScript host, and `FuturesTimerSleeper` is the
+//! default sleeper. The tokio-based types aren't available there.
+//!
+//! [`RetryAfterMiddleware`]: crate::RetryAfterMiddleware
+
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::Instant;
+#[cfg(target_arch = "wasm32")]
+use web_time::Instant;
+
+/// An owned, boxed future, as returned by custom sleep functions.
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// A source of the current wall-clock time.
+///
+/// The middleware asks its clock for the time whenever it converts a `Retry-After` value into
+/// an instant, and whenever it decides how long a request still has to wait.
+pub trait Clock: Send + Sync {
+    /// Returns the current time.
+    fn now(&self) -> SystemTime;
+}
+
+/// Something that can wait for a [`Duration`] to pass.
+///
+/// Besides the types in this crate, any `Fn(Duration) -> BoxFuture<'static, ()>` is a
+/// `Sleeper`, so waits can be routed through an embedder's own timer facilities:
+///
+/// ```
+/// use std::time::Duration;
+/// use reqwest_retry_after::{clock::BoxFuture, RetryAfterMiddleware};
+///
+/// fn my_sleep(duration: Duration) -> BoxFuture<'static, ()> {
+///     Box::pin(tokio::time::sleep(duration))
+/// }
+///
+/// let middleware = RetryAfterMiddleware::builder().with_sleeper(my_sleep).build();
+/// ```
+#[async_trait::async_trait]
+pub trait Sleeper: Send + Sync {
+    /// Waits for `duration` to pass.
+    async fn sleep(&self, duration: Duration);
+}
+
+#[async_trait::async_trait]
+impl<F> Sleeper for F
+where
+    F: Fn(Duration) -> BoxFuture<'static, ()> + Send + Sync,
+{
+    async fn sleep(&self, duration: Duration) {
+        self(duration).await
+    }
+}
+
+/// The default [`Clock`], backed by [`SystemTime::now`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        wall_clock()
+    }
+}
+
+/// Returns the current wall-clock time, read through `web-time` on wasm, where
+/// [`SystemTime::now`] panics.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) fn wall_clock() -> SystemTime {
+    SystemTime::now()
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) fn wall_clock() -> SystemTime {
+    let since_epoch = web_time::SystemTime::now()
+        .duration_since(web_time::UNIX_EPOCH)
+        .unwrap_or_default();
+    SystemTime::UNIX_EPOCH + since_epoch
+}
+
+/// A [`Clock`] that reads the wall clock once, and then only moves forward with a monotonic
+/// [`Instant`].
+///
+/// With [`SystemClock`], an NTP correction or a manual change of the system time while a
+/// `Retry-After` is stored stretches or skips the wait, as the stored instant is compared
+/// against the new time. A `MonotonicClock` ignores such adjustments: every value is turned
+/// into an instant on its own timeline when received, and waits for it last exactly as long
+/// as the server asked. Its time drifts from the wall clock as the system clock is
+/// corrected, which only matters for `Retry-After` dates, and for instants shared through a
+/// [`RetryAfterStore`](crate::store::RetryAfterStore) with other processes.
+///
+/// ```
+/// use reqwest_retry_after::{clock::MonotonicClock, RetryAfterMiddleware};
+///
+/// let middleware = RetryAfterMiddleware::builder()
+///     .with_clock(MonotonicClock::new())
+///     .build();
+/// ```
+#[derive(Debug, Clone, Copy)]
+pub struct MonotonicClock {
+    anchor: SystemTime,
+    start: Instant,
+}
+
+impl MonotonicClock {
+    /// Creates a `MonotonicClock` starting at the current wall-clock time.
+    pub fn new() -> Self {
+        Self {
+            anchor: wall_clock(),
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for MonotonicClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> SystemTime {
+        self.anchor + self.start.elapsed()
+    }
+}
+
+/// A [`Clock`] that reads the wall clock once, and then moves forward with tokio's
+/// [`Instant`](tokio::time::Instant).
+///
+/// Tests running with tokio's time paused, as with `#[tokio::test(start_paused = true)]`,
+/// skip through the waits of a [`TokioSleeper`], but the wall clock doesn't move along, so
+/// the stored values still appear to be ahead. With a `TokioClock`, which follows tokio's
+/// time while it is paused or advanced, they pass as soon as they are waited out. Like
+/// [`MonotonicClock`], it also ignores adjustments of the system time.
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// use reqwest_retry_after::{
+///     clock::{TokioClock, TokioSleeper},
+///     RetryAfterMiddleware,
+/// };
+///
+/// let middleware = RetryAfterMiddleware::builder()
+///     .with_clock(TokioClock::new())
+///     .with_sleeper(TokioSleeper)
+///     .build();
+/// # }
+/// ```
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug, Clone, Copy)]
+pub struct TokioClock {
+    anchor: SystemTime,
+    start: tokio::time::Instant,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl TokioClock {
+    /// Creates a `TokioClock` starting at the current wall-clock time.
+    pub fn new() -> Self {
+        Self {
+            anchor: wall_clock(),
+            start: tokio::time::Instant::now(),
+        }
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Default for TokioClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Clock for TokioClock {
+    fn now(&self) -> SystemTime {
+        self.anchor + self.start.elapsed()
+    }
+}
+
+/// The default [`Sleeper`], backed by [`tokio::time::sleep`].
+#[cfg(not(target_arch = "wasm32"))]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioSleeper;
+
+#[cfg(not(target_arch = "wasm32"))]
+#[async_trait::async_trait]
+impl Sleeper for TokioSleeper {
+    async fn sleep(&self, duration: Duration) {
+        tokio::time::sleep(duration).await
+    }
+}
+
+/// A [`Sleeper`] backed by [`futures_timer::Delay`], which doesn't need a tokio runtime.
+///
+/// With the `futures-timer` feature enabled, and on `wasm32` targets, this is the default
+/// sleeper.
+#[cfg(any(feature = "futures-timer", target_arch = "wasm32"))]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FuturesTimerSleeper;
+
+#[cfg(any(feature = "futures-timer", target_arch = "wasm32"))]
+#[async_trait::async_trait]
+impl Sleeper for FuturesTimerSleeper {
+    async fn sleep(&self, duration: Duration) {
+        futures_timer::Delay::new(duration).await
+    }
+}
+
+#[cfg(any(feature = "futures-timer", target_arch = "wasm32"))]
+pub(crate) fn default_sleeper() -> Arc<dyn Sleeper> {
+    Arc::new(FuturesTimerSleeper)
+}
+
+#[cfg(not(any(feature = "futures-timer", target_arch = "wasm32")))]
+pub(crate) fn default_sleeper() -> Arc<dyn Sleeper> {
+    Arc::new(TokioSleeper)
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{Clock, MonotonicClock};
+
+    #[test]
+    fn monotonic_clock() {
+        let clock = MonotonicClock::new();
+        let start = clock.now();
+
+        std::thread::sleep(Duration::from_millis(10));
+        let elapsed = clock.now().duration_since(start).unwrap();
+        assert!(elapsed >= Duration::from_millis(10));
+    }
+}
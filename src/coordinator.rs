@@ -0,0 +1,210 @@
+//! Sharing `Retry-After` state between processes.
+//!
+//! A [`Coordinator`] publishes every `Retry-After` a middleware stores, and hands it any
+//! published by its peers, so that several processes talking to the same API learn about a
+//! throttle as soon as one of them is told.
+//!
+//! ```no_run
+//! # #[cfg(unix)]
+//! # fn main() -> std::io::Result<()> {
+//! use reqwest_retry_after::{coordinator::UnixSocketCoordinator, RetryAfterMiddleware};
+//!
+//! let coordinator = UnixSocketCoordinator::bind("/run/my-app/retry-after")?;
+//! let middleware = RetryAfterMiddleware::builder().with_coordinator(coordinator).build();
+//! # Ok(())
+//! # }
+//! # #[cfg(not(unix))]
+//! # fn main() {}
+//! ```
+
+use std::time::SystemTime;
+
+/// Publishes and receives `Retry-After` instants shared with other processes.
+///
+/// Every instant belongs to the key it was stored under, as determined by the middleware's
+/// [`Scope`](crate::Scope).
+#[async_trait::async_trait]
+pub trait Coordinator: Send + Sync {
+    /// Tells every peer that requests with `key` must wait until `until`.
+    async fn publish(&self, key: &str, until: SystemTime);
+
+    /// Returns the latest instant published by a peer for every key since the previous call,
+    /// without waiting for one to arrive.
+    async fn receive(&self) -> Vec<(String, SystemTime)>;
+}
+
+#[cfg(unix)]
+pub use unix::UnixSocketCoordinator;
+
+#[cfg(unix)]
+mod unix {
+    use std::{
+        collections::HashMap,
+        fs, io,
+        os::unix::net::UnixDatagram,
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::{Duration, SystemTime},
+    };
+
+    use super::Coordinator;
+    use crate::store::unblock;
+
+    const EXTENSION: &str = "sock";
+    const HEADER_LEN: usize = 12;
+    const MAX_KEY_LEN: usize = 2048;
+
+    /// A [`Coordinator`] for processes on the same machine, backed by Unix datagram sockets.
+    ///
+    /// Every coordinator binds its own socket inside a shared directory, and publishing sends a
+    /// datagram to every other socket found there. Sockets left behind by processes that exited
+    /// without cleaning up are removed the next time a peer publishes.
+    ///
+    /// Socket paths are limited to about 100 bytes by the operating system, so the directory
+    /// should have a short path. Keys longer than 2 KiB are not shared.
+    ///
+    /// Publishing scans the directory and sends to every peer on tokio's blocking thread pool,
+    /// where a slow file system stalls no runtime. Receiving never waits, since the socket is
+    /// non-blocking.
+    #[derive(Debug)]
+    pub struct UnixSocketCoordinator {
+        socket: Arc<UnixDatagram>,
+        path: PathBuf,
+        dir: PathBuf,
+    }
+
+    impl UnixSocketCoordinator {
+        /// Binds a new socket inside `dir`, creating the directory if needed.
+        pub fn bind(dir: impl AsRef<Path>) -> io::Result<Self> {
+            static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+            let dir = dir.as_ref().to_path_buf();
+            fs::create_dir_all(&dir)?;
+
+            let name = format!(
+                "{}-{}.{EXTENSION}",
+                std::process::id(),
+                COUNT.fetch_add(1, Ordering::Relaxed)
+            );
+            let path = dir.join(name);
+            // a previous process with the same pid can't be listening anymore
+            let _ = fs::remove_file(&path);
+
+            let socket = UnixDatagram::bind(&path)?;
+            socket.set_nonblocking(true)?;
+
+            Ok(Self {
+                socket: Arc::new(socket),
+                path,
+                dir,
+            })
+        }
+    }
+
+    fn peers(dir: &Path, own: &Path) -> io::Result<Vec<PathBuf>> {
+        Ok(fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == EXTENSION))
+            .filter(|path| path != own)
+            .collect())
+    }
+
+    fn send(socket: &UnixDatagram, dir: &Path, own: &Path, message: &[u8]) {
+        for peer in peers(dir, own).unwrap_or_default() {
+            match socket.send_to(message, &peer) {
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    // nobody is bound to this socket anymore
+                    let _ = fs::remove_file(&peer);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    impl Drop for UnixSocketCoordinator {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Coordinator for UnixSocketCoordinator {
+        async fn publish(&self, key: &str, until: SystemTime) {
+            let Some(message) = encode(key, until) else {
+                return;
+            };
+
+            let socket = self.socket.clone();
+            let (dir, own) = (self.dir.clone(), self.path.clone());
+            unblock(move || send(&socket, &dir, &own, &message)).await;
+        }
+
+        async fn receive(&self) -> Vec<(String, SystemTime)> {
+            let mut latest = HashMap::new();
+            let mut buf = [0; HEADER_LEN + MAX_KEY_LEN];
+
+            while let Ok(len) = self.socket.recv(&mut buf) {
+                if let Some((key, until)) = decode(&buf[..len]) {
+                    let latest = latest.entry(key).or_insert(until);
+                    *latest = (*latest).max(until);
+                }
+            }
+            latest.into_iter().collect()
+        }
+    }
+
+    fn encode(key: &str, until: SystemTime) -> Option<Vec<u8>> {
+        let since_epoch = until.duration_since(SystemTime::UNIX_EPOCH).ok()?;
+        if key.len() > MAX_KEY_LEN {
+            return None;
+        }
+
+        let mut message = Vec::with_capacity(HEADER_LEN + key.len());
+        message.extend_from_slice(&since_epoch.as_secs().to_be_bytes());
+        message.extend_from_slice(&since_epoch.subsec_nanos().to_be_bytes());
+        message.extend_from_slice(key.as_bytes());
+        Some(message)
+    }
+
+    fn decode(message: &[u8]) -> Option<(String, SystemTime)> {
+        let secs = u64::from_be_bytes(message.get(..8)?.try_into().ok()?);
+        let nanos = u32::from_be_bytes(message.get(8..HEADER_LEN)?.try_into().ok()?);
+        let key = std::str::from_utf8(&message[HEADER_LEN..]).ok()?;
+        let until = SystemTime::UNIX_EPOCH.checked_add(Duration::new(secs, nanos))?;
+        Some((key.to_owned(), until))
+    }
+
+    #[cfg(test)]
+    mod test {
+        use std::time::{Duration, SystemTime};
+
+        use super::UnixSocketCoordinator;
+        use crate::coordinator::Coordinator;
+
+        #[tokio::test]
+        async fn publish_and_receive() {
+            let dir = std::env::temp_dir().join(format!("rra-{}", std::process::id()));
+            let a = UnixSocketCoordinator::bind(&dir).unwrap();
+            let b = UnixSocketCoordinator::bind(&dir).unwrap();
+
+            let soon = SystemTime::UNIX_EPOCH + Duration::new(1_700_000_000, 500);
+            let later = soon + Duration::from_secs(30);
+            a.publish("api.github.com", soon).await;
+            a.publish("api.github.com", later).await;
+
+            assert_eq!(
+                b.receive().await,
+                vec![("api.github.com".to_owned(), later)]
+            );
+            assert_eq!(b.receive().await, vec![]);
+            // publishers don't hear themselves
+            assert_eq!(a.receive().await, vec![]);
+
+            drop((a, b));
+            let _ = std::fs::remove_dir(&dir);
+        }
+    }
+}
@@ -0,0 +1,462 @@
+//! Detecting throttling from response bodies.
+//!
+//! Some services signal throttling with an error code in the response body instead of a
+//! `Retry-After` header. A [`ThrottleDetector`] registered with
+//! [`RetryAfterMiddlewareBuilder::with_detector`] is shown the beginning of such bodies, and
+//! the delay it returns is stored exactly like a `Retry-After` would be.
+//!
+//! Bodies are only inspected for responses without a `Retry-After` header whose status a
+//! detector asked for. The inspected bytes are handed back to the caller untouched. On `wasm32`
+//! targets, where a read body can't be handed back, detectors are shown an empty body.
+//!
+//! [`RetryAfterMiddlewareBuilder::with_detector`]: crate::RetryAfterMiddlewareBuilder::with_detector
+
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+#[cfg(not(target_arch = "wasm32"))]
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use http::StatusCode;
+#[cfg(not(target_arch = "wasm32"))]
+use http_body::{Body as HttpBody, Frame};
+#[cfg(not(target_arch = "wasm32"))]
+use http_body_util::BodyExt;
+use reqwest_middleware::reqwest::{self, Response};
+#[cfg(not(target_arch = "wasm32"))]
+use reqwest_middleware::reqwest::{Body, ResponseBuilderExt};
+
+/// Recognizes throttling responses from their status and body.
+pub trait ThrottleDetector: Send + Sync {
+    /// Returns whether bodies of responses with `status` should be inspected.
+    fn applies_to(&self, status: StatusCode) -> bool;
+
+    /// Inspects the beginning of the body of a response to a request with `key`, returning how
+    /// long requests should wait if it signals throttling.
+    fn detect(&self, key: &str, status: StatusCode, body: &[u8]) -> Option<Duration>;
+
+    /// The maximum number of body bytes to inspect.
+    fn max_body_len(&self) -> usize {
+        4096
+    }
+
+    /// Called for every response to a request with `key` without a `Retry-After` that no
+    /// detector recognized, so that detectors which escalate their delay can start over.
+    fn reset(&self, _key: &str) {}
+}
+
+/// Detects the throttling error codes returned by AWS services.
+///
+/// AWS APIs answer with a `400`, `429` or `503` carrying an error code such as
+/// `ThrottlingException` or `SlowDown` in a JSON or XML body, and no `Retry-After`. This
+/// detector looks for those codes and applies a fixed backoff when it finds one.
+///
+/// ```
+/// use std::time::Duration;
+/// use reqwest_retry_after::{detect::AwsThrottling, RetryAfterMiddleware};
+///
+/// let middleware = RetryAfterMiddleware::builder()
+///     .with_detector(AwsThrottling::new(Duration::from_secs(1)).with_code("MyCustomThrottle"))
+///     .build();
+/// ```
+#[derive(Debug, Clone)]
+pub struct AwsThrottling {
+    codes: Vec<String>,
+    backoff: Duration,
+    max_body_len: usize,
+}
+
+impl AwsThrottling {
+    /// The error codes recognized by default.
+    pub const CODES: &'static [&'static str] = &[
+        "Throttling",
+        "ThrottlingException",
+        "ThrottledException",
+        "RequestThrottledException",
+        "TooManyRequestsException",
+        "ProvisionedThroughputExceededException",
+        "TransactionInProgressException",
+        "RequestLimitExceeded",
+        "BandwidthLimitExceeded",
+        "LimitExceededException",
+        "RequestThrottled",
+        "SlowDown",
+        "PriorRequestNotComplete",
+        "EC2ThrottledException",
+    ];
+
+    /// Creates a detector for the default [`CODES`](Self::CODES) that backs off for `backoff`.
+    pub fn new(backoff: Duration) -> Self {
+        Self {
+            codes: Self::CODES.iter().map(|code| code.to_string()).collect(),
+            backoff,
+            max_body_len: 4096,
+        }
+    }
+
+    /// Also recognizes `code` as a throttling error.
+    pub fn with_code(mut self, code: impl Into<String>) -> Self {
+        self.codes.push(code.into());
+        self
+    }
+
+    /// Inspects at most `len` bytes of each body, instead of 4096.
+    pub fn with_max_body_len(mut self, len: usize) -> Self {
+        self.max_body_len = len;
+        self
+    }
+}
+
+impl ThrottleDetector for AwsThrottling {
+    fn applies_to(&self, status: StatusCode) -> bool {
+        matches!(
+            status,
+            StatusCode::BAD_REQUEST
+                | StatusCode::TOO_MANY_REQUESTS
+                | StatusCode::SERVICE_UNAVAILABLE
+        )
+    }
+
+    fn detect(&self, _: &str, _: StatusCode, body: &[u8]) -> Option<Duration> {
+        contains_code(body, &self.codes).then_some(self.backoff)
+    }
+
+    fn max_body_len(&self) -> usize {
+        self.max_body_len
+    }
+}
+
+/// Detects the "slow down" responses of object stores, and backs off exponentially.
+///
+/// Amazon S3 answers too many requests with `503 SlowDown`, and Azure Storage with
+/// `503 ServerBusy`, without a `Retry-After`. Each consecutive detection for a key doubles the
+/// delay, starting from the base delay and up to the maximum, until a response with that key
+/// goes through without being throttled.
+///
+/// Backoff is per key, so a prefix that is slowed down only slows down the others with the
+/// same key. [`Scope::Prefix`](crate::Scope::Prefix) gives every bucket, or every prefix
+/// within one, its own key with path-style URLs; other layouts, such as virtual-hosted buckets
+/// with prefixes, can use
+/// [`with_key_extractor`](crate::RetryAfterMiddlewareBuilder::with_key_extractor).
+///
+/// ```
+/// use std::time::Duration;
+/// use reqwest_retry_after::{detect::ObjectStoreSlowDown, RetryAfterMiddleware, Scope};
+///
+/// // `https://s3.amazonaws.com/bucket/logs/...` backs off apart from `.../bucket/images/...`
+/// let middleware = RetryAfterMiddleware::builder()
+///     .with_scope(Scope::Prefix(2))
+///     .with_detector(ObjectStoreSlowDown::new(
+///         Duration::from_millis(200),
+///         Duration::from_secs(20),
+///     ))
+///     .build();
+/// ```
+#[derive(Debug)]
+pub struct ObjectStoreSlowDown {
+    base: Duration,
+    max: Duration,
+    attempts: Mutex<HashMap<String, u32>>,
+}
+
+impl ObjectStoreSlowDown {
+    /// The error codes recognized.
+    pub const CODES: &'static [&'static str] = &["SlowDown", "ServerBusy"];
+
+    /// Creates a detector that backs off from `base` up to `max`.
+    pub fn new(base: Duration, max: Duration) -> Self {
+        Self {
+            base,
+            max,
+            attempts: Mutex::default(),
+        }
+    }
+}
+
+impl ThrottleDetector for ObjectStoreSlowDown {
+    fn applies_to(&self, status: StatusCode) -> bool {
+        status == StatusCode::SERVICE_UNAVAILABLE
+    }
+
+    fn detect(&self, key: &str, _: StatusCode, body: &[u8]) -> Option<Duration> {
+        if !contains_code(body, Self::CODES) {
+            return None;
+        }
+
+        let mut attempts = self.attempts.lock().unwrap();
+        let attempt = attempts.entry(key.to_owned()).or_default();
+        let factor = 2u32.checked_pow(*attempt).unwrap_or(u32::MAX);
+        *attempt = attempt.saturating_add(1);
+        Some(self.base.saturating_mul(factor).min(self.max))
+    }
+
+    fn reset(&self, key: &str) {
+        self.attempts.lock().unwrap().remove(key);
+    }
+}
+
+/// Detects the `RetryInfo` error details returned by Google APIs.
+///
+/// Google Cloud REST APIs answer throttled requests with a `429` or `503` whose JSON error
+/// body carries a `google.rpc.RetryInfo` detail, such as `"retryDelay": "3.5s"`. This detector
+/// stores that delay. Requires the `google` feature.
+///
+/// ```
+/// use reqwest_retry_after::{detect::GoogleRetryInfo, RetryAfterMiddleware};
+///
+/// let middleware = RetryAfterMiddleware::builder().with_detector(GoogleRetryInfo::new()).build();
+/// ```
+#[cfg(feature = "google")]
+#[derive(Debug, Clone)]
+pub struct GoogleRetryInfo {
+    max_body_len: usize,
+}
+
+#[cfg(feature = "google")]
+impl GoogleRetryInfo {
+    const TYPE: &'static str = "type.googleapis.com/google.rpc.RetryInfo";
+
+    /// Creates a `GoogleRetryInfo` detector.
+    pub fn new() -> Self {
+        Self {
+            max_body_len: 16 * 1024,
+        }
+    }
+
+    /// Inspects at most `len` bytes of each body, instead of 16 KiB.
+    ///
+    /// The body is parsed as a whole, so a body longer than this is never recognized.
+    pub fn with_max_body_len(mut self, len: usize) -> Self {
+        self.max_body_len = len;
+        self
+    }
+}
+
+#[cfg(feature = "google")]
+impl Default for GoogleRetryInfo {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "google")]
+impl ThrottleDetector for GoogleRetryInfo {
+    fn applies_to(&self, status: StatusCode) -> bool {
+        matches!(
+            status,
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::SERVICE_UNAVAILABLE
+        )
+    }
+
+    fn detect(&self, _: &str, _: StatusCode, body: &[u8]) -> Option<Duration> {
+        let body: serde_json::Value = serde_json::from_slice(body).ok()?;
+        body.pointer("/error/details")?
+            .as_array()?
+            .iter()
+            .filter(|detail| detail["@type"] == Self::TYPE)
+            .find_map(|detail| {
+                // a protobuf `Duration` in JSON: decimal seconds with an `s` suffix
+                let secs = detail["retryDelay"].as_str()?.strip_suffix('s')?;
+                Duration::try_from_secs_f64(secs.parse().ok()?).ok()
+            })
+    }
+
+    fn max_body_len(&self) -> usize {
+        self.max_body_len
+    }
+}
+
+/// Returns whether any of `codes` appears in `body` as a whole word.
+fn contains_code(body: &[u8], codes: &[impl AsRef<str>]) -> bool {
+    String::from_utf8_lossy(body)
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .any(|token| codes.iter().any(|code| code.as_ref() == token))
+}
+
+/// Reads up to `max_len` bytes from the body of `res`, returning them together with a response
+/// that still yields the whole body.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) async fn sniff(res: Response, max_len: usize) -> reqwest::Result<(Response, Bytes)> {
+    let url = res.url().clone();
+    let (mut parts, mut body) = http::Response::<Body>::from(res).into_parts();
+
+    let mut prefix = Vec::new();
+    let mut done = false;
+    while prefix.len() < max_len {
+        match body.frame().await.transpose()? {
+            Some(frame) => {
+                if let Ok(data) = frame.into_data() {
+                    prefix.extend_from_slice(&data);
+                }
+            }
+            None => {
+                done = true;
+                break;
+            }
+        }
+    }
+
+    let prefix = Bytes::from(prefix);
+    let body = match done {
+        true => Body::from(prefix.clone()),
+        false => Body::wrap(PrefixedBody {
+            prefix: Some(prefix.clone()),
+            rest: body,
+        }),
+    };
+
+    // the response URL can only be set through the builder
+    let (url_parts, body) = http::Response::builder()
+        .url(url)
+        .body(body)
+        .expect("an empty builder is always valid")
+        .into_parts();
+    parts.extensions.extend(url_parts.extensions);
+
+    let len = prefix.len().min(max_len);
+    Ok((
+        http::Response::from_parts(parts, body).into(),
+        prefix.slice(..len),
+    ))
+}
+
+/// Bodies can't be handed back to responses on `wasm32` targets, so none is read there.
+#[cfg(target_arch = "wasm32")]
+pub(crate) async fn sniff(res: Response, _: usize) -> reqwest::Result<(Response, Bytes)> {
+    Ok((res, Bytes::new()))
+}
+
+/// A body that yields already-read bytes before the rest of another body.
+#[cfg(not(target_arch = "wasm32"))]
+struct PrefixedBody {
+    prefix: Option<Bytes>,
+    rest: Body,
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl HttpBody for PrefixedBody {
+    type Data = Bytes;
+    type Error = reqwest::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if let Some(prefix) = self.prefix.take() {
+            return Poll::Ready(Some(Ok(Frame::data(prefix))));
+        }
+        Pin::new(&mut self.rest).poll_frame(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use http::StatusCode;
+
+    use super::{AwsThrottling, ObjectStoreSlowDown, ThrottleDetector};
+
+    #[test]
+    fn aws_codes() {
+        let detector = AwsThrottling::new(Duration::from_secs(2));
+
+        let json = br#"{"__type":"com.amazon.coral.availability#ThrottlingException"}"#;
+        let xml =
+            b"<Error><Code>SlowDown</Code><Message>Reduce your request rate.</Message></Error>";
+        let other = br#"{"__type":"ValidationException","message":"Throttlingish"}"#;
+
+        let status = StatusCode::BAD_REQUEST;
+        assert_eq!(
+            detector.detect("", status, json),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(
+            detector.detect("", status, xml),
+            Some(Duration::from_secs(2))
+        );
+        assert_eq!(detector.detect("", status, other), None);
+        assert!(!detector.applies_to(StatusCode::OK));
+    }
+
+    #[test]
+    fn object_store_backoff() {
+        let detector = ObjectStoreSlowDown::new(Duration::from_secs(1), Duration::from_secs(5));
+
+        let s3: &[u8] = b"<Error><Code>SlowDown</Code></Error>";
+        let azure: &[u8] = b"<Error><Code>ServerBusy</Code></Error>";
+
+        let status = StatusCode::SERVICE_UNAVAILABLE;
+        let delays: Vec<_> = [s3, azure, s3, s3]
+            .iter()
+            .map(|body| detector.detect("", status, body))
+            .collect();
+        assert_eq!(
+            delays,
+            [1, 2, 4, 5].map(|secs| Some(Duration::from_secs(secs)))
+        );
+
+        detector.reset("");
+        assert_eq!(
+            detector.detect("", status, s3),
+            Some(Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn object_store_backoff_per_key() {
+        let detector = ObjectStoreSlowDown::new(Duration::from_secs(1), Duration::from_secs(60));
+        let s3: &[u8] = b"<Error><Code>SlowDown</Code></Error>";
+        let status = StatusCode::SERVICE_UNAVAILABLE;
+
+        let delays: Vec<_> = ["logs/", "images/", "logs/", "images/", "logs/"]
+            .iter()
+            .map(|key| detector.detect(key, status, s3))
+            .collect();
+        assert_eq!(
+            delays,
+            [1, 1, 2, 2, 4].map(|secs| Some(Duration::from_secs(secs)))
+        );
+
+        // a response that goes through only resets its own key
+        detector.reset("images/");
+        assert_eq!(
+            detector.detect("logs/", status, s3),
+            Some(Duration::from_secs(8))
+        );
+        assert_eq!(
+            detector.detect("images/", status, s3),
+            Some(Duration::from_secs(1))
+        );
+    }
+
+    #[cfg(feature = "google")]
+    #[test]
+    fn google_retry_info() {
+        let detector = super::GoogleRetryInfo::new();
+
+        let body = br#"{
+            "error": {
+                "code": 429,
+                "status": "RESOURCE_EXHAUSTED",
+                "details": [
+                    { "@type": "type.googleapis.com/google.rpc.ErrorInfo", "reason": "RATE_LIMIT_EXCEEDED" },
+                    { "@type": "type.googleapis.com/google.rpc.RetryInfo", "retryDelay": "3.5s" }
+                ]
+            }
+        }"#;
+
+        let status = StatusCode::TOO_MANY_REQUESTS;
+        assert_eq!(
+            detector.detect("", status, body),
+            Some(Duration::from_millis(3500))
+        );
+        assert_eq!(
+            detector.detect("", status, br#"{"error": {"code": 429}}"#),
+            None
+        );
+        assert_eq!(detector.detect("", status, b"not json"), None);
+    }
+}
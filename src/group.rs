@@ -0,0 +1,236 @@
+//! Widening a throttle from the key of a request to a group of keys.
+//!
+//! Some limits aren't enforced by the server a request was meant for, but by something in
+//! front of it. A [`KeyGroup`] registered with
+//! [`RetryAfterMiddlewareBuilder::with_key_group`] tells which group a request belongs to,
+//! and recognizes the responses limited for the whole group. The `Retry-After` of such a
+//! response is then also stored under the key of the group, and every request in the group
+//! waits for it along with the value stored under its own key.
+//!
+//! [`CdnEdge`] groups the requests to a host behind a CDN, whose edge limits the whole host
+//! whatever the path. [`ProxyGroup`] groups the requests sent through a forward proxy, which
+//! limits all of them whatever their destination.
+//!
+//! Values stored for a group are kept by the middleware only, and not written to its
+//! [store](crate::store) or published through its [coordinator](crate::coordinator).
+//!
+//! [`RetryAfterMiddlewareBuilder::with_key_group`]: crate::RetryAfterMiddlewareBuilder::with_key_group
+
+use http::{HeaderMap, HeaderName, StatusCode};
+use reqwest_middleware::reqwest::{Request, Url};
+
+/// Groups requests whose limit may be shared, and recognizes the responses limited for the
+/// whole group.
+pub trait KeyGroup: Send + Sync {
+    /// Returns the key of the group `req` belongs to, if it belongs to one.
+    fn group(&self, req: &Request) -> Option<String>;
+
+    /// Returns whether a throttled response with `status` and `headers`, to a request in a
+    /// group, was limited for the whole group rather than for the request's own key.
+    fn limits_group(&self, status: StatusCode, headers: &HeaderMap) -> bool;
+}
+
+/// Groups the requests to a host, and recognizes the responses of the CDN edge in front of it
+/// by their headers.
+///
+/// CDN edges limit a whole host, while the middleware's [scope](crate::Scope) may keep
+/// requests to different paths apart. A throttled response carrying one of the edge's headers
+/// holds back every request to its host. By default, `server: cloudflare`, `cf-ray` and
+/// Fastly's `x-served-by` are recognized. Since a CDN adds its headers to the responses of the
+/// origin behind it too, a throttle sent by the origin is grouped as well.
+///
+/// ```
+/// use http::HeaderName;
+/// use reqwest_retry_after::{group::CdnEdge, RetryAfterMiddleware, Scope};
+///
+/// let middleware = RetryAfterMiddleware::builder()
+///     .with_scope(Scope::Url)
+///     .with_key_group(CdnEdge::new().with_header(HeaderName::from_static("x-amz-cf-id")))
+///     .build();
+/// ```
+#[derive(Debug, Clone)]
+pub struct CdnEdge {
+    headers: Vec<(HeaderName, Option<String>)>,
+}
+
+impl CdnEdge {
+    /// Creates a grouping recognizing the default edge headers.
+    pub fn new() -> Self {
+        Self::empty()
+            .with_header_value(http::header::SERVER, "cloudflare")
+            .with_header(HeaderName::from_static("cf-ray"))
+            .with_header(HeaderName::from_static("x-served-by"))
+    }
+
+    /// Creates a grouping recognizing no header, to be configured from scratch.
+    pub fn empty() -> Self {
+        Self {
+            headers: Vec::new(),
+        }
+    }
+
+    /// Also recognizes responses carrying a `name` header, whatever its value.
+    pub fn with_header(mut self, name: HeaderName) -> Self {
+        self.headers.push((name, None));
+        self
+    }
+
+    /// Also recognizes responses carrying a `name` header that contains `value`, ignoring
+    /// case.
+    pub fn with_header_value(mut self, name: HeaderName, value: &str) -> Self {
+        self.headers.push((name, Some(value.to_ascii_lowercase())));
+        self
+    }
+}
+
+impl Default for CdnEdge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeyGroup for CdnEdge {
+    fn group(&self, req: &Request) -> Option<String> {
+        req.url().host_str().map(str::to_owned)
+    }
+
+    fn limits_group(&self, _: StatusCode, headers: &HeaderMap) -> bool {
+        self.headers.iter().any(|(name, value)| {
+            headers.get_all(name).iter().any(|val| match value {
+                None => true,
+                Some(value) => val
+                    .to_str()
+                    .is_ok_and(|val| val.to_ascii_lowercase().contains(value)),
+            })
+        })
+    }
+}
+
+/// Groups the requests sent through a forward proxy, and recognizes the responses the proxy
+/// sent itself.
+///
+/// A proxy that throttles holds back all traffic through it, not just the requests to one
+/// destination. A throttled response is recognized as the proxy's when its status is
+/// `407 Proxy Authentication Required`, or when it carries one of the proxy's headers: by
+/// default, the `Proxy-Status` of RFC 9209 and Squid's `X-Squid-Error`. A `407` is not
+/// throttling by default, so its `Retry-After` is only honored once added with
+/// [`with_throttle_status`](crate::RetryAfterMiddlewareBuilder::with_throttle_status).
+///
+/// reqwest doesn't tell which requests went through a proxy, so the grouping is given the
+/// proxy the client was built with, and the hosts it bypasses. Values are stored under the
+/// origin of the proxy.
+///
+/// ```
+/// use http::StatusCode;
+/// use reqwest_retry_after::{group::ProxyGroup, RetryAfterMiddleware, Scope};
+///
+/// let proxy = "http://proxy.internal:3128".parse().unwrap();
+/// let middleware = RetryAfterMiddleware::builder()
+///     .with_scope(Scope::Origin)
+///     .with_throttle_status(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+///     .with_key_group(ProxyGroup::new(&proxy).bypassing("localhost"))
+///     .build();
+/// ```
+#[derive(Debug, Clone)]
+pub struct ProxyGroup {
+    key: String,
+    headers: Vec<HeaderName>,
+    bypassed: Vec<String>,
+}
+
+impl ProxyGroup {
+    /// Creates a grouping of the requests sent through `proxy`, recognizing the default proxy
+    /// headers.
+    pub fn new(proxy: &Url) -> Self {
+        Self {
+            key: proxy.origin().ascii_serialization(),
+            headers: vec![
+                HeaderName::from_static("proxy-status"),
+                HeaderName::from_static("x-squid-error"),
+            ],
+            bypassed: Vec::new(),
+        }
+    }
+
+    /// Also recognizes responses carrying a `name` header as the proxy's.
+    pub fn with_header(mut self, name: HeaderName) -> Self {
+        self.headers.push(name);
+        self
+    }
+
+    /// Leaves requests to `host` and its subdomains out of the group, like the client's
+    /// [`NoProxy`](reqwest::NoProxy) would.
+    pub fn bypassing(mut self, host: &str) -> Self {
+        self.bypassed
+            .push(host.trim_start_matches('.').to_ascii_lowercase());
+        self
+    }
+}
+
+impl KeyGroup for ProxyGroup {
+    fn group(&self, req: &Request) -> Option<String> {
+        let host = req
+            .url()
+            .host_str()
+            .unwrap_or_default()
+            .to_ascii_lowercase();
+        let bypassed = self.bypassed.iter().any(|bypassed| {
+            host.strip_suffix(bypassed.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.ends_with('.'))
+        });
+        (!bypassed).then(|| self.key.clone())
+    }
+
+    fn limits_group(&self, status: StatusCode, headers: &HeaderMap) -> bool {
+        status == StatusCode::PROXY_AUTHENTICATION_REQUIRED
+            || self.headers.iter().any(|name| headers.contains_key(name))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use http::{HeaderMap, HeaderValue, Method, StatusCode};
+    use reqwest_middleware::reqwest::Request;
+
+    use super::{CdnEdge, KeyGroup, ProxyGroup};
+
+    #[test]
+    fn cdn_edge_headers() {
+        let edge = CdnEdge::new();
+        let status = StatusCode::TOO_MANY_REQUESTS;
+        let headers = |name: &'static str, value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, HeaderValue::from_static(value));
+            headers
+        };
+
+        assert!(edge.limits_group(status, &headers("server", "Cloudflare")));
+        assert!(edge.limits_group(status, &headers("cf-ray", "8a1b2c3d4e5f-AMS")));
+        assert!(edge.limits_group(status, &headers("x-served-by", "cache-ams21")));
+        assert!(!edge.limits_group(status, &headers("server", "nginx")));
+        assert!(!CdnEdge::empty().limits_group(status, &headers("cf-ray", "8a1b2c3d4e5f-AMS")));
+    }
+
+    #[test]
+    fn proxy_group() {
+        let proxy = ProxyGroup::new(&"http://proxy.internal:3128/".parse().unwrap())
+            .bypassing(".example.com");
+        let group = |url: &str| proxy.group(&Request::new(Method::GET, url.parse().unwrap()));
+        assert_eq!(
+            group("https://api.github.com/"),
+            Some("http://proxy.internal:3128".into())
+        );
+        assert_eq!(group("https://example.com/"), None);
+        assert_eq!(group("https://api.example.com/"), None);
+        assert!(group("https://notexample.com/").is_some());
+
+        let mut headers = HeaderMap::new();
+        assert!(proxy.limits_group(StatusCode::PROXY_AUTHENTICATION_REQUIRED, &headers));
+        assert!(!proxy.limits_group(StatusCode::TOO_MANY_REQUESTS, &headers));
+        headers.insert(
+            "proxy-status",
+            HeaderValue::from_static("squid; error=http_429"),
+        );
+        assert!(proxy.limits_group(StatusCode::TOO_MANY_REQUESTS, &headers));
+    }
+}
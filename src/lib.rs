@@ -18,139 +18,4622 @@
 //!
 //! ## Notes
 //!
-//! A client constructed with [`RetryAfterMiddleware`] will apply the `Retry-After` header
-//! to all future requests, regardless of domain or URL. This means that if you query one URL
-//! which sets a `Retry-After`, and then query a different URL that has no ratelimiting,
+//! By default, a client constructed with [`RetryAfterMiddleware`] will apply the `Retry-After`
+//! header to all future requests, regardless of domain or URL. This means that if you query
+//! one URL which sets a `Retry-After`, and then query a different URL that has no ratelimiting,
 //! the `Retry-After` will be applied to the new URL.
 //!
-//! If you need this functionality, consider creating a seperate client for each endpoint.
+//! To keep one `Retry-After` from holding back unrelated requests, scope stored values to the
+//! URL, origin or host of the response with [`RetryAfterMiddlewareBuilder::with_scope`].
+//! Limits that are wider than a scope, such as those of a CDN edge, can be recognized with a
+//! [key group](group).
+//!
+//! ## Features
+//!
+//! - `http-date` (enabled by default): parse `Retry-After` values given as HTTP dates, in any
+//!   of the three formats of RFC 9110, including the obsolete RFC 850 and asctime ones. When
+//!   disabled, the `time` dependency is dropped and only delay-seconds values are honored; a
+//!   date is treated like any other unparseable value.
+//! - `futures-timer`: wait with [`futures-timer`](https://docs.rs/futures-timer) instead of
+//!   tokio's timer by default, for applications that don't run a tokio runtime.
+//! - `google`: the `detect::GoogleRetryInfo` detector for Google API error bodies.
+//! - `mock-server`: `testing::ThrottlingServer`, a local server that throttles requests, for
+//!   integration tests.
+//! - `serde`: `Serialize` and `Deserialize` for [`SavedState`], so that it can be persisted
+//!   across restarts.
+//! - `redis`: `store::RedisStore`, which shares stored values between processes through a
+//!   Redis server.
+//! - `sled`: `store::SledStore`, which keeps stored values in an embedded database, so that
+//!   they survive restarts.
+//! - `global`: `RetryAfterMiddleware::global`, which shares one lazily created state across
+//!   the whole process.
+//! - `prometheus`: `stats::RetryAfterMetrics`, which exposes the statistics of a middleware as
+//!   Prometheus metrics.
+//! - `log`: a [`log`](https://docs.rs/log) warning when a throttle longer than a minute, or
+//!   the threshold set with `RetryAfterMiddlewareBuilder::with_log_threshold`, is recorded,
+//!   and an info message whenever a request is delayed.
+//! - `blocking`: `blocking::RetryAfterClient`, which honors `Retry-After` in
+//!   `reqwest::blocking` clients by blocking the calling thread.
+//! - `retry-policies`: `retry::RetryAfterPolicy`, which keeps the retry schedule of
+//!   [`reqwest-retry`](https://docs.rs/reqwest-retry) from retrying before a stored
+//!   `Retry-After` has passed.
+//! - `tracing`: debug-level [`tracing`](https://docs.rs/tracing) events when a throttle is
+//!   recorded, with its key, the header it was read from and the wait, when it is cleared,
+//!   and when a request starts waiting.
+//!
+//! ## WebAssembly
+//!
+//! The middleware also works in browsers, on `wasm32-unknown-unknown`. There, it waits with
+//! `futures-timer` on top of the browser's timers, and tells the time from the JavaScript host,
+//! see the [`clock`] module. Responses have no extensions on that target, and bodies are not
+//! shown to [detectors](detect).
+//!
+//! The preflights a browser sends on its own never go through the middleware, and a throttled
+//! one fails the request. Preflights sent as requests share the key of the request they are
+//! made for, see [`KeyExtractor`].
+//!
+//! ## Per-request limits
+//!
+//! A request can bound how long it is willing to wait by carrying a [`MaxWaitOverride`]
+//! extension. If the remaining `Retry-After` is longer than the override, the request only
+//! waits for the override before being sent, or fails if the middleware is set to fail
+//! excessive delays.
+//!
+//! ```
+//! # async fn run(client: reqwest_middleware::ClientWithMiddleware) {
+//! use std::time::Duration;
+//! use reqwest_retry_after::MaxWaitOverride;
+//!
+//! let res = client
+//!     .get("https://example.com")
+//!     .with_extension(MaxWaitOverride(Duration::from_secs(2)))
+//!     .send()
+//!     .await;
+//! # }
+//! ```
+//!
+//! A request carrying [`IgnoreRetryAfter`] goes out right away, and its response's
+//! `Retry-After` is ignored.
+//!
+//! ## Custom clients
+//!
+//! Clients that send requests on their own can still honor `Retry-After` with a
+//! [`RetryAfterTracker`], which reads it off the responses it is shown and tells how long
+//! requests have to wait, without any I/O.
+//!
+//! ## Request spans
+//!
+//! With the `tracing` feature, the middleware also records what it did on the span it runs
+//! in, so that traces show client-side throttling as part of the request timeline. Installed
+//! after [`reqwest-tracing`](https://docs.rs/reqwest-tracing)'s `TracingMiddleware`, that is
+//! the request span, and the fields end up as OpenTelemetry attributes. A span only records
+//! fields it declares, so a custom `ReqwestOtelSpanBackend` has to declare them as empty in
+//! `reqwest_otel_span!`:
+//!
+//! - `retry_after.key`: the key of the request, see [`RetryAfterMiddlewareBuilder::with_scope`].
+//! - `retry_after.waited_ms`: how long the request waited, in milliseconds, if it did.
+//! - `retry_after.source_header`: the header the throttle of the response was read from, if any.
 #![warn(missing_docs)]
 #![warn(rustdoc::missing_doc_code_examples)]
 
-use std::time::{Duration, SystemTime};
+#[cfg(feature = "blocking")]
+pub mod blocking;
+mod builder;
+pub mod clock;
+pub mod coordinator;
+pub mod detect;
+pub mod group;
+pub mod observer;
+mod parse;
+pub mod rate_limit;
+#[cfg(feature = "retry-policies")]
+pub mod retry;
+pub mod stats;
+pub mod store;
+pub mod testing;
+mod tracker;
+
+use std::{
+    collections::{hash_map::RandomState, HashMap, VecDeque},
+    future::{poll_fn, Future},
+    hash::{BuildHasher, Hasher},
+    pin::pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::Poll,
+    time::{Duration, SystemTime},
+};
+
+pub use builder::RetryAfterMiddlewareBuilder;
+use clock::{Clock, Sleeper};
+use coordinator::Coordinator;
+use detect::ThrottleDetector;
+use group::KeyGroup;
+use observer::RetryAfterObserver;
+use rate_limit::RateLimitPolicy;
+use stats::{Event, EventKind, HostStats, RetryAfterEvent};
+use store::RetryAfterStore;
+pub use tracker::RetryAfterTracker;
+use tracker::{Recorded, Verdict};
 
-use http::{header::RETRY_AFTER, Extensions};
+use http::{
+    header::{ACCESS_CONTROL_REQUEST_HEADERS, ACCESS_CONTROL_REQUEST_METHOD},
+    Extensions, Method, StatusCode,
+};
 use reqwest_middleware::{
-    reqwest::{Request, Response},
+    reqwest::{Request, Response, Url},
     Middleware, Next, Result,
 };
-use time::{format_description::well_known::Rfc2822, OffsetDateTime};
-use tokio::sync::RwLock;
+use tokio::sync::{broadcast, watch};
+
+type PersistCallback = Arc<dyn Fn(&Url, SystemTime) + Send + Sync>;
+type UnpersistCallback = Arc<dyn Fn(&Url) + Send + Sync>;
+type DeprecationCallback = Arc<dyn Fn(&Url, &DeprecationNotice) + Send + Sync>;
+type WindowCallback = Arc<dyn Fn(&Url, Duration) + Send + Sync>;
+type ViolationCallback = Arc<dyn Fn(&Url, &RetryAfterViolation) + Send + Sync>;
+type StatusFilter = Arc<dyn Fn(StatusCode) -> bool + Send + Sync>;
 
 /// The `RetryAfterMiddleware` is a [`Middleware`] that adds support for the `Retry-After`
 /// header in [`reqwest`].
+///
+/// Clones share the state and configuration of the original, so a clone can be kept for
+/// introspection while the original is handed to the client builder.
+#[derive(Clone)]
 pub struct RetryAfterMiddleware {
-    retry_after: RwLock<Option<SystemTime>>,
+    tracker: RetryAfterTracker,
+    sleeper: Arc<dyn Sleeper>,
+    sleeping: watch::Sender<usize>,
+    on_persist: Option<PersistCallback>,
+    on_unpersist: Option<UnpersistCallback>,
+    on_limited: Vec<(String, PersistCallback)>,
+    on_long_window: Option<(Duration, WindowCallback)>,
+    coordinator: Option<Arc<dyn Coordinator>>,
+    on_deprecation: Option<DeprecationCallback>,
+    detectors: Vec<Arc<dyn ThrottleDetector>>,
+    groups: Vec<Arc<dyn KeyGroup>>,
+    sleep_chunk: Option<Duration>,
+    release_stagger: Option<Duration>,
+    recovery_probe: Option<reqwest::Client>,
+    predictive_delay: Option<Duration>,
+    event_history: usize,
+    on_violation: Option<ViolationCallback>,
+    retries: u32,
+    store: Option<Arc<dyn RetryAfterStore>>,
+    observers: Vec<Arc<dyn RetryAfterObserver>>,
+    #[cfg(feature = "log")]
+    log_threshold: Duration,
 }
 
 impl RetryAfterMiddleware {
-    /// Creates a new `RetryAfterMiddleware`.
+    /// Creates a new `RetryAfterMiddleware` with the default configuration.
     pub fn new() -> Self {
+        Self::builder().build()
+    }
+
+    /// Creates a new `RetryAfterMiddleware` that shares its `Retry-After` state with every
+    /// other middleware created with the same `name`, anywhere in the process.
+    ///
+    /// This is a shorthand for [`RetryAfterMiddlewareBuilder::named`].
+    pub fn named(name: &str) -> Self {
+        Self::builder().named(name).build()
+    }
+
+    /// Creates a new `RetryAfterMiddleware` over `state`, which it shares with every other
+    /// middleware created over it.
+    ///
+    /// This is a shorthand for [`RetryAfterMiddlewareBuilder::with_state`].
+    pub fn with_state(state: RetryAfterState) -> Self {
+        Self::builder().with_state(state).build()
+    }
+
+    /// Creates a new `RetryAfterMiddleware` over the [process-wide state](RetryAfterState::global).
+    ///
+    /// Libraries that build their own clients internally can use this to coordinate with
+    /// every other client in the process, without being handed a state. Requires the `global`
+    /// feature.
+    ///
+    /// ```
+    /// use reqwest_middleware::ClientBuilder;
+    /// use reqwest_retry_after::RetryAfterMiddleware;
+    ///
+    /// let client = ClientBuilder::new(reqwest::Client::new())
+    ///     .with(RetryAfterMiddleware::global())
+    ///     .build();
+    /// ```
+    #[cfg(feature = "global")]
+    pub fn global() -> Self {
+        Self::with_state(RetryAfterState::global())
+    }
+
+    /// Returns a handle over the middleware's state, to create more middlewares sharing it with
+    /// [`with_state`](RetryAfterMiddlewareBuilder::with_state).
+    pub fn state(&self) -> RetryAfterState {
+        RetryAfterState(self.tracker.state.clone())
+    }
+
+    /// Returns a builder to configure a new `RetryAfterMiddleware`.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use reqwest_retry_after::{RetryAfterMiddleware, Scope};
+    ///
+    /// let middleware = RetryAfterMiddleware::builder()
+    ///     .with_scope(Scope::Host)
+    ///     .with_max_ttl(Duration::from_secs(3600))
+    ///     .build();
+    /// ```
+    pub fn builder() -> RetryAfterMiddlewareBuilder {
+        RetryAfterMiddlewareBuilder::from_state(Arc::default())
+    }
+
+    pub(crate) fn from_state(state: Arc<State>) -> Self {
         Self {
-            retry_after: RwLock::new(None),
+            tracker: RetryAfterTracker::from_state(state),
+            sleeper: clock::default_sleeper(),
+            sleeping: watch::Sender::new(0),
+            on_persist: None,
+            on_unpersist: None,
+            on_limited: Vec::new(),
+            on_long_window: None,
+            coordinator: None,
+            on_deprecation: None,
+            detectors: Vec::new(),
+            groups: Vec::new(),
+            sleep_chunk: None,
+            release_stagger: None,
+            recovery_probe: None,
+            predictive_delay: None,
+            event_history: 0,
+            on_violation: None,
+            retries: 0,
+            store: None,
+            observers: Vec::new(),
+            #[cfg(feature = "log")]
+            log_threshold: Duration::from_secs(60),
         }
     }
-}
 
-impl Default for RetryAfterMiddleware {
-    fn default() -> Self {
-        Self::new()
+    /// Returns the longest wait across all keys, if any request still has to wait.
+    ///
+    /// This doesn't need an async context, so it can be called from GUI threads and other
+    /// synchronous code. The state is only ever locked to copy or replace a value, so this
+    /// never waits on in-flight requests.
+    ///
+    /// Unless the middleware has a [`Scope::Global`] scope, this can be the wait of requests to
+    /// another host or URL entirely; see [`poll_remaining_for`](Self::poll_remaining_for).
+    pub fn poll_remaining(&self) -> Option<Duration> {
+        self.reader().poll_remaining()
+    }
+
+    /// Returns how long a `GET` request to `url` still has to wait before being sent, if at
+    /// all, going by the value stored under its key.
+    ///
+    /// Like [`poll_remaining`](Self::poll_remaining), this can be called from synchronous
+    /// code.
+    pub fn poll_remaining_for(&self, url: &Url) -> Option<Duration> {
+        self.reader().poll_remaining_for(url)
     }
-}
 
-fn parse_retry_value(val: &str) -> Option<SystemTime> {
-    if let Ok(secs) = val.parse::<u64>() {
-        return Some(SystemTime::now() + Duration::from_secs(secs));
+    /// Returns how long `GET` requests to every URL in `urls` still have to wait before all of
+    /// them can be sent, if at all: the longest of their
+    /// [`poll_remaining_for`](Self::poll_remaining_for).
+    ///
+    /// Workflows that need several endpoints before starting a multi-call transaction can
+    /// wait this long first.
+    ///
+    /// ```
+    /// # let middleware = reqwest_retry_after::RetryAfterMiddleware::new();
+    /// let users = "https://api.example.com/users".parse().unwrap();
+    /// let orders = "https://api.example.com/orders".parse().unwrap();
+    /// if let Some(wait) = middleware.wait_all([&users, &orders]) {
+    ///     eprintln!("starting the transaction in {}s", wait.as_secs());
+    /// }
+    /// ```
+    pub fn wait_all<'a>(&self, urls: impl IntoIterator<Item = &'a Url>) -> Option<Duration> {
+        self.reader().wait_all(urls)
     }
-    if let Ok(date) = OffsetDateTime::parse(val, &Rfc2822) {
-        return Some(date.into());
+
+    /// Returns whether requests to `host` are currently sent without waiting.
+    ///
+    /// Health checks and readiness probes can use this, together with
+    /// [`throttled_until`](Self::throttled_until), to report a degraded upstream.
+    ///
+    /// ```
+    /// # let middleware = reqwest_retry_after::RetryAfterMiddleware::new();
+    /// if !middleware.is_host_available("api.github.com") {
+    ///     let until = middleware.throttled_until("api.github.com").unwrap();
+    ///     eprintln!("degraded: upstream throttling us until {until:?}");
+    /// }
+    /// ```
+    pub fn is_host_available(&self, host: &str) -> bool {
+        self.reader().is_host_available(host)
     }
-    None
-}
 
-#[async_trait::async_trait]
-impl Middleware for RetryAfterMiddleware {
-    async fn handle(
+    /// Returns when the window holding back requests to `host` expires, if one is active.
+    ///
+    /// With the default [`Scope::Global`], a stored `Retry-After` applies to every host, so any
+    /// active window is reported, whichever host sent it.
+    pub fn throttled_until(&self, host: &str) -> Option<SystemTime> {
+        self.reader().throttled_until(host)
+    }
+
+    /// Returns how long a `GET` request to `url` would still have to wait before being sent,
+    /// if at all.
+    ///
+    /// Applications can show the wait in a UI, or do other work first. The request's key is
+    /// derived like the middleware would, see
+    /// [`RetryAfterMiddlewareBuilder::with_scope`].
+    ///
+    /// ```
+    /// # let middleware = reqwest_retry_after::RetryAfterMiddleware::new();
+    /// let url = "https://api.github.com/repos".parse().unwrap();
+    /// if let Some(wait) = middleware.wait_remaining(&url) {
+    ///     eprintln!("rate limited, next page in {}s", wait.as_secs());
+    /// }
+    /// ```
+    pub fn wait_remaining(&self, url: &Url) -> Option<Duration> {
+        self.reader().wait_remaining(url)
+    }
+
+    /// Returns how long `req` would still have to wait before being sent, if at all.
+    ///
+    /// Unlike [`wait_remaining`](Self::wait_remaining), this sees the whole request, which
+    /// matters for [key extractors](RetryAfterMiddlewareBuilder::with_key_extractor) that look
+    /// at more than its URL.
+    pub fn wait_remaining_for(&self, req: &Request) -> Option<Duration> {
+        self.reader().wait_remaining_for(req)
+    }
+
+    /// Returns whether a `GET` request to `url` would have to wait before being sent.
+    ///
+    /// Schedulers can skip throttled work and come back to it later.
+    pub fn is_throttled(&self, url: &Url) -> bool {
+        self.reader().is_throttled(url)
+    }
+
+    /// Returns `Ok` if a `GET` request to `url` would be sent right away, and otherwise how
+    /// long it would still have to wait.
+    ///
+    /// ```
+    /// # let middleware = reqwest_retry_after::RetryAfterMiddleware::new();
+    /// let url = "https://api.github.com/repos".parse().unwrap();
+    /// if let Err(wait) = middleware.check(&url) {
+    ///     eprintln!("skipping, throttled for another {}s", wait.as_secs());
+    /// }
+    /// ```
+    pub fn check(&self, url: &Url) -> std::result::Result<(), Duration> {
+        self.reader().check(url)
+    }
+
+    /// Returns a copy of every stored value, sorted by key.
+    ///
+    /// Values are keyed like they are stored, see
+    /// [`RetryAfterMiddlewareBuilder::with_scope`], so with the default [`Scope::Global`] there
+    /// is at most one, under the empty key. Values that have passed but weren't cleared by a
+    /// response yet are included. The copy is taken under a single lock, so it is consistent.
+    pub fn snapshot(&self) -> Vec<(String, SystemTime)> {
+        self.reader().snapshot()
+    }
+
+    /// Returns the stored values that are still to come but pass within `horizon`, sorted by
+    /// key like [`snapshot`](Self::snapshot).
+    ///
+    /// Schedulers can pre-stage work for endpoints that are about to become available,
+    /// without [subscribing](Self::subscribe) to events.
+    pub fn expiring_within(&self, horizon: Duration) -> Vec<(String, SystemTime)> {
+        self.reader().expiring_within(horizon)
+    }
+
+    /// Returns throttling statistics for every host that has sent a `Retry-After`, keyed by
+    /// host name.
+    pub fn stats(&self) -> HashMap<String, HostStats> {
+        self.reader().stats()
+    }
+
+    /// Returns a summary of the throttling observed so far, with one entry per host.
+    ///
+    /// The [`Report`](stats::Report) can be printed, which makes it easy to see at the end of
+    /// a batch job or CI task how much time rate limits cost:
+    ///
+    /// ```
+    /// # let middleware = reqwest_retry_after::RetryAfterMiddleware::new();
+    /// eprint!("{}", middleware.report());
+    /// ```
+    pub fn report(&self) -> stats::Report {
+        self.reader().report()
+    }
+
+    /// Returns the quotas most recently advertised through a `RateLimit-Policy` header to
+    /// requests stored under the same key as a `GET` request to `url`.
+    ///
+    /// Policies are kept until a later response for the same key advertises different ones,
+    /// since servers usually don't repeat them on every response.
+    pub fn rate_limit_policies(&self, url: &Url) -> Vec<RateLimitPolicy> {
+        self.reader().rate_limit_policies(url)
+    }
+
+    /// Returns the most recent throttling events, oldest first.
+    ///
+    /// Only as many events as configured with
+    /// [`with_event_history`](RetryAfterMiddlewareBuilder::with_event_history) are kept.
+    ///
+    /// ```
+    /// use reqwest_retry_after::RetryAfterMiddleware;
+    ///
+    /// let middleware = RetryAfterMiddleware::builder().with_event_history(100).build();
+    /// for event in middleware.recent_events() {
+    ///     println!("{:?} {} {:?}", event.at, event.url, event.kind);
+    /// }
+    /// ```
+    pub fn recent_events(&self) -> Vec<Event> {
+        self.reader().recent_events()
+    }
+
+    /// Returns a receiver of every [`RetryAfterEvent`] from now on.
+    ///
+    /// Unlike [`recent_events`](Self::recent_events), this lets dashboards and schedulers react
+    /// to throttles as they happen. Events are only sent to existing receivers, and a receiver
+    /// that falls more than 256 events behind misses the oldest ones, see
+    /// [`broadcast::error::RecvError::Lagged`].
+    ///
+    /// ```
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// use reqwest_retry_after::{stats::RetryAfterEvent, RetryAfterMiddleware};
+    ///
+    /// let middleware = RetryAfterMiddleware::new();
+    /// let mut events = middleware.subscribe();
+    /// tokio::spawn(async move {
+    ///     while let Ok(event) = events.recv().await {
+    ///         if let RetryAfterEvent::Recorded { key, until } = event {
+    ///             println!("{key:?} throttled until {until:?}");
+    ///         }
+    ///     }
+    /// });
+    /// # }
+    /// ```
+    pub fn subscribe(&self) -> broadcast::Receiver<RetryAfterEvent> {
+        self.tracker.state.subscribers.subscribe()
+    }
+
+    /// Returns a read-only handle over the middleware's state.
+    ///
+    /// The handle can be cloned and handed to dashboards, admin endpoints or metrics
+    /// exporters, without giving them a way to change the state or to send requests.
+    ///
+    /// ```
+    /// use reqwest_retry_after::RetryAfterMiddleware;
+    ///
+    /// let middleware = RetryAfterMiddleware::new();
+    /// let reader = middleware.reader();
+    /// std::thread::spawn(move || println!("{:?}", reader.poll_remaining()));
+    /// ```
+    pub fn reader(&self) -> RetryAfterReader {
+        RetryAfterReader {
+            state: self.tracker.state.clone(),
+            clock: self.tracker.clock.clone(),
+            keys: self.tracker.keys.clone(),
+        }
+    }
+
+    /// Returns a [`RetryAfterTracker`] over the middleware's configuration and state, to honor
+    /// `Retry-After` in clients that send requests and wait on their own.
+    ///
+    /// ```
+    /// use std::time::SystemTime;
+    /// use reqwest_retry_after::RetryAfterMiddleware;
+    ///
+    /// let middleware = RetryAfterMiddleware::new();
+    /// let tracker = middleware.tracker();
+    /// let url = "https://api.github.com/".parse().unwrap();
+    /// let wait = tracker.next_wait(&tracker.key(&url), SystemTime::now()).unwrap();
+    /// assert_eq!(wait, None);
+    /// ```
+    pub fn tracker(&self) -> RetryAfterTracker {
+        self.tracker.clone()
+    }
+
+    /// Returns a handle to change the bound on waits, the jitter and dry-run mode of the
+    /// middleware while it runs.
+    ///
+    /// ```
+    /// use reqwest_retry_after::RetryAfterMiddleware;
+    ///
+    /// let middleware = RetryAfterMiddleware::builder().dry_run(true).build();
+    /// let config = middleware.config();
+    /// // later, once a feature flag says so
+    /// config.set_dry_run(false);
+    /// ```
+    pub fn config(&self) -> ConfigHandle {
+        self.tracker.config.clone()
+    }
+
+    /// Holds back requests to `url` until `until`, as if a response had sent a `Retry-After`.
+    ///
+    /// Applications can register cool-downs they learned about out of band. The value is
+    /// stored under the key of a `GET` request to `url`, replacing any stored value, and the
+    /// [TTL](RetryAfterMiddlewareBuilder::with_max_ttl) applies. Unlike a response, this
+    /// fires no callbacks and publishes nothing to a
+    /// [coordinator](RetryAfterMiddlewareBuilder::with_coordinator).
+    ///
+    /// ```
+    /// use std::time::{Duration, SystemTime};
+    /// use reqwest_retry_after::RetryAfterMiddleware;
+    ///
+    /// let middleware = RetryAfterMiddleware::new();
+    /// let url = "https://api.github.com/".parse().unwrap();
+    /// middleware.insert(&url, SystemTime::now() + Duration::from_secs(60));
+    /// assert!(middleware.wait_remaining(&url).is_some());
+    ///
+    /// middleware.remove(&url);
+    /// assert!(middleware.wait_remaining(&url).is_none());
+    /// ```
+    pub fn insert(&self, url: &Url, until: SystemTime) {
+        let key = self.tracker.key(url);
+        let now = self.tracker.clock.now();
+        let until = self.tracker.clamp_ttl(until, now);
+        let mut stored = self.tracker.state.retry_after.lock().unwrap();
+        stored.insert(key.clone(), until);
+        self.tracker.bound_entries(&mut stored, Some(&key), now);
+    }
+
+    /// Lets requests to `url` through again, forgetting the value stored for them.
+    ///
+    /// See [`insert`](Self::insert).
+    pub fn remove(&self, url: &Url) {
+        let key = self.tracker.key(url);
+        self.tracker.state.retry_after.lock().unwrap().remove(&key);
+    }
+
+    /// Returns the stored values, to be restored with [`load_state`](Self::load_state), for
+    /// instance after a restart.
+    ///
+    /// With the `serde` feature, the [`SavedState`] can be written to disk in any format.
+    pub fn save_state(&self) -> SavedState {
+        SavedState {
+            retry_after: self.snapshot(),
+        }
+    }
+
+    /// Restores values returned by [`save_state`](Self::save_state).
+    ///
+    /// Values that have passed in the meantime are skipped, and a value already stored for
+    /// the same key is only replaced by a later one.
+    ///
+    /// ```
+    /// use std::time::{Duration, SystemTime};
+    /// use reqwest_retry_after::RetryAfterMiddleware;
+    ///
+    /// let url = "https://api.github.com/".parse().unwrap();
+    /// let before = RetryAfterMiddleware::new();
+    /// before.insert(&url, SystemTime::now() + Duration::from_secs(60));
+    /// let saved = before.save_state();
+    ///
+    /// let after = RetryAfterMiddleware::new();
+    /// after.load_state(saved);
+    /// assert!(after.wait_remaining(&url).is_some());
+    /// ```
+    pub fn load_state(&self, saved: SavedState) {
+        let now = self.tracker.clock.now();
+        let mut stored = self.tracker.state.retry_after.lock().unwrap();
+        for (key, until) in saved.retry_after {
+            if until <= now {
+                continue;
+            }
+            let until = self.tracker.clamp_ttl(until, now);
+            let stored = stored.entry(key).or_insert(until);
+            *stored = (*stored).max(until);
+        }
+        self.tracker.bound_entries(&mut stored, None, now);
+    }
+
+    /// Forgets every stored value, letting all requests through again.
+    ///
+    /// Statistics and events are kept. See [`insert`](Self::insert).
+    pub fn clear(&self) {
+        self.tracker.state.retry_after.lock().unwrap().clear();
+    }
+
+    /// Forgets every stored value that has passed, returning how many there were.
+    ///
+    /// A value is otherwise only forgotten when a response clears it, so a client that
+    /// touches many unique URLs with a narrow [scope](RetryAfterMiddlewareBuilder::with_scope)
+    /// keeps values for URLs it never requests again.
+    /// [`eviction_task`](Self::eviction_task) calls this periodically.
+    pub fn evict_expired(&self) -> usize {
+        let expired = self.tracker.state.evict(self.tracker.clock.now());
+        let count = expired.len();
+        for key in expired {
+            let _ = self
+                .tracker
+                .state
+                .subscribers
+                .send(RetryAfterEvent::Expired { key });
+        }
+        count
+    }
+
+    /// Returns a task that calls [`evict_expired`](Self::evict_expired) every `interval`, and a
+    /// handle to stop it.
+    ///
+    /// The task waits with the middleware's [sleeper](RetryAfterMiddlewareBuilder::with_sleeper),
+    /// and can be spawned on any executor. It runs until [`EvictionHandle::shutdown`] is
+    /// called or the handle is dropped.
+    ///
+    /// ```
+    /// # #[tokio::main(flavor = "current_thread")]
+    /// # async fn main() {
+    /// use std::time::Duration;
+    /// use reqwest_retry_after::RetryAfterMiddleware;
+    ///
+    /// let middleware = RetryAfterMiddleware::new();
+    /// let (task, eviction) = middleware.eviction_task(Duration::from_secs(60));
+    /// let task = tokio::spawn(task);
+    ///
+    /// // ...
+    /// eviction.shutdown();
+    /// task.await.unwrap();
+    /// # }
+    /// ```
+    pub fn eviction_task(
         &self,
-        req: Request,
-        extensions: &mut Extensions,
-        next: Next<'_>,
-    ) -> Result<Response> {
-        let lock = *self.retry_after.read().await;
+        interval: Duration,
+    ) -> (impl Future<Output = ()> + Send + 'static, EvictionHandle) {
+        let (shutdown, mut stopped) = watch::channel(());
+        let (state, clock, sleeper, events) = (
+            self.tracker.state.clone(),
+            self.tracker.clock.clone(),
+            self.sleeper.clone(),
+            self.tracker.state.subscribers.clone(),
+        );
+        let task = async move {
+            loop {
+                let mut sleep = sleeper.sleep(interval);
+                let mut stop = pin!(stopped.changed());
+                let slept = poll_fn(|cx| match stop.as_mut().poll(cx) {
+                    Poll::Ready(_) => Poll::Ready(false),
+                    Poll::Pending => sleep.as_mut().poll(cx).map(|()| true),
+                });
+                if !slept.await {
+                    return;
+                }
+                for key in state.evict(clock.now()) {
+                    let _ = events.send(RetryAfterEvent::Expired { key });
+                }
+            }
+        };
+        (task, EvictionHandle(shutdown))
+    }
 
-        if let Some(it) = lock {
-            let now = SystemTime::now();
+    /// Waits until no request is sleeping inside the middleware.
+    ///
+    /// Batch jobs can await this before exiting to tell "every request finished" apart from
+    /// "some requests are still waiting out a `Retry-After`". Requests that start waiting after
+    /// this resolves are not tracked.
+    pub async fn block_until_idle(&self) {
+        let mut sleeping = self.sleeping.subscribe();
+        // the sender lives as long as `self`, so this can't fail
+        let _ = sleeping.wait_for(|count| *count == 0).await;
+    }
+
+    /// Sleeps for `duration`, or in chunks until the value stored for `key` passes, and returns
+    /// how long the request waited. No chunked wait lasts longer than `max`.
+    async fn sleep(&self, key: &str, duration: Duration, max: Option<Duration>) -> Duration {
+        self.sleeping.send_modify(|count| *count += 1);
+        let _guard = SleepGuard(&self.sleeping);
+
+        // without chunks, the stored value is still re-read after waking, in case a response
+        // extended it in the meantime
+        let chunk = self.sleep_chunk.unwrap_or(Duration::MAX);
+        let start = self.tracker.clock.now();
+        // the stored value is re-read between chunks; the jitter, which may have shortened the
+        // wait as well as lengthened it, moves along with it
+        let stored = self.tracker.state.until(key);
+        let deadline = start + duration;
+        let mut slept = Duration::ZERO;
+        let mut remaining = duration;
+        #[cfg(feature = "tracing")]
+        tracing::debug!(key, remaining = ?remaining, "waiting before send");
+        #[cfg(feature = "log")]
+        log::info!("delaying a request with key {key:?} for {duration:?}");
+        for observer in &self.observers {
+            observer.on_wait_start(key, duration).await;
+        }
+        let _ = self
+            .tracker
+            .state
+            .subscribers
+            .send(RetryAfterEvent::WaitStarted {
+                key: key.to_owned(),
+                duration,
+            });
+        while !remaining.is_zero() {
+            let step = remaining.min(chunk);
+            self.sleeper.sleep(step).await;
+            slept += step;
 
-            if let Ok(duration) = it.duration_since(now) {
-                tokio::time::sleep(duration).await
+            // a sleeper may return without the clock moving, so count the slept time too
+            let waited = self
+                .tracker
+                .clock
+                .now()
+                .duration_since(start)
+                .unwrap_or_default()
+                .max(slept);
+            let target = match (stored, self.tracker.state.until(key)) {
+                (Some(stored), Some(until)) => match until.duration_since(stored) {
+                    Ok(extended) => deadline + extended,
+                    Err(shortened) => deadline.checked_sub(shortened.duration()).unwrap_or(start),
+                },
+                (Some(_), None) => start,
+                (None, until) => until.map_or(deadline, |until| until.max(deadline)),
+            };
+            remaining = target.duration_since(start + waited).unwrap_or_default();
+            if let Some(max) = max {
+                remaining = remaining.min(max.saturating_sub(waited));
             }
         }
+        for observer in &self.observers {
+            observer.on_wait_end(key).await;
+        }
+        let _ = self
+            .tracker
+            .state
+            .subscribers
+            .send(RetryAfterEvent::WaitFinished {
+                key: key.to_owned(),
+                waited: slept,
+            });
+        slept
+    }
+
+    /// Returns how much longer than the value stored for `key` a request waiting for it waits,
+    /// going by how many requests started waiting for the same value before it.
+    fn stagger(&self, key: &str) -> Duration {
+        let (Some(gap), Some(until)) = (self.release_stagger, self.tracker.state.until(key)) else {
+            return Duration::ZERO;
+        };
+        let mut released = self.tracker.state.released.lock().unwrap();
+        let (released_until, count) = released.entry(key.to_owned()).or_insert((until, 0));
+        if *released_until != until {
+            *released_until = until;
+            *count = 0;
+        }
+        let stagger = gap.saturating_mul(*count);
+        *count = count.saturating_add(1);
+        stagger
+    }
 
-        let res = next.run(req, extensions).await;
+    /// Probes `url` once the value stored for `key` has passed, until a probe doesn't store
+    /// another value still to come, and returns how long the request waited in the meantime.
+    ///
+    /// Fails if a probe's response breaks strict parsing set to fail.
+    async fn recover(&self, client: &reqwest::Client, key: &str, url: &Url) -> Result<Duration> {
+        let mut waited = Duration::ZERO;
+        let probing = {
+            let mut probing = self.tracker.state.probing.lock().unwrap();
+            probing.entry(key.to_owned()).or_default().clone()
+        };
+        loop {
+            // only requests with the same key wait for each other's probes
+            let leader = probing.lock().await;
 
-        if let Ok(res) = &res {
-            match res.headers().get(RETRY_AFTER) {
-                Some(retry_after) => {
-                    if let Ok(val) = retry_after.to_str() {
-                        *self.retry_after.write().await = parse_retry_value(val);
+            // another request may have probed successfully while this one waited for the lock
+            let Some(until) = self.tracker.state.until(key) else {
+                return Ok(waited);
+            };
+            if let Some(remaining) = until
+                .duration_since(self.tracker.clock.now())
+                .ok()
+                .filter(|remaining| !remaining.is_zero())
+            {
+                drop(leader);
+                waited += self.sleep(key, remaining, None).await;
+                continue;
+            }
+
+            match client.head(url.clone()).send().await {
+                // stores the probe's `Retry-After`, or clears the stored value
+                Ok(res) => drop(self.observe(res, key).await?),
+                // the probe tells nothing, so let the request find out for itself
+                Err(_) => return Ok(waited),
+            }
+            // a probe that leaves a passed value in place is not probed again, or it would be
+            // sent in a loop
+            let now = self.tracker.clock.now();
+            if self
+                .tracker
+                .state
+                .until(key)
+                .is_none_or(|until| until <= now)
+            {
+                return Ok(waited);
+            }
+        }
+    }
+
+    /// Records the `Retry-After` of `res` under `key`, falling back to the detectors when there
+    /// is none or its status isn't honored.
+    async fn observe(&self, res: Response, key: &str) -> Result<Response> {
+        let verdict = self.tracker.verdict(
+            res.status(),
+            res.headers(),
+            self.tracker.clock.now(),
+            |violation| self.violated(res.url(), violation),
+        );
+        let (res, retry_after, source) = match verdict {
+            Verdict::Record(retry_after, source) => (res, retry_after, source),
+            Verdict::Clear => (res, None, "Retry-After"),
+            Verdict::Keep => return Ok(res),
+            Verdict::Reject(violation) => {
+                return Err(reqwest_middleware::Error::middleware(violation))
+            }
+            Verdict::Undecided => {
+                let (res, delay) = self.detect(res, key).await?;
+                let (delay, source) = match delay {
+                    Some(delay) => (Some(delay), "body"),
+                    None => (self.tracker.fallback_delay(key, res.status()), "fallback"),
+                };
+                (
+                    res,
+                    delay.map(|delay| self.tracker.clock.now() + delay),
+                    source,
+                )
+            }
+        };
+
+        self.record(key, res.url(), res.status(), retry_after, source)
+            .await;
+        Ok(res)
+    }
+
+    /// Also stores the value just stored for `key` under the keys of the `groups` that `res`
+    /// was limited for, so that the other requests in them wait for it too.
+    fn widen(&self, res: &Response, key: &str, groups: &[(&Arc<dyn KeyGroup>, String)]) {
+        if groups.is_empty() || !self.tracker.is_throttling(res.status()) {
+            return;
+        }
+        let now = self.tracker.clock.now();
+        let Some(until) = self.tracker.state.until(key).filter(|until| *until > now) else {
+            return;
+        };
+
+        let mut retry_after = self.tracker.state.retry_after.lock().unwrap();
+        let limited = groups
+            .iter()
+            .filter(|(group, _)| group.limits_group(res.status(), res.headers()));
+        for (_, group_key) in limited {
+            let stored = retry_after.entry(group_key.clone()).or_insert(until);
+            *stored = (*stored).max(until);
+        }
+        self.tracker.bound_entries(&mut retry_after, Some(key), now);
+    }
+
+    async fn detect(&self, res: Response, key: &str) -> Result<(Response, Option<Duration>)> {
+        let status = match res.status() {
+            status if self.tracker.throttle_statuses.contains(&status) => {
+                StatusCode::TOO_MANY_REQUESTS
+            }
+            status => status,
+        };
+        let detectors: Vec<_> = self
+            .detectors
+            .iter()
+            .filter(|detector| detector.applies_to(status))
+            .collect();
+        let Some(max_len) = detectors
+            .iter()
+            .map(|detector| detector.max_body_len())
+            .max()
+        else {
+            self.reset_detectors(key);
+            return Ok((res, None));
+        };
+
+        let (res, body) = detect::sniff(res, max_len).await?;
+        let delay = detectors.iter().find_map(|detector| {
+            let len = body.len().min(detector.max_body_len());
+            detector.detect(key, status, &body[..len])
+        });
+        if delay.is_none() {
+            self.reset_detectors(key);
+        }
+        Ok((res, delay))
+    }
+
+    fn reset_detectors(&self, key: &str) {
+        for detector in &self.detectors {
+            detector.reset(key);
+        }
+    }
+
+    /// Returns how long to wait before re-sending a request that got `res`, if it should be.
+    ///
+    /// Only throttled responses are retried, and only when the wait fits in any bound on it,
+    /// since a truncated wait would just be throttled again.
+    fn retry_delay(
+        &self,
+        res: &Result<Response>,
+        key: &str,
+        extensions: &Extensions,
+    ) -> Option<Duration> {
+        if !self.tracker.is_throttling(res.as_ref().ok()?.status()) {
+            return None;
+        }
+
+        let delay = self
+            .tracker
+            .state
+            .until(key)?
+            .duration_since(self.tracker.clock.now())
+            .ok()?;
+        let max = match (
+            extensions.get::<MaxWaitOverride>(),
+            self.tracker.config.max_wait(),
+        ) {
+            (Some(MaxWaitOverride(max)), _) => Some(*max),
+            (None, Some((_, OnExcessiveDelay::Sleep))) | (None, None) => None,
+            (None, Some((max, _))) => Some(max),
+        };
+        max.is_none_or(|max| delay <= max).then_some(delay)
+    }
+
+    /// Returns how long a request with `key` should wait pre-emptively, if at all.
+    fn predicted_delay(&self, key: &str, now: SystemTime) -> Option<Duration> {
+        let delay = self.predictive_delay?;
+        let predictions = self.tracker.state.predictions.lock().unwrap();
+        let stats = predictions.get(key)?;
+
+        let interval = stats.smoothed_interval?;
+        let elapsed = now.duration_since(stats.last_limited?).ok()?;
+        (elapsed >= interval.mul_f64(0.8) && elapsed <= interval.mul_f64(1.2))
+            .then(|| delay.min(stats.smoothed_window.unwrap_or(delay)))
+    }
+
+    fn push_event(&self, url: &Url, kind: EventKind) {
+        if self.event_history == 0 {
+            return;
+        }
+        let mut events = self.tracker.state.events.lock().unwrap();
+        while events.len() >= self.event_history {
+            events.pop_front();
+        }
+        events.push_back(Event {
+            at: self.tracker.clock.now(),
+            url: url.clone(),
+            kind,
+        });
+    }
+
+    /// Accounts for a request to `url` having waited for `duration`.
+    fn waited(&self, url: &Url, duration: Duration) {
+        self.update_stats(url, |stats| stats.total_waited += duration);
+        self.push_event(url, EventKind::Waited(duration));
+    }
+
+    fn update_stats(&self, url: &Url, f: impl FnOnce(&mut HostStats)) {
+        let host = url.host_str().unwrap_or_default().to_owned();
+        f(self
+            .tracker
+            .state
+            .stats
+            .lock()
+            .unwrap()
+            .entry(host)
+            .or_default());
+    }
+
+    /// Stores `retry_after`, read from `source` in a response from `url` with `status`, for
+    /// `key`, or clears the value stored for it.
+    async fn record(
+        &self,
+        key: &str,
+        url: &Url,
+        status: StatusCode,
+        retry_after: Option<SystemTime>,
+        source: &str,
+    ) {
+        let now = self.tracker.clock.now();
+        let recorded = self.tracker.record_locally(key, retry_after, source, now);
+        self.recorded(url, &recorded);
+        match recorded {
+            Recorded::Stored(time, _) => {
+                if self.predictive_delay.is_some() {
+                    let delay = time.duration_since(now).unwrap_or_default();
+                    let mut predictions = self.tracker.state.predictions.lock().unwrap();
+                    let stats = predictions.entry(key.to_owned()).or_default();
+                    stats.limited(now, delay);
+                }
+                if let Some(store) = &self.store {
+                    store.set(key, time).await;
+                }
+                if let Some(coordinator) = &self.coordinator {
+                    coordinator.publish(key, time).await;
+                }
+                for observer in &self.observers {
+                    observer.on_throttle_recorded(key, time, status).await;
+                }
+            }
+            Recorded::Cleared => {
+                if let Some(store) = &self.store {
+                    store.remove(key).await;
+                    store.purge(self.tracker.clock.now()).await;
+                }
+            }
+            Recorded::Unchanged => {}
+        }
+    }
+
+    /// Does the part of recording a value that is about `url`, the response having been from
+    /// it: updates the statistics and events of the middleware, and calls its callbacks.
+    fn recorded(&self, url: &Url, recorded: &Recorded<'_>) {
+        match *recorded {
+            #[cfg_attr(not(feature = "log"), allow(unused_variables))]
+            Recorded::Stored(time, source) => {
+                let now = self.tracker.clock.now();
+                let delay = time.duration_since(now).unwrap_or_default();
+                #[cfg(feature = "log")]
+                if delay > self.log_threshold {
+                    log::warn!("{url} sent a {source} of {delay:?}");
+                }
+                self.update_stats(url, |stats| stats.limited(now, delay));
+                self.push_event(url, EventKind::Stored(time));
+
+                if let Some(on_persist) = &self.on_persist {
+                    on_persist(url, time);
+                }
+                let limited = self.on_limited.iter().filter(|(host, _)| {
+                    url.host_str().is_some_and(|h| h.eq_ignore_ascii_case(host))
+                });
+                for (_, on_limited) in limited {
+                    on_limited(url, time);
+                }
+                if let Some((threshold, on_long_window)) = &self.on_long_window {
+                    match time.duration_since(now) {
+                        Ok(window) if window >= *threshold => on_long_window(url, window),
+                        _ => {}
                     }
                 }
-                _ => *self.retry_after.write().await = None,
             }
+            Recorded::Cleared => {
+                self.push_event(url, EventKind::Cleared);
+                if let Some(on_unpersist) = &self.on_unpersist {
+                    on_unpersist(url);
+                }
+            }
+            Recorded::Unchanged => {}
+        }
+    }
+
+    /// Tells the violation callback that a response from `url` broke strict parsing.
+    fn violated(&self, url: &Url, violation: &RetryAfterViolation) {
+        if let Some(on_violation) = &self.on_violation {
+            on_violation(url, violation);
         }
-        res
     }
 }
 
-#[cfg(test)]
-mod test {
-    use crate::RetryAfterMiddleware;
-    use httpmock::{Method::GET, MockServer};
-    use reqwest_middleware::ClientBuilder;
-    use std::{sync::Arc, time::SystemTime};
+/// A cloneable handle over the `Retry-After` state of middlewares, to share it between them.
+///
+/// Clients with different configurations, such as default headers, that talk to the same API
+/// can share one cool-down table. Every middleware created over the same state sees the values
+/// the others store, their statistics and recent events, and the events sent to their
+/// [subscribers](RetryAfterMiddleware::subscribe), but keeps its own configuration.
+///
+/// ```
+/// use reqwest_retry_after::{RetryAfterMiddleware, RetryAfterState};
+///
+/// let state = RetryAfterState::new();
+/// let uploads = RetryAfterMiddleware::with_state(state.clone());
+/// let issues = RetryAfterMiddleware::builder().with_state(state).build();
+/// ```
+#[derive(Clone, Default)]
+pub struct RetryAfterState(Arc<State>);
 
-    #[tokio::test]
-    async fn test() {
-        // create
-        let ra_test_duration = 2;
-        let middleware = Arc::new(RetryAfterMiddleware::new());
+impl RetryAfterState {
+    /// Creates an empty state.
+    pub fn new() -> Self {
+        Self::default()
+    }
 
-        // build client with middleware
-        let client = ClientBuilder::new(reqwest::Client::new())
-            .with_arc(middleware.clone())
-            .build();
+    /// Returns the process-wide state, which is created on first use. Requires the `global`
+    /// feature.
+    #[cfg(feature = "global")]
+    pub fn global() -> Self {
+        static GLOBAL: std::sync::OnceLock<RetryAfterState> = std::sync::OnceLock::new();
+        GLOBAL.get_or_init(Self::new).clone()
+    }
+}
 
-        test_empty_retry_after(&middleware).await;
+/// The `Retry-After` state of a middleware, which may be shared with others.
+struct State {
+    retry_after: Mutex<HashMap<String, SystemTime>>,
+    policies: Mutex<HashMap<String, Vec<RateLimitPolicy>>>,
+    stats: Mutex<HashMap<String, HostStats>>,
+    /// The statistics that predictive delays go by, kept per key rather than per host.
+    predictions: Mutex<HashMap<String, HostStats>>,
+    probing: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+    events: Mutex<VecDeque<Event>>,
+    subscribers: broadcast::Sender<RetryAfterEvent>,
+    attempts: Mutex<HashMap<String, u32>>,
+    jittered: Mutex<HashMap<String, Duration>>,
+    released: Mutex<HashMap<String, (SystemTime, u32)>>,
+}
 
-        // create mock server
-        let server = MockServer::start();
-        let ra_mock = server.mock(|when, then| {
-            when.method(GET).path("/");
-            then.status(200)
-                .header("Retry-After", ra_test_duration.to_string())
-                .body("");
-        });
-        let no_ra_mock = server.mock(|when, then| {
-            when.method(GET).path("/normal");
-            then.status(200).body("");
-        });
+impl Default for State {
+    fn default() -> Self {
+        Self {
+            retry_after: Mutex::default(),
+            policies: Mutex::default(),
+            stats: Mutex::default(),
+            predictions: Mutex::default(),
+            probing: Mutex::default(),
+            events: Mutex::default(),
+            subscribers: broadcast::Sender::new(EVENT_CAPACITY),
+            attempts: Mutex::default(),
+            jittered: Mutex::default(),
+            released: Mutex::default(),
+        }
+    }
+}
 
-        // create request
-        let now = SystemTime::now();
-        client.get(server.url("/")).send().await.unwrap();
-        ra_mock.assert_async().await;
+impl State {
+    fn until(&self, key: &str) -> Option<SystemTime> {
+        self.retry_after.lock().unwrap().get(key).copied()
+    }
 
-        test_some_retry_after(&middleware).await;
-        test_valid_retry_after(&middleware, now, ra_test_duration).await;
+    /// Removes every value up to `now`, returning their keys.
+    fn evict(&self, now: SystemTime) -> Vec<String> {
+        let mut retry_after = self.retry_after.lock().unwrap();
+        let expired: Vec<_> = retry_after
+            .iter()
+            .filter(|(_, until)| **until <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &expired {
+            retry_after.remove(key);
+        }
+        self.jittered
+            .lock()
+            .unwrap()
+            .retain(|key, _| retry_after.contains_key(key));
+        self.probing
+            .lock()
+            .unwrap()
+            .retain(|key, _| retry_after.contains_key(key));
+        self.released
+            .lock()
+            .unwrap()
+            .retain(|key, _| retry_after.contains_key(key));
+        expired
+    }
+}
 
-        // verify that we actually sleep for the duration of the retry-after header
-        let now = SystemTime::now();
-        client.get(server.url("/normal")).send().await.unwrap();
-        no_ra_mock.assert_async().await;
-        let duration = SystemTime::now().duration_since(now).unwrap();
+impl Default for RetryAfterMiddleware {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
-        assert!(duration.as_secs_f64() >= ra_test_duration as f64);
-        test_empty_retry_after(&middleware).await;
+/// Which requests share a stored `Retry-After`, set with
+/// [`with_scope`](RetryAfterMiddlewareBuilder::with_scope).
+///
+/// One value is kept per key, and no URL is kept along with it. Crawlers requesting many URLs
+/// under few hosts keep as many values as they have hosts with [`Host`](Self::Host), or
+/// prefixes with [`Prefix`](Self::Prefix); [`Url`](Self::Url) keeps one per URL throttled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Scope {
+    /// Every request shares the same value, whatever its URL.
+    #[default]
+    Global,
+    /// Only requests to the exact same URL, query included, share a value.
+    Url,
+    /// Requests to the same scheme, host and port share a value.
+    Origin,
+    /// Requests to the same host share a value, whatever the scheme and port.
+    Host,
+    /// Requests to the same host whose paths start with the same given number of segments
+    /// share a value, like the requests to one bucket, or one prefix of a bucket, of an object
+    /// store. Keys look like `host/first/second`.
+    Prefix(usize),
+}
+
+impl KeyExtractor for Scope {
+    fn key(&self, req: &Request) -> String {
+        self.key_for_url(req.url())
+    }
+
+    fn key_for_url(&self, url: &Url) -> String {
+        match self {
+            Self::Global => String::new(),
+            Self::Url => url.as_str().to_owned(),
+            Self::Origin => url.origin().ascii_serialization(),
+            Self::Host => url.host_str().unwrap_or_default().to_owned(),
+            Self::Prefix(segments) => {
+                let mut key = url.host_str().unwrap_or_default().to_owned();
+                for segment in url.path_segments().into_iter().flatten().take(*segments) {
+                    key.push('/');
+                    key.push_str(segment);
+                }
+                key
+            }
+        }
+    }
+
+    fn covers(&self, key: &str, host: &str) -> bool {
+        match self {
+            Self::Global => true,
+            _ => covers_host(key, host),
+        }
+    }
+}
+
+/// Derives the key a request's `Retry-After` is stored under, set with
+/// [`with_key_extractor`](RetryAfterMiddlewareBuilder::with_key_extractor).
+///
+/// [`Scope`] implements this for the common cases, and so does any
+/// `Fn(&Request) -> String`.
+///
+/// A CORS preflight, an `OPTIONS` request with an `Access-Control-Request-Method` header, is
+/// shown to the extractor as the request it is made for, with that method, so that both share
+/// a key.
+///
+/// Keys are derived before a request is sent, so they can't tell apart the backends a host
+/// name resolves to: which one answers is only known from the response, too late for the
+/// request to know what to wait for.
+pub trait KeyExtractor: Send + Sync {
+    /// Returns the key of `req`.
+    fn key(&self, req: &Request) -> String;
+
+    /// Returns the key of a `GET` request to `url`, as used by queries like
+    /// [`wait_remaining`](RetryAfterMiddleware::wait_remaining) that only have a URL.
+    ///
+    /// By default, such a request is built and passed to [`key`](Self::key). Extractors that
+    /// only look at the URL can override this to skip building it.
+    fn key_for_url(&self, url: &Url) -> String {
+        self.key(&Request::new(Method::GET, url.clone()))
+    }
+
+    /// Whether requests to `host` are affected by the value stored under `key`, as reported by
+    /// [`throttled_until`](RetryAfterMiddleware::throttled_until).
+    ///
+    /// By default, a key that is a URL covers its host, and any other key covers the host it
+    /// starts with, up to an optional `/`.
+    fn covers(&self, key: &str, host: &str) -> bool {
+        covers_host(key, host)
+    }
+}
+
+impl<F> KeyExtractor for F
+where
+    F: Fn(&Request) -> String + Send + Sync,
+{
+    fn key(&self, req: &Request) -> String {
+        self(req)
+    }
+}
+
+/// The key a `Retry-After` is stored under, as seen by
+/// [`snapshot`](RetryAfterMiddleware::snapshot) and [stores](store::RetryAfterStore).
+///
+/// Schedulers, dashboards and custom stores can build the key the middleware will use for a
+/// request, without sending it:
+///
+/// ```
+/// use reqwest_retry_after::{RetryAfterKey, Scope};
+///
+/// let url = "https://api.github.com/repos?page=2".parse().unwrap();
+/// let key = RetryAfterKey::from_url_scoped(&url, Scope::Origin);
+/// assert_eq!(key.as_str(), "https://api.github.com");
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RetryAfterKey(String);
+
+impl RetryAfterKey {
+    /// Returns the key of a `GET` request to `url` under `scope`.
+    pub fn from_url_scoped(url: &Url, scope: Scope) -> Self {
+        Self(scope.key_for_url(url))
+    }
+
+    /// Returns the key of `req` under `keys`, which can be a [`Scope`] or the extractor
+    /// passed to [`with_key_extractor`](RetryAfterMiddlewareBuilder::with_key_extractor).
+    pub fn from_request(req: &Request, keys: &impl KeyExtractor) -> Self {
+        Self(request_key(keys, req))
+    }
+
+    /// Returns the key as a string.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Returns the key as an owned string.
+    pub fn into_string(self) -> String {
+        self.0
+    }
+}
+
+impl From<String> for RetryAfterKey {
+    fn from(key: String) -> Self {
+        Self(key)
+    }
+}
+
+impl From<RetryAfterKey> for String {
+    fn from(key: RetryAfterKey) -> Self {
+        key.0
+    }
+}
+
+impl AsRef<str> for RetryAfterKey {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Display for RetryAfterKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Returns the key of `req` under `keys`, deriving that of a CORS preflight from the request
+/// it is made for, so that a throttled preflight holds back the actual request.
+fn request_key(keys: &dyn KeyExtractor, req: &Request) -> String {
+    let method = req
+        .headers()
+        .get(ACCESS_CONTROL_REQUEST_METHOD)
+        .filter(|_| req.method() == Method::OPTIONS)
+        .and_then(|method| Method::from_bytes(method.as_bytes()).ok());
+    let Some(method) = method else {
+        return keys.key(req);
+    };
+
+    let mut actual = Request::new(method, req.url().clone());
+    *actual.headers_mut() = req.headers().clone();
+    actual.headers_mut().remove(ACCESS_CONTROL_REQUEST_METHOD);
+    actual.headers_mut().remove(ACCESS_CONTROL_REQUEST_HEADERS);
+    keys.key(&actual)
+}
+
+fn covers_host(key: &str, host: &str) -> bool {
+    match Url::parse(key) {
+        Ok(url) => url.host_str().is_some_and(|h| h.eq_ignore_ascii_case(host)),
+        Err(_) => {
+            let prefix = key.split_once('/').map_or(key, |(prefix, _)| prefix);
+            prefix.eq_ignore_ascii_case(host)
+        }
+    }
+}
+
+/// What the middleware would have done with a request, had dry-run mode been disabled.
+///
+/// Inserted into the response extensions in [dry-run mode](RetryAfterMiddlewareBuilder::dry_run).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DryRunOutcome {
+    /// The request would have waited for this long before being sent.
+    Wait(Duration),
+    /// The request would have failed with an [`ExcessiveDelay`] error, instead of waiting for
+    /// this long.
+    Reject(Duration),
+}
+
+/// How the value of a header set with
+/// [`with_retry_after_header`](RetryAfterMiddlewareBuilder::with_retry_after_header) is read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HeaderFormat {
+    /// A whole or fractional number of seconds to wait, like `30` or `1.5`.
+    Seconds,
+    /// The Unix timestamp to wait until, in whole or fractional seconds.
+    Timestamp,
+    /// The HTTP date to wait until. Requires the `http-date` feature, without which such
+    /// headers are ignored.
+    HttpDate,
+}
+
+impl HeaderFormat {
+    fn parse(self, val: &str, now: SystemTime) -> Option<SystemTime> {
+        match self {
+            Self::Seconds => parse::parse_delay(val).map(|delay| now + delay),
+            Self::Timestamp => parse::parse_delay(val).map(|time| SystemTime::UNIX_EPOCH + time),
+            Self::HttpDate => parse::parse_http_date(val, now),
+        }
+    }
+}
+
+/// Randomizes waits, so that clients sharing a `Retry-After` don't all come back at once. Set
+/// with [`with_jitter`](RetryAfterMiddlewareBuilder::with_jitter).
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+#[non_exhaustive]
+pub enum Jitter {
+    /// Waits exactly as long as the server asked.
+    #[default]
+    None,
+    /// Waits for up to twice as long as the server asked.
+    Full,
+    /// Waits for between what the server asked and three times the previous wait for the same
+    /// key, or three times what the server asked for the first wait, like AWS's "decorrelated
+    /// jitter".
+    Decorrelated,
+    /// Scales every wait by a random factor within the given fraction of 1, so
+    /// `Proportional(0.1)` waits between 90% and 110% of what the server asked. Unlike the
+    /// others, this may send requests before the server allows them.
+    Proportional(f64),
+}
+
+impl Jitter {
+    /// Returns a randomized wait for a `delay`, given the previous wait for the same key.
+    fn apply(self, delay: Duration, previous: Option<Duration>) -> Duration {
+        match self {
+            Self::None => delay,
+            Self::Full => delay + delay.mul_f64(random()),
+            Self::Decorrelated => {
+                let upper = (previous.unwrap_or(delay) * 3).max(delay);
+                delay + (upper - delay).mul_f64(random())
+            }
+            Self::Proportional(fraction) => {
+                let fraction = fraction.clamp(0.0, 1.0);
+                delay.mul_f64(1.0 - fraction + 2.0 * fraction * random())
+            }
+        }
+    }
+}
+
+/// Returns a number in `[0, 1)`, random enough for jitter without pulling in an RNG.
+fn random() -> f64 {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// How long to wait after a throttled response that didn't say, as set with
+/// [`with_fallback_backoff`](RetryAfterMiddlewareBuilder::with_fallback_backoff).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Backoff {
+    /// Always waits for the same delay.
+    Fixed(Duration),
+    /// Waits for `base`, then twice as long after every consecutive throttled response, up to
+    /// `max`.
+    Exponential {
+        /// The first delay.
+        base: Duration,
+        /// The longest delay.
+        max: Duration,
+    },
+}
+
+impl Backoff {
+    /// Returns the delay after `attempt` earlier consecutive throttled responses.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use reqwest_retry_after::Backoff;
+    ///
+    /// let backoff = Backoff::Exponential {
+    ///     base: Duration::from_secs(1),
+    ///     max: Duration::from_secs(10),
+    /// };
+    /// assert_eq!(backoff.delay(2), Duration::from_secs(4));
+    /// assert_eq!(backoff.delay(8), Duration::from_secs(10));
+    /// ```
+    pub fn delay(&self, attempt: u32) -> Duration {
+        match *self {
+            Self::Fixed(delay) => delay,
+            Self::Exponential { base, max } => {
+                let factor = 2u32.checked_pow(attempt).unwrap_or(u32::MAX);
+                base.saturating_mul(factor).min(max)
+            }
+        }
+    }
+}
+
+/// What happens to a request that would wait longer than the bound set with
+/// [`with_max_wait`](RetryAfterMiddlewareBuilder::with_max_wait).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnExcessiveDelay {
+    /// Waits for the whole delay anyway, as without a bound.
+    Sleep,
+    /// Waits for the bound only, then sends the request.
+    Truncate,
+    /// Fails the request with an [`ExcessiveDelay`] error without sending it.
+    Error,
+}
+
+/// The error of a request that would have waited longer than allowed by
+/// [`OnExcessiveDelay::Error`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExcessiveDelay {
+    /// How long the request would have waited.
+    pub delay: Duration,
+    /// The longest the request was allowed to wait.
+    pub max: Duration,
+}
+
+impl std::fmt::Display for ExcessiveDelay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Retry-After delay of {:?} exceeds the maximum wait of {:?}",
+            self.delay, self.max
+        )
+    }
+}
+
+impl std::error::Error for ExcessiveDelay {}
+
+/// What [strict validation](RetryAfterMiddlewareBuilder::with_strict_validation) does with a
+/// `Retry-After` that doesn't conform to RFC 9110.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnViolation {
+    /// Leaves the stored value as it was, as if the response had no `Retry-After`.
+    Ignore,
+    /// Interprets the value as leniently as without strict validation. Negative delays are
+    /// clamped to zero, which clears the stored value like a passed date would, and several
+    /// fields are combined as [configured](RetryAfterMiddlewareBuilder::with_multiple_fields).
+    Clamp,
+    /// Fails the request with a [`RetryAfterViolation`].
+    Error,
+}
+
+/// Which value wins when a response sets one for a key that still holds another, see
+/// [`RetryAfterMiddlewareBuilder::with_conflict_resolution`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnConflict {
+    /// The value of the latest response, even if it ends an existing cool-down early.
+    KeepLatest,
+    /// The value that ends later.
+    KeepLongest,
+    /// The value that ends earlier.
+    KeepShortest,
+}
+
+impl OnConflict {
+    fn resolve(self, current: SystemTime, new: SystemTime) -> SystemTime {
+        match self {
+            OnConflict::KeepLatest => new,
+            OnConflict::KeepLongest => current.max(new),
+            OnConflict::KeepShortest => current.min(new),
+        }
+    }
+}
+
+/// How a response with several `Retry-After` fields is read, see
+/// [`RetryAfterMiddlewareBuilder::with_multiple_fields`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OnMultipleFields {
+    /// Only the first field is read, and the others are ignored.
+    First,
+    /// The field that ends later.
+    Longest,
+    /// The field that ends earlier.
+    Shortest,
+}
+
+impl OnMultipleFields {
+    fn combine(self, a: SystemTime, b: SystemTime) -> SystemTime {
+        match self {
+            OnMultipleFields::First => a,
+            OnMultipleFields::Longest => a.max(b),
+            OnMultipleFields::Shortest => a.min(b),
+        }
+    }
+}
+
+/// A `Retry-After` that doesn't conform to RFC 9110.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryAfterViolation {
+    /// How the field is wrong.
+    pub kind: ViolationKind,
+    /// The value of the first `Retry-After` field, lossily converted to UTF-8.
+    pub value: String,
+}
+
+impl std::fmt::Display for RetryAfterViolation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let problem = match self.kind {
+            ViolationKind::MultipleFields => "more than one Retry-After field",
+            ViolationKind::Negative => "negative Retry-After delay",
+            ViolationKind::ObsoleteDate => "Retry-After date is not an IMF-fixdate",
+            ViolationKind::Malformed => "malformed Retry-After",
+        };
+        write!(f, "{problem}: {:?}", self.value)
+    }
+}
+
+impl std::error::Error for RetryAfterViolation {}
+
+/// The ways a `Retry-After` can violate RFC 9110.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ViolationKind {
+    /// The response has more than one `Retry-After` field.
+    MultipleFields,
+    /// The delay is a negative number of seconds.
+    Negative,
+    /// The date is valid, but in an obsolete or non-standard format rather than IMF-fixdate.
+    ObsoleteDate,
+    /// The value is neither delay-seconds nor a date.
+    Malformed,
+}
+
+/// A deprecation announced by a response, passed to
+/// [`on_deprecation`](RetryAfterMiddlewareBuilder::on_deprecation) callbacks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeprecationNotice {
+    /// Whether the response carried a `Deprecation` header.
+    pub deprecated: bool,
+    /// When the resource was, or will be, deprecated, if the `Deprecation` header said so.
+    pub deprecated_at: Option<SystemTime>,
+    /// When the resource is expected to stop responding, from the `Sunset` header.
+    pub sunset: Option<SystemTime>,
+}
+
+/// The stored values of a [`RetryAfterMiddleware`], as returned by
+/// [`RetryAfterMiddleware::save_state`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SavedState {
+    /// Every stored value and its key, sorted by key.
+    pub retry_after: Vec<(String, SystemTime)>,
+}
+
+/// A cloneable handle over the settings of a [`RetryAfterMiddleware`] that can be changed
+/// while it runs, created with [`RetryAfterMiddleware::config`].
+///
+/// A feature flag can take the middleware out of [dry-run
+/// mode](RetryAfterMiddlewareBuilder::dry_run), or tighten the bound on waits, without the
+/// client being built again. Changes apply to requests from when they are handed to the
+/// middleware; requests already waiting keep the settings they started with. Clones of the
+/// middleware share its settings, but other middlewares over the same [`RetryAfterState`]
+/// keep their own.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigHandle(Arc<Mutex<Settings>>);
+
+/// The settings behind a [`ConfigHandle`].
+#[derive(Debug, Clone, Copy, Default)]
+struct Settings {
+    max_wait: Option<(Duration, OnExcessiveDelay)>,
+    jitter: Jitter,
+    dry_run: bool,
+}
+
+impl ConfigHandle {
+    /// Bounds how long a request may wait, like
+    /// [`with_max_wait`](RetryAfterMiddlewareBuilder::with_max_wait).
+    pub fn set_max_wait(&self, max: Duration, policy: OnExcessiveDelay) {
+        self.0.lock().unwrap().max_wait = Some((max, policy));
+    }
+
+    /// Lets requests wait for as long as they are asked to again.
+    pub fn clear_max_wait(&self) {
+        self.0.lock().unwrap().max_wait = None;
+    }
+
+    /// Returns the bound on waits and what happens to requests that would wait longer, if
+    /// there is one.
+    pub fn max_wait(&self) -> Option<(Duration, OnExcessiveDelay)> {
+        self.0.lock().unwrap().max_wait
+    }
+
+    /// Randomizes waits with `jitter`, like
+    /// [`with_jitter`](RetryAfterMiddlewareBuilder::with_jitter).
+    pub fn set_jitter(&self, jitter: Jitter) {
+        self.0.lock().unwrap().jitter = jitter;
+    }
+
+    /// Returns how waits are randomized.
+    pub fn jitter(&self) -> Jitter {
+        self.0.lock().unwrap().jitter
+    }
+
+    /// Turns [dry-run mode](RetryAfterMiddlewareBuilder::dry_run) on or off.
+    pub fn set_dry_run(&self, enabled: bool) {
+        self.0.lock().unwrap().dry_run = enabled;
+    }
+
+    /// Returns whether the middleware is in dry-run mode.
+    pub fn is_dry_run(&self) -> bool {
+        self.0.lock().unwrap().dry_run
+    }
+}
+
+/// A cloneable, read-only handle over the state of a [`RetryAfterMiddleware`], created with
+/// [`RetryAfterMiddleware::reader`].
+#[derive(Clone)]
+pub struct RetryAfterReader {
+    state: Arc<State>,
+    clock: Arc<dyn Clock>,
+    keys: Arc<dyn KeyExtractor>,
+}
+
+impl RetryAfterReader {
+    /// See [`RetryAfterMiddleware::poll_remaining_for`].
+    pub fn poll_remaining_for(&self, url: &Url) -> Option<Duration> {
+        self.wait_remaining(url)
+    }
+
+    /// See [`RetryAfterMiddleware::wait_all`].
+    pub fn wait_all<'a>(&self, urls: impl IntoIterator<Item = &'a Url>) -> Option<Duration> {
+        urls.into_iter()
+            .filter_map(|url| self.poll_remaining_for(url))
+            .max()
+    }
+
+    /// See [`RetryAfterMiddleware::poll_remaining`].
+    pub fn poll_remaining(&self) -> Option<Duration> {
+        let retry_after = *self.state.retry_after.lock().unwrap().values().max()?;
+        retry_after
+            .duration_since(self.clock.now())
+            .ok()
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    /// See [`RetryAfterMiddleware::is_host_available`].
+    pub fn is_host_available(&self, host: &str) -> bool {
+        self.throttled_until(host).is_none()
+    }
+
+    /// See [`RetryAfterMiddleware::throttled_until`].
+    pub fn throttled_until(&self, host: &str) -> Option<SystemTime> {
+        let now = self.clock.now();
+        self.state
+            .retry_after
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(key, until)| **until > now && self.keys.covers(key, host))
+            .map(|(_, until)| *until)
+            .max()
+    }
+
+    /// See [`RetryAfterMiddleware::wait_remaining`].
+    pub fn wait_remaining(&self, url: &Url) -> Option<Duration> {
+        self.remaining(&self.keys.key_for_url(url))
+    }
+
+    /// See [`RetryAfterMiddleware::wait_remaining_for`].
+    pub fn wait_remaining_for(&self, req: &Request) -> Option<Duration> {
+        self.remaining(&request_key(&*self.keys, req))
+    }
+
+    fn remaining(&self, key: &str) -> Option<Duration> {
+        self.state
+            .until(key)?
+            .duration_since(self.clock.now())
+            .ok()
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    /// See [`RetryAfterMiddleware::is_throttled`].
+    pub fn is_throttled(&self, url: &Url) -> bool {
+        self.wait_remaining(url).is_some()
+    }
+
+    /// See [`RetryAfterMiddleware::check`].
+    pub fn check(&self, url: &Url) -> std::result::Result<(), Duration> {
+        self.wait_remaining(url).map_or(Ok(()), Err)
+    }
+
+    /// See [`RetryAfterMiddleware::snapshot`].
+    pub fn snapshot(&self) -> Vec<(String, SystemTime)> {
+        let mut snapshot: Vec<_> = self
+            .state
+            .retry_after
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, until)| (key.clone(), *until))
+            .collect();
+        snapshot.sort();
+        snapshot
+    }
+
+    /// See [`RetryAfterMiddleware::expiring_within`].
+    pub fn expiring_within(&self, horizon: Duration) -> Vec<(String, SystemTime)> {
+        let now = self.clock.now();
+        let mut snapshot = self.snapshot();
+        snapshot.retain(|(_, until)| *until > now && *until <= now + horizon);
+        snapshot
+    }
+
+    /// See [`RetryAfterMiddleware::stats`].
+    pub fn stats(&self) -> HashMap<String, HostStats> {
+        self.state.stats.lock().unwrap().clone()
+    }
+
+    /// See [`RetryAfterMiddleware::report`].
+    pub fn report(&self) -> stats::Report {
+        let mut hosts: Vec<_> = self.stats().into_iter().collect();
+        hosts.sort_by(|(a, _), (b, _)| a.cmp(b));
+        stats::Report {
+            generated_at: self.clock.now(),
+            hosts,
+        }
+    }
+
+    /// See [`RetryAfterMiddleware::rate_limit_policies`].
+    pub fn rate_limit_policies(&self, url: &Url) -> Vec<RateLimitPolicy> {
+        let key = self.keys.key_for_url(url);
+        let policies = self.state.policies.lock().unwrap();
+        policies.get(&key).cloned().unwrap_or_default()
+    }
+
+    /// See [`RetryAfterMiddleware::recent_events`].
+    pub fn recent_events(&self) -> Vec<Event> {
+        self.state.events.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+/// A request extension that bounds how long this particular request may wait for a
+/// `Retry-After` to elapse.
+///
+/// The bound replaces any set with
+/// [`with_max_wait`](RetryAfterMiddlewareBuilder::with_max_wait). A longer wait is truncated,
+/// or fails with an [`ExcessiveDelay`] if the configured policy is [`OnExcessiveDelay::Error`].
+///
+/// Insert it with [`reqwest_middleware::RequestBuilder::with_extension`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaxWaitOverride(pub Duration);
+
+/// A request extension that sends this particular request right away, even if its key must
+/// wait, and ignores the `Retry-After` of its response.
+///
+/// Meant for requests that can't wait, such as health checks and cancellations. Insert it
+/// with [`reqwest_middleware::RequestBuilder::with_extension`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IgnoreRetryAfter;
+
+/// A request extension that builds a request again, for the middleware to
+/// [retry](RetryAfterMiddlewareBuilder::with_retries) requests whose body can't be cloned.
+///
+/// Requests with a streaming body, such as a file upload, can't be cloned to be sent again.
+/// Without this extension, such a request is sent once and its throttled response returned.
+/// Insert it with [`reqwest_middleware::RequestBuilder::with_extension`], or with a
+/// [`RequestInitialiser`](reqwest_middleware::RequestInitialiser) to attach it to every
+/// request of a client.
+///
+/// ```
+/// # async fn run(client: reqwest_middleware::ClientWithMiddleware) {
+/// # fn open_upload() -> reqwest::Body { reqwest::Body::from("") }
+/// use reqwest_retry_after::RebuildRequest;
+///
+/// // `open_upload` streams the file to upload from disk
+/// let rebuild = RebuildRequest::new(|| {
+///     let client = reqwest::Client::new();
+///     client.put("https://example.com/upload").body(open_upload()).build()
+/// });
+/// let res = client
+///     .put("https://example.com/upload")
+///     .body(open_upload())
+///     .with_extension(rebuild)
+///     .send()
+///     .await;
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct RebuildRequest(Arc<dyn Fn() -> reqwest::Result<Request> + Send + Sync>);
+
+impl RebuildRequest {
+    /// Creates an extension building requests with `rebuild`. A request that fails to build
+    /// is not retried.
+    pub fn new(rebuild: impl Fn() -> reqwest::Result<Request> + Send + Sync + 'static) -> Self {
+        Self(Arc::new(rebuild))
+    }
+}
+
+impl std::fmt::Debug for RebuildRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("RebuildRequest").finish_non_exhaustive()
+    }
+}
+
+/// How long the middleware waited for `Retry-After`s to elapse before sending a request, in
+/// total over any retries.
+///
+/// Whenever a request waited, this is inserted into its extensions, for middlewares further
+/// down the stack, and into the extensions of its response. With it, the part of a request's
+/// latency spent waiting can be told apart from the round trip.
+///
+/// It also lets retry middlewares further up the stack, such as `reqwest-retry`'s, skip
+/// backing off when the middleware already waited: they share the extensions of the request,
+/// and find the marker there once it comes back. The middleware removes the marker whenever
+/// it is given a request, so that a retried request only carries it if it waited again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryAfterWaited(pub Duration);
+
+/// Stops the task returned by [`RetryAfterMiddleware::eviction_task`] when shut down or
+/// dropped.
+#[derive(Debug)]
+pub struct EvictionHandle(watch::Sender<()>);
+
+impl EvictionHandle {
+    /// Stops the task, which finishes without evicting again.
+    pub fn shutdown(self) {
+        self.0.send_replace(());
+    }
+}
+
+/// Inserts the dry-run outcome and the time waited into the extensions of `res`.
+#[cfg(not(target_arch = "wasm32"))]
+fn annotate(res: &mut Response, dry_run: Option<DryRunOutcome>, waited: Duration) {
+    if let Some(outcome) = dry_run {
+        res.extensions_mut().insert(outcome);
+    }
+    if !waited.is_zero() {
+        res.extensions_mut().insert(RetryAfterWaited(waited));
+    }
+}
+
+/// Responses have no extensions on `wasm32` targets.
+#[cfg(target_arch = "wasm32")]
+fn annotate(_: &mut Response, _: Option<DryRunOutcome>, _: Duration) {}
+
+/// How many events a [`RetryAfterMiddleware::subscribe`] receiver may fall behind.
+const EVENT_CAPACITY: usize = 256;
+
+/// Decrements the sleeping count when a wait finishes or its future is dropped.
+struct SleepGuard<'a>(&'a watch::Sender<usize>);
+
+impl Drop for SleepGuard<'_> {
+    fn drop(&mut self) {
+        self.0.send_modify(|count| *count -= 1);
+    }
+}
+
+#[cfg_attr(not(target_arch = "wasm32"), async_trait::async_trait)]
+#[cfg_attr(target_arch = "wasm32", async_trait::async_trait(?Send))]
+impl Middleware for RetryAfterMiddleware {
+    async fn handle(
+        &self,
+        req: Request,
+        extensions: &mut Extensions,
+        next: Next<'_>,
+    ) -> Result<Response> {
+        // left over from an earlier pass of a retried request
+        extensions.remove::<RetryAfterWaited>();
+        if extensions.get::<IgnoreRetryAfter>().is_some() {
+            return next.run(req, extensions).await;
+        }
+
+        if let Some(coordinator) = &self.coordinator {
+            let received = coordinator.receive().await;
+            let now = self.tracker.clock.now();
+            let mut retry_after = self.tracker.state.retry_after.lock().unwrap();
+            for (key, until) in received {
+                let until = self.tracker.clamp_ttl(until, now);
+                let stored = retry_after.entry(key).or_insert(until);
+                *stored = (*stored).max(until);
+            }
+            self.tracker.bound_entries(&mut retry_after, None, now);
+        }
+
+        let key = self.tracker.key_for(&req);
+        #[cfg(feature = "tracing")]
+        tracing::Span::current().record("retry_after.key", key.as_str());
+        if let Some(store) = &self.store {
+            if let Some(until) = store.get(&key).await {
+                let now = self.tracker.clock.now();
+                let until = self.tracker.clamp_ttl(until, now);
+                let mut retry_after = self.tracker.state.retry_after.lock().unwrap();
+                let stored = retry_after.entry(key.clone()).or_insert(until);
+                *stored = (*stored).max(until);
+                self.tracker
+                    .bound_entries(&mut retry_after, Some(&key), now);
+            }
+        }
+
+        let groups: Vec<_> = self
+            .groups
+            .iter()
+            .filter_map(|group| Some((group, group.group(&req)?)))
+            .collect();
+        if !groups.is_empty() {
+            // a group's value holds back its requests like one stored under their own key
+            let mut retry_after = self.tracker.state.retry_after.lock().unwrap();
+            for (_, group_key) in &groups {
+                if let Some(&until) = retry_after.get(group_key) {
+                    let stored = retry_after.entry(key.clone()).or_insert(until);
+                    *stored = (*stored).max(until);
+                }
+            }
+        }
+
+        let now = self.tracker.clock.now();
+        let max_wait = extensions
+            .get::<MaxWaitOverride>()
+            .map(|MaxWaitOverride(max)| *max);
+        let dry_run_mode = self.tracker.config.is_dry_run();
+        let mut dry_run = None;
+        let mut total_waited = Duration::ZERO;
+
+        match self.tracker.bounded_wait(&key, now, max_wait) {
+            Err(excessive) if dry_run_mode => {
+                dry_run = Some(DryRunOutcome::Reject(excessive.delay));
+            }
+            Err(excessive) => return Err(reqwest_middleware::Error::middleware(excessive)),
+            Ok(Some((duration, _))) if dry_run_mode => {
+                dry_run = Some(DryRunOutcome::Wait(duration));
+            }
+            Ok(Some((duration, max))) => {
+                let duration = duration + self.stagger(&key);
+                let mut waited = self.sleep(&key, duration, max).await;
+                if let (Some(client), None) = (&self.recovery_probe, max) {
+                    waited += self.recover(client, &key, req.url()).await?;
+                }
+                self.waited(req.url(), waited);
+                total_waited += waited;
+            }
+            Ok(None) => {
+                if let Some(delay) = self.predicted_delay(&key, now) {
+                    if dry_run_mode {
+                        dry_run = Some(DryRunOutcome::Wait(delay));
+                    } else {
+                        let waited = self.sleep(&key, delay, None).await;
+                        self.waited(req.url(), waited);
+                        total_waited += waited;
+                    }
+                }
+            }
+        }
+
+        let url = (self.event_history > 0).then(|| req.url().clone());
+        let mut req = req;
+        let mut attempts = 0;
+        let mut res = loop {
+            // requests with a streaming body can't be cloned, and are only retried if they can
+            // be built again
+            let retry = (attempts < self.retries && !dry_run_mode)
+                .then(|| {
+                    req.try_clone().or_else(|| {
+                        let RebuildRequest(rebuild) = extensions.get::<RebuildRequest>()?;
+                        rebuild().ok()
+                    })
+                })
+                .flatten();
+            if !total_waited.is_zero() {
+                extensions.insert(RetryAfterWaited(total_waited));
+            }
+            let res = match next.clone().run(req, extensions).await {
+                Ok(res) => self.observe(res, &key).await.inspect(|res| {
+                    self.widen(res, &key, &groups);
+                }),
+                Err(e) => {
+                    if let Some(url) = &url {
+                        self.push_event(url, EventKind::Errored(e.to_string()));
+                    }
+                    Err(e)
+                }
+            };
+
+            let Some(retry) = retry else { break res };
+            let Some(delay) = self.retry_delay(&res, &key, extensions) else {
+                break res;
+            };
+            let waited = self
+                .sleep(&key, self.tracker.jitter(&key, delay), None)
+                .await;
+            self.waited(retry.url(), waited);
+            total_waited += waited;
+            attempts += 1;
+            req = retry;
+        };
+
+        if let Ok(res) = &res {
+            let policies = RateLimitPolicy::from_headers(res.headers());
+            if !policies.is_empty() {
+                let mut stored = self.tracker.state.policies.lock().unwrap();
+                stored.insert(key.clone(), policies);
+            }
+        }
+
+        if let (Ok(res), Some(on_deprecation)) = (&res, &self.on_deprecation) {
+            if let Some(notice) = parse::parse_deprecation(res.headers(), self.tracker.clock.now())
+            {
+                on_deprecation(res.url(), &notice);
+            }
+        }
+
+        if let Ok(res) = &mut res {
+            annotate(res, dry_run, total_waited);
+        }
+        #[cfg(feature = "tracing")]
+        if !total_waited.is_zero() {
+            let waited_ms = u64::try_from(total_waited.as_millis()).unwrap_or(u64::MAX);
+            tracing::Span::current().record("retry_after.waited_ms", waited_ms);
+        }
+
+        res
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        clock::{BoxFuture, Clock, Sleeper, TokioClock, TokioSleeper},
+        detect::{AwsThrottling, ObjectStoreSlowDown},
+        group::{CdnEdge, ProxyGroup},
+        observer::RetryAfterObserver,
+        rate_limit::{RateLimitPolicy, RateLimitSource},
+        stats::{EventKind, RetryAfterEvent},
+        store::{MemoryStore, MockStore, RetryAfterStore, StoreCall},
+        testing::{InstantSleeper, ManualClock},
+        Backoff, DryRunOutcome, ExcessiveDelay, HeaderFormat, IgnoreRetryAfter, Jitter,
+        KeyExtractor, MaxWaitOverride, OnConflict, OnExcessiveDelay, OnMultipleFields, OnViolation,
+        RebuildRequest, RetryAfterKey, RetryAfterMiddleware, RetryAfterMiddlewareBuilder,
+        RetryAfterState, RetryAfterWaited, Scope, State, ViolationKind,
+    };
+    use http::{Extensions, HeaderName, StatusCode};
+    use httpmock::{
+        Method::{GET, HEAD, OPTIONS, PUT},
+        MockServer,
+    };
+    use reqwest::{Request, Url};
+    use reqwest_middleware::{ClientBuilder, Middleware, Next};
+    use std::{
+        sync::{Arc, Mutex},
+        time::{Duration, SystemTime},
+    };
+    use tokio::sync::Notify;
+
+    #[tokio::test]
+    async fn test() {
+        // create
+        let ra_test_duration = 2;
+        let middleware = Arc::new(RetryAfterMiddleware::new());
+
+        // build client with middleware
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with_arc(middleware.clone())
+            .build();
+
+        test_empty_retry_after(&middleware).await;
+
+        // create mock server
+        let server = MockServer::start();
+        let ra_mock = server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429)
+                .header("Retry-After", ra_test_duration.to_string())
+                .body("");
+        });
+        let no_ra_mock = server.mock(|when, then| {
+            when.method(GET).path("/normal");
+            then.status(200).body("");
+        });
+
+        // create request
+        let now = SystemTime::now();
+        client.get(server.url("/")).send().await.unwrap();
+        ra_mock.assert_async().await;
+
+        test_some_retry_after(&middleware).await;
+        test_valid_retry_after(&middleware, now, ra_test_duration).await;
+
+        // verify that we actually sleep for the duration of the retry-after header
+        let now = SystemTime::now();
+        client.get(server.url("/normal")).send().await.unwrap();
+        no_ra_mock.assert_async().await;
+        let duration = SystemTime::now().duration_since(now).unwrap();
+
+        assert!(duration.as_secs_f64() >= ra_test_duration as f64);
+        test_empty_retry_after(&middleware).await;
+    }
+
+    #[tokio::test]
+    async fn max_wait_override() {
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(RetryAfterMiddleware::new())
+            .build();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "30").body("");
+        });
+
+        client.get(server.url("/")).send().await.unwrap();
+
+        // the override should cut the 30 second wait short
+        let now = SystemTime::now();
+        client
+            .get(server.url("/"))
+            .with_extension(MaxWaitOverride(Duration::from_millis(100)))
+            .send()
+            .await
+            .unwrap();
+        let duration = SystemTime::now().duration_since(now).unwrap();
+
+        assert!(duration < Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn ignore_retry_after() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "30").body("");
+        });
+
+        let sleeper = InstantSleeper::new();
+        let middleware = Arc::new(
+            RetryAfterMiddleware::builder()
+                .with_clock(ManualClock::new())
+                .with_sleeper(sleeper.clone())
+                .build(),
+        );
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with_arc(middleware.clone())
+            .build();
+
+        // neither waits nor stores a value
+        let url = Url::parse(&server.url("/")).unwrap();
+        for _ in 0..2 {
+            client
+                .get(url.clone())
+                .with_extension(IgnoreRetryAfter)
+                .send()
+                .await
+                .unwrap();
+        }
+        assert_eq!(middleware.wait_remaining(&url), None);
+
+        client.get(url.clone()).send().await.unwrap();
+        client
+            .get(url)
+            .with_extension(IgnoreRetryAfter)
+            .send()
+            .await
+            .unwrap();
+        assert!(sleeper.sleeps().is_empty());
+    }
+
+    #[tokio::test]
+    async fn observer() {
+        #[derive(Default)]
+        struct Log(Mutex<Vec<String>>);
+
+        #[async_trait::async_trait]
+        impl RetryAfterObserver for Log {
+            async fn on_throttle_recorded(&self, key: &str, _: SystemTime, status: StatusCode) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("recorded {key:?} {status}"));
+            }
+
+            async fn on_wait_start(&self, key: &str, duration: Duration) {
+                self.0
+                    .lock()
+                    .unwrap()
+                    .push(format!("start {key:?} {duration:?}"));
+            }
+
+            async fn on_wait_end(&self, key: &str) {
+                self.0.lock().unwrap().push(format!("end {key:?}"));
+            }
+        }
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(503).header("Retry-After", "30").body("");
+        });
+
+        let clock = ManualClock::new();
+        let log = Arc::new(Log::default());
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(
+                RetryAfterMiddleware::builder()
+                    .with_clock(clock.clone())
+                    .with_sleeper(InstantSleeper::advancing(&clock))
+                    .with_observer(log.clone())
+                    .build(),
+            )
+            .build();
+        client.get(server.url("/")).send().await.unwrap();
+        client.get(server.url("/")).send().await.unwrap();
+
+        assert_eq!(
+            *log.0.lock().unwrap(),
+            [
+                r#"recorded "" 503 Service Unavailable"#,
+                r#"start "" 30s"#,
+                r#"end """#,
+                r#"recorded "" 503 Service Unavailable"#,
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn max_wait() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "86400").body("");
+        });
+
+        let sleeper = InstantSleeper::new();
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(
+                RetryAfterMiddleware::builder()
+                    .with_clock(ManualClock::new())
+                    .with_sleeper(sleeper.clone())
+                    .with_max_wait(Duration::from_secs(60), OnExcessiveDelay::Truncate)
+                    .build(),
+            )
+            .build();
+        client.get(server.url("/")).send().await.unwrap();
+        client.get(server.url("/")).send().await.unwrap();
+        assert_eq!(sleeper.sleeps(), vec![Duration::from_secs(60)]);
+
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(
+                RetryAfterMiddleware::builder()
+                    .with_clock(ManualClock::new())
+                    .with_max_wait(Duration::from_secs(60), OnExcessiveDelay::Error)
+                    .build(),
+            )
+            .build();
+        client.get(server.url("/")).send().await.unwrap();
+        let err = client.get(server.url("/")).send().await.unwrap_err();
+        assert!(err.to_string().contains("exceeds the maximum wait"));
+
+        // an override raises the bound, and is enforced the same way
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(
+                RetryAfterMiddleware::builder()
+                    .with_clock(ManualClock::new())
+                    .with_sleeper(sleeper.clone())
+                    .with_max_wait(Duration::from_secs(60), OnExcessiveDelay::Error)
+                    .build(),
+            )
+            .build();
+        client.get(server.url("/")).send().await.unwrap();
+        let err = client
+            .get(server.url("/"))
+            .with_extension(MaxWaitOverride(Duration::from_secs(3600)))
+            .send()
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("maximum wait of 3600s"));
+        client
+            .get(server.url("/"))
+            .with_extension(MaxWaitOverride(Duration::from_secs(86400)))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            sleeper.sleeps(),
+            vec![Duration::from_secs(60), Duration::from_secs(86400)]
+        );
+    }
+
+    #[tokio::test]
+    async fn fail_fast() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "30").body("");
+        });
+
+        let clock = ManualClock::new();
+        let sleeper = InstantSleeper::new();
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(
+                RetryAfterMiddleware::builder()
+                    .with_clock(clock.clone())
+                    .with_sleeper(sleeper.clone())
+                    .with_fail_fast()
+                    .build(),
+            )
+            .build();
+        client.get(server.url("/")).send().await.unwrap();
+        clock.advance(Duration::from_secs(10));
+
+        let Err(reqwest_middleware::Error::Middleware(err)) =
+            client.get(server.url("/")).send().await
+        else {
+            panic!("expected a middleware error");
+        };
+        assert_eq!(
+            err.downcast_ref(),
+            Some(&ExcessiveDelay {
+                delay: Duration::from_secs(20),
+                max: Duration::ZERO,
+            })
+        );
+        mock.assert_hits(1);
+        assert!(sleeper.sleeps().is_empty());
+    }
+
+    #[tokio::test]
+    async fn retries() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "5").body("");
+        });
+
+        let clock = ManualClock::new();
+        let sleeper = InstantSleeper::advancing(&clock);
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(
+                RetryAfterMiddleware::builder()
+                    .with_clock(clock)
+                    .with_sleeper(sleeper.clone())
+                    .with_retries(2)
+                    .build(),
+            )
+            .build();
+        let res = client.get(server.url("/")).send().await.unwrap();
+        assert_eq!(res.status(), 429);
+        mock.assert_hits(3);
+        assert_eq!(sleeper.sleeps(), vec![Duration::from_secs(5); 2]);
+        assert_eq!(
+            res.extensions().get(),
+            Some(&RetryAfterWaited(Duration::from_secs(10)))
+        );
+    }
+
+    #[tokio::test]
+    async fn rebuild_request() {
+        let server = MockServer::start();
+        let mock = server.mock(|when, then| {
+            when.method(PUT).path("/upload");
+            then.status(429).header("Retry-After", "5").body("");
+        });
+
+        let clock = ManualClock::new();
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(
+                RetryAfterMiddleware::builder()
+                    .with_clock(clock.clone())
+                    .with_sleeper(InstantSleeper::advancing(&clock))
+                    .with_retries(2)
+                    .build(),
+            )
+            .build();
+        let url = server.url("/upload");
+        let stream = || {
+            let chunk: std::io::Result<_> = Ok("data");
+            reqwest::Body::wrap_stream(futures_util::stream::once(async { chunk }))
+        };
+
+        // a streaming body can't be cloned, so it is only retried when rebuilt
+        client.put(&url).body(stream()).send().await.unwrap();
+        mock.assert_hits(1);
+
+        let rebuilt = Arc::new(Mutex::new(0));
+        let rebuild = {
+            let (url, rebuilt) = (url.clone(), rebuilt.clone());
+            RebuildRequest::new(move || {
+                *rebuilt.lock().unwrap() += 1;
+                reqwest::Client::new().put(&url).body(stream()).build()
+            })
+        };
+        client
+            .put(&url)
+            .body(stream())
+            .with_extension(rebuild)
+            .send()
+            .await
+            .unwrap();
+        mock.assert_hits(4);
+        assert_eq!(*rebuilt.lock().unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn kubernetes() {
+        let clock = ManualClock::new();
+        let sleeper = InstantSleeper::advancing(&clock);
+        let middleware = Arc::new(
+            RetryAfterMiddleware::builder()
+                .kubernetes()
+                .with_clock(clock.clone())
+                .with_sleeper(sleeper.clone())
+                .build(),
+        );
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with_arc(middleware.clone())
+            .build();
+
+        let server = MockServer::start();
+        let throttled = server.mock(|when, then| {
+            when.method(GET).path("/api/v1/pods");
+            then.status(429).header("Retry-After", "1").body("");
+        });
+        let unavailable = server.mock(|when, then| {
+            when.method(GET).path("/apis/apps/v1/deployments");
+            then.status(500).header("Retry-After", "2").body("");
+        });
+
+        let res = client.get(server.url("/api/v1/pods")).send().await.unwrap();
+        assert_eq!(res.status(), 429);
+        throttled.assert_hits(4);
+        assert_eq!(sleeper.sleeps(), vec![Duration::from_secs(1); 3]);
+
+        // a server error's `Retry-After` is honored, but only throttles are retried
+        client
+            .get(server.url("/apis/apps/v1/deployments"))
+            .send()
+            .await
+            .unwrap();
+        unavailable.assert_hits(1);
+        assert_eq!(
+            middleware.snapshot(),
+            vec![(server.host(), clock.now() + Duration::from_secs(2))]
+        );
+    }
+
+    #[tokio::test]
+    async fn waited_marker() {
+        /// Sends every request three times, like a retry middleware.
+        struct Passes {
+            clock: ManualClock,
+            seen: Arc<Mutex<Vec<Option<RetryAfterWaited>>>>,
+        }
+
+        #[async_trait::async_trait]
+        impl Middleware for Passes {
+            async fn handle(
+                &self,
+                req: Request,
+                extensions: &mut Extensions,
+                next: Next<'_>,
+            ) -> reqwest_middleware::Result<reqwest::Response> {
+                let mut res = None;
+                for pass in 0..3 {
+                    if pass == 2 {
+                        self.clock.advance(Duration::from_secs(5));
+                    }
+                    let req = req.try_clone().unwrap();
+                    res = Some(next.clone().run(req, extensions).await);
+                    self.seen.lock().unwrap().push(extensions.get().copied());
+                }
+                res.unwrap()
+            }
+        }
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "5").body("");
+        });
+
+        let clock = ManualClock::new();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(Passes {
+                clock: clock.clone(),
+                seen: seen.clone(),
+            })
+            .with(
+                RetryAfterMiddleware::builder()
+                    .with_clock(clock.clone())
+                    .with_sleeper(InstantSleeper::advancing(&clock))
+                    .build(),
+            )
+            .build();
+        client.get(server.url("/")).send().await.unwrap();
+        assert_eq!(
+            *seen.lock().unwrap(),
+            [None, Some(RetryAfterWaited(Duration::from_secs(5))), None]
+        );
+    }
+
+    #[tokio::test]
+    async fn fallback_backoff() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/throttled");
+            then.status(429).body("");
+        });
+        server.mock(|when, then| {
+            when.method(GET).path("/ok");
+            then.status(200).body("");
+        });
+
+        let clock = ManualClock::new();
+        let middleware = RetryAfterMiddleware::builder()
+            .with_clock(clock.clone())
+            .with_sleeper(InstantSleeper::advancing(&clock))
+            .with_fallback_backoff(Backoff::Exponential {
+                base: Duration::from_secs(1),
+                max: Duration::from_secs(3),
+            })
+            .build();
+        let state = middleware.tracker.state.clone();
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(middleware)
+            .build();
+
+        for secs in [1, 2, 3] {
+            client.get(server.url("/throttled")).send().await.unwrap();
+            assert_eq!(
+                state.until(""),
+                Some(clock.now() + Duration::from_secs(secs))
+            );
+        }
+        client.get(server.url("/ok")).send().await.unwrap();
+        client.get(server.url("/throttled")).send().await.unwrap();
+        assert_eq!(state.until(""), Some(clock.now() + Duration::from_secs(1)));
+    }
+
+    #[tokio::test]
+    async fn retry_after_statuses() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(200).header("Retry-After", "30").body("");
+        });
+
+        let middleware = RetryAfterMiddleware::new();
+        let state = middleware.tracker.state.clone();
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(middleware)
+            .build();
+        client.get(server.url("/")).send().await.unwrap();
+        assert_eq!(state.until(""), None);
+
+        let middleware = RetryAfterMiddleware::builder()
+            .with_retry_after_statuses(|_| true)
+            .build();
+        let state = middleware.tracker.state.clone();
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(middleware)
+            .build();
+        client.get(server.url("/")).send().await.unwrap();
+        assert!(state.until("").is_some());
+    }
+
+    #[tokio::test]
+    async fn retry_after_ms() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429)
+                .header("Retry-After", "1")
+                .header("Retry-After-Ms", "250")
+                .body("");
+        });
+
+        let clock = ManualClock::new();
+        let middleware = RetryAfterMiddleware::builder()
+            .with_clock(clock.clone())
+            .build();
+        let state = middleware.tracker.state.clone();
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(middleware)
+            .build();
+        client.get(server.url("/")).send().await.unwrap();
+        assert_eq!(
+            state.until(""),
+            Some(clock.now() + Duration::from_millis(250))
+        );
+    }
+
+    #[tokio::test]
+    async fn retry_after_header() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429)
+                .header("Retry-After", "60")
+                .header("X-Cooldown-Seconds", "nope")
+                .header("X-Cooldown-Until", "1700000030")
+                .body("");
+        });
+
+        let clock =
+            ManualClock::starting_at(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        let middleware = RetryAfterMiddleware::builder()
+            .with_clock(clock.clone())
+            .with_retry_after_header(
+                HeaderName::from_static("x-cooldown-seconds"),
+                HeaderFormat::Seconds,
+            )
+            .with_retry_after_header(
+                HeaderName::from_static("x-cooldown-until"),
+                HeaderFormat::Timestamp,
+            )
+            .build();
+        let state = middleware.tracker.state.clone();
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(middleware)
+            .build();
+        client.get(server.url("/")).send().await.unwrap();
+        assert_eq!(state.until(""), Some(clock.now() + Duration::from_secs(30)));
+    }
+
+    #[tokio::test]
+    async fn rate_limit_reset() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(200)
+                .header("RateLimit-Limit", "10")
+                .header("RateLimit-Remaining", "0")
+                .header("RateLimit-Reset", "40")
+                .body("");
+        });
+
+        let clock = ManualClock::new();
+        let middleware = RetryAfterMiddleware::builder()
+            .with_clock(clock.clone())
+            .with_rate_limit_source(RateLimitSource::RateLimit)
+            .build();
+        let state = middleware.tracker.state.clone();
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(middleware)
+            .build();
+        client.get(server.url("/")).send().await.unwrap();
+        assert_eq!(state.until(""), Some(clock.now() + Duration::from_secs(40)));
+    }
+
+    #[tokio::test]
+    async fn x_rate_limit_reset() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(403)
+                .header("X-RateLimit-Remaining", "0")
+                .header("X-RateLimit-Reset", "1700000060")
+                .body("");
+        });
+
+        let clock =
+            ManualClock::starting_at(SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+        let middleware = RetryAfterMiddleware::builder()
+            .with_clock(clock.clone())
+            .with_rate_limit_source(RateLimitSource::XRateLimit)
+            .build();
+        let state = middleware.tracker.state.clone();
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(middleware)
+            .build();
+        client.get(server.url("/")).send().await.unwrap();
+        assert_eq!(state.until(""), Some(clock.now() + Duration::from_secs(60)));
+    }
+
+    #[tokio::test]
+    async fn safety_margin() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "10").body("");
+        });
+
+        let clock = ManualClock::new();
+        let middleware = RetryAfterMiddleware::builder()
+            .with_clock(clock.clone())
+            .with_safety_margin(Duration::from_millis(500))
+            .with_delay_factor(1.5)
+            .build();
+        let state = middleware.tracker.state.clone();
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(middleware)
+            .build();
+        client.get(server.url("/")).send().await.unwrap();
+        assert_eq!(
+            state.until(""),
+            Some(clock.now() + Duration::from_millis(15_500))
+        );
+    }
+
+    #[test]
+    fn jitter() {
+        let delay = Duration::from_secs(10);
+        for _ in 0..100 {
+            let full = Jitter::Full.apply(delay, None);
+            assert!(full >= delay && full <= delay * 2);
+
+            let first = Jitter::Decorrelated.apply(delay, None);
+            assert!(first >= delay && first <= delay * 3);
+
+            let decorrelated = Jitter::Decorrelated.apply(delay, Some(Duration::from_secs(5)));
+            assert!(decorrelated >= delay && decorrelated <= Duration::from_secs(15));
+
+            let proportional = Jitter::Proportional(0.1).apply(delay, None);
+            assert!(
+                proportional >= Duration::from_secs(9) && proportional <= Duration::from_secs(11)
+            );
+        }
+        assert_eq!(Jitter::None.apply(delay, None), delay);
+    }
+
+    #[tokio::test]
+    async fn manual_clock() {
+        let clock = ManualClock::new();
+        let sleeper = InstantSleeper::advancing(&clock);
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(
+                RetryAfterMiddleware::builder()
+                    .with_clock(clock.clone())
+                    .with_sleeper(sleeper.clone())
+                    .build(),
+            )
+            .build();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "3600").body("");
+        });
+
+        let start = clock.now();
+        client.get(server.url("/")).send().await.unwrap();
+        client.get(server.url("/")).send().await.unwrap();
+
+        assert_eq!(sleeper.sleeps(), vec![Duration::from_secs(3600)]);
+        assert_eq!(clock.now(), start + Duration::from_secs(3600));
+    }
+
+    #[tokio::test]
+    async fn sleep_chunk() {
+        let clock = ManualClock::new();
+        let state = Arc::<State>::default();
+        let sleeps = Arc::new(Mutex::new(Vec::new()));
+
+        // clears the stored value after the second chunk, as a later response would
+        let sleeper = {
+            let (clock, state, sleeps) = (clock.clone(), state.clone(), sleeps.clone());
+            move |duration| -> BoxFuture<'static, ()> {
+                clock.advance(duration);
+                let mut sleeps = sleeps.lock().unwrap();
+                sleeps.push(duration);
+                if sleeps.len() == 2 {
+                    state.retry_after.lock().unwrap().clear();
+                }
+                Box::pin(async {})
+            }
+        };
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(
+                RetryAfterMiddlewareBuilder::from_state(state)
+                    .with_clock(clock.clone())
+                    .with_sleeper(sleeper)
+                    .with_sleep_chunk(Duration::from_secs(3))
+                    .build(),
+            )
+            .build();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "10").body("");
+        });
+        server.mock(|when, then| {
+            when.method(GET).path("/ok");
+            then.status(200).body("");
+        });
+
+        client.get(server.url("/")).send().await.unwrap();
+        client.get(server.url("/ok")).send().await.unwrap();
+
+        assert_eq!(*sleeps.lock().unwrap(), vec![Duration::from_secs(3); 2]);
+    }
+
+    #[tokio::test]
+    async fn proportional_jitter_shortens() {
+        let clock = ManualClock::new();
+        let middleware = RetryAfterMiddleware::builder()
+            .with_clock(clock.clone())
+            .with_sleeper(InstantSleeper::advancing(&clock))
+            .with_sleep_chunk(Duration::from_secs(1))
+            .with_jitter(Jitter::Proportional(0.5))
+            .build();
+        let mut events = middleware.subscribe();
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(middleware.clone())
+            .build();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(200).body("");
+        });
+
+        // half of the jittered waits are shorter than the stored one
+        let url = Url::parse(&server.url("/")).unwrap();
+        let mut shortened = false;
+        for _ in 0..64 {
+            middleware.insert(&url, clock.now() + Duration::from_secs(10));
+            client.get(url.clone()).send().await.unwrap();
+
+            let (mut started, mut finished) = (None, None);
+            for event in std::iter::from_fn(|| events.try_recv().ok()) {
+                match event {
+                    RetryAfterEvent::WaitStarted { duration, .. } => started = Some(duration),
+                    RetryAfterEvent::WaitFinished { waited, .. } => finished = Some(waited),
+                    _ => {}
+                }
+            }
+            assert_eq!(started, finished);
+            if started < Some(Duration::from_secs(10)) {
+                shortened = true;
+                break;
+            }
+        }
+        assert!(shortened);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paused_time() {
+        let middleware = Arc::new(
+            RetryAfterMiddleware::builder()
+                .with_clock(TokioClock::new())
+                .with_sleeper(TokioSleeper)
+                .build(),
+        );
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with_arc(middleware.clone())
+            .build();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "3600").body("");
+        });
+        server.mock(|when, then| {
+            when.method(GET).path("/ok");
+            then.status(200).body("");
+        });
+
+        let url = Url::parse(&server.url("/")).unwrap();
+        client.get(url.clone()).send().await.unwrap();
+        assert!(middleware.wait_remaining(&url).is_some());
+
+        // the wait is skipped through, and the stored value passes with it
+        let start = std::time::Instant::now();
+        client.get(server.url("/ok")).send().await.unwrap();
+        assert!(start.elapsed() < Duration::from_secs(60));
+        assert_eq!(middleware.wait_remaining(&url), None);
+    }
+
+    #[tokio::test]
+    async fn extended_while_sleeping() {
+        let clock = ManualClock::new();
+        let state = Arc::<State>::default();
+        let sleeps = Arc::new(Mutex::new(Vec::new()));
+
+        // extends the stored value during the first wait, as a concurrent response would
+        let sleeper = {
+            let (clock, state, sleeps) = (clock.clone(), state.clone(), sleeps.clone());
+            move |duration| -> BoxFuture<'static, ()> {
+                clock.advance(duration);
+                let mut sleeps = sleeps.lock().unwrap();
+                sleeps.push(duration);
+                if sleeps.len() == 1 {
+                    let until = clock.now() + Duration::from_secs(5);
+                    state
+                        .retry_after
+                        .lock()
+                        .unwrap()
+                        .insert(String::new(), until);
+                }
+                Box::pin(async {})
+            }
+        };
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(
+                RetryAfterMiddlewareBuilder::from_state(state)
+                    .with_clock(clock.clone())
+                    .with_sleeper(sleeper)
+                    .build(),
+            )
+            .build();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "10").body("");
+        });
+        server.mock(|when, then| {
+            when.method(GET).path("/ok");
+            then.status(200).body("");
+        });
+
+        client.get(server.url("/")).send().await.unwrap();
+        client.get(server.url("/ok")).send().await.unwrap();
+
+        let expected = [Duration::from_secs(10), Duration::from_secs(5)];
+        assert_eq!(*sleeps.lock().unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn recovery_probe() {
+        let clock = ManualClock::new();
+        let middleware = Arc::new(
+            RetryAfterMiddleware::builder()
+                .with_clock(clock.clone())
+                .with_sleeper(InstantSleeper::advancing(&clock))
+                .with_recovery_probe(reqwest::Client::new())
+                .build(),
+        );
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with_arc(middleware.clone())
+            .build();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "10").body("");
+        });
+        let probe = server.mock(|when, then| {
+            when.method(HEAD).path("/ok");
+            then.status(200);
+        });
+        let ok = server.mock(|when, then| {
+            when.method(GET).path("/ok");
+            then.status(200).body("");
+        });
+
+        client.get(server.url("/")).send().await.unwrap();
+        client.get(server.url("/ok")).send().await.unwrap();
+
+        probe.assert();
+        ok.assert();
+        assert_eq!(middleware.poll_remaining(), None);
+    }
+
+    #[tokio::test]
+    async fn recovery_probe_violations() {
+        for policy in [OnViolation::Ignore, OnViolation::Error] {
+            let clock = ManualClock::new();
+            let client = ClientBuilder::new(reqwest::Client::new())
+                .with(
+                    RetryAfterMiddleware::builder()
+                        .with_clock(clock.clone())
+                        .with_sleeper(InstantSleeper::advancing(&clock))
+                        .with_strict_validation(policy)
+                        .with_recovery_probe(reqwest::Client::new())
+                        .build(),
+                )
+                .build();
+
+            let server = MockServer::start();
+            server.mock(|when, then| {
+                when.method(GET).path("/");
+                then.status(429).header("Retry-After", "10").body("");
+            });
+            // kept by `Ignore`, rejected by `Error`
+            let probe = server.mock(|when, then| {
+                when.method(HEAD).path("/ok");
+                then.status(429).header("Retry-After", "soon");
+            });
+            let ok = server.mock(|when, then| {
+                when.method(GET).path("/ok");
+                then.status(200).body("");
+            });
+
+            client.get(server.url("/")).send().await.unwrap();
+            let res = client.get(server.url("/ok")).send().await;
+
+            probe.assert_hits(1);
+            if policy == OnViolation::Ignore {
+                assert!(res.is_ok());
+                ok.assert_hits(1);
+            } else {
+                assert!(res.is_err());
+                ok.assert_hits(0);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn predictive_delay() {
+        let clock = ManualClock::new();
+        let sleeper = InstantSleeper::new();
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(
+                RetryAfterMiddleware::builder()
+                    .with_clock(clock.clone())
+                    .with_sleeper(sleeper.clone())
+                    .with_predictive_delay(Duration::from_secs(2))
+                    .build(),
+            )
+            .build();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/limit");
+            then.status(429).header("Retry-After", "10").body("");
+        });
+        server.mock(|when, then| {
+            when.method(GET).path("/ok");
+            then.status(200).body("");
+        });
+
+        // the host throttles every 60 seconds
+        client.get(server.url("/limit")).send().await.unwrap();
+        clock.advance(Duration::from_secs(60));
+        client.get(server.url("/limit")).send().await.unwrap();
+
+        clock.advance(Duration::from_secs(20));
+        client.get(server.url("/ok")).send().await.unwrap();
+        assert!(sleeper.sleeps().is_empty());
+
+        clock.advance(Duration::from_secs(30));
+        client.get(server.url("/ok")).send().await.unwrap();
+        assert_eq!(sleeper.sleeps(), vec![Duration::from_secs(2)]);
+    }
+
+    #[tokio::test]
+    async fn predictive_delay_per_key() {
+        let clock = ManualClock::new();
+        let sleeper = InstantSleeper::new();
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(
+                RetryAfterMiddleware::builder()
+                    .with_clock(clock.clone())
+                    .with_sleeper(sleeper.clone())
+                    .with_scope(Scope::Url)
+                    .with_predictive_delay(Duration::from_secs(2))
+                    .build(),
+            )
+            .build();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/limit");
+            then.status(429).header("Retry-After", "10").body("");
+        });
+        server.mock(|when, then| {
+            when.method(GET).path("/ok");
+            then.status(200).body("");
+        });
+
+        client.get(server.url("/limit")).send().await.unwrap();
+        clock.advance(Duration::from_secs(60));
+        client.get(server.url("/limit")).send().await.unwrap();
+
+        // only the URL that throttles every 60 seconds is expected to do so again
+        clock.advance(Duration::from_secs(50));
+        client.get(server.url("/ok")).send().await.unwrap();
+        assert!(sleeper.sleeps().is_empty());
+        client.get(server.url("/limit")).send().await.unwrap();
+        assert_eq!(sleeper.sleeps(), vec![Duration::from_secs(2)]);
+    }
+
+    #[tokio::test]
+    async fn scope() {
+        let clock = ManualClock::new();
+        let sleeper = InstantSleeper::advancing(&clock);
+        let middleware = Arc::new(
+            RetryAfterMiddleware::builder()
+                .with_clock(clock.clone())
+                .with_sleeper(sleeper.clone())
+                .with_scope(Scope::Origin)
+                .build(),
+        );
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with_arc(middleware.clone())
+            .build();
+
+        // both servers listen on localhost, on different ports
+        let limited = MockServer::start();
+        limited.mock(|when, then| {
+            when.method(GET);
+            then.status(429).header("Retry-After", "10").body("");
+        });
+        let other = MockServer::start();
+        other.mock(|when, then| {
+            when.method(GET);
+            then.status(200).body("");
+        });
+
+        client
+            .get(limited.url("/items?page=2"))
+            .send()
+            .await
+            .unwrap();
+        client.get(other.url("/")).send().await.unwrap();
+        assert!(sleeper.sleeps().is_empty());
+        assert_eq!(
+            middleware.wait_remaining(&other.url("/").parse().unwrap()),
+            None
+        );
+        assert_eq!(
+            middleware.wait_remaining(&limited.url("/").parse().unwrap()),
+            Some(Duration::from_secs(10))
+        );
+
+        client
+            .get(limited.url("/items?page=3"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(sleeper.sleeps(), vec![Duration::from_secs(10)]);
+    }
+
+    #[tokio::test]
+    async fn scope_prefix() {
+        let clock = ManualClock::new();
+        let sleeper = InstantSleeper::advancing(&clock);
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(
+                RetryAfterMiddleware::builder()
+                    .with_clock(clock.clone())
+                    .with_sleeper(sleeper.clone())
+                    .with_scope(Scope::Prefix(2))
+                    .with_detector(ObjectStoreSlowDown::new(
+                        Duration::from_secs(1),
+                        Duration::from_secs(20),
+                    ))
+                    .build(),
+            )
+            .build();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/bucket/logs/a");
+            then.status(503)
+                .body("<Error><Code>SlowDown</Code></Error>");
+        });
+        server.mock(|when, then| {
+            when.method(GET).path_contains("/bucket/");
+            then.status(200).body("");
+        });
+
+        client
+            .get(server.url("/bucket/logs/a"))
+            .send()
+            .await
+            .unwrap();
+        client
+            .get(server.url("/bucket/images/b"))
+            .send()
+            .await
+            .unwrap();
+        assert!(sleeper.sleeps().is_empty());
+        client
+            .get(server.url("/bucket/logs/c"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(sleeper.sleeps(), vec![Duration::from_secs(1)]);
+
+        let key = Scope::Prefix(2).key_for_url(&server.url("/bucket/logs/c").parse().unwrap());
+        assert_eq!(key, format!("{}/bucket/logs", server.host()));
+    }
+
+    #[tokio::test]
+    async fn release_stagger() {
+        let clock = ManualClock::new();
+        let sleeper = InstantSleeper::new();
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(
+                RetryAfterMiddleware::builder()
+                    .with_clock(clock.clone())
+                    .with_sleeper(sleeper.clone())
+                    .with_scope(Scope::Origin)
+                    .with_release_stagger(Duration::from_secs(1))
+                    .build(),
+            )
+            .build();
+
+        // the clock stands still, so every response stores the same value again
+        let (a, b) = (MockServer::start(), MockServer::start());
+        for server in [&a, &b] {
+            server.mock(|when, then| {
+                when.method(GET);
+                then.status(429).header("Retry-After", "10").body("");
+            });
+        }
+        client.get(a.url("/")).send().await.unwrap();
+        client.get(b.url("/")).send().await.unwrap();
+
+        for server in [&a, &a, &b, &a] {
+            client.get(server.url("/")).send().await.unwrap();
+        }
+        let secs = |secs| Duration::from_secs(secs);
+        assert_eq!(
+            sleeper.sleeps(),
+            vec![secs(10), secs(11), secs(10), secs(12)]
+        );
+    }
+
+    #[tokio::test]
+    async fn cdn_edge() {
+        let clock = ManualClock::new();
+        let sleeper = InstantSleeper::advancing(&clock);
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(
+                RetryAfterMiddleware::builder()
+                    .with_clock(clock.clone())
+                    .with_sleeper(sleeper.clone())
+                    .with_scope(Scope::Url)
+                    .with_key_group(CdnEdge::new())
+                    .build(),
+            )
+            .build();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/edge");
+            then.status(429)
+                .header("Retry-After", "10")
+                .header("cf-ray", "8a1b2c3d4e5f-AMS")
+                .body("");
+        });
+        server.mock(|when, then| {
+            when.method(GET).path("/origin");
+            then.status(429).header("Retry-After", "5").body("");
+        });
+        server.mock(|when, then| {
+            when.method(GET).path("/ok");
+            then.status(200).body("");
+        });
+
+        // a throttle from the origin only holds back its own URL
+        client.get(server.url("/origin")).send().await.unwrap();
+        client.get(server.url("/ok")).send().await.unwrap();
+        assert!(sleeper.sleeps().is_empty());
+
+        // one from the edge holds back the whole host
+        client.get(server.url("/edge")).send().await.unwrap();
+        client.get(server.url("/ok")).send().await.unwrap();
+        assert_eq!(sleeper.sleeps(), vec![Duration::from_secs(10)]);
+    }
+
+    #[tokio::test]
+    async fn proxy_group() {
+        let clock = ManualClock::new();
+        let sleeper = InstantSleeper::advancing(&clock);
+        let proxy = "http://proxy.internal:3128".parse().unwrap();
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(
+                RetryAfterMiddleware::builder()
+                    .with_clock(clock.clone())
+                    .with_sleeper(sleeper.clone())
+                    .with_scope(Scope::Origin)
+                    .with_throttle_status(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+                    .with_key_group(ProxyGroup::new(&proxy))
+                    .build(),
+            )
+            .build();
+
+        // stands in for a destination reached through the proxy, which answers in its place
+        let limited = MockServer::start();
+        limited.mock(|when, then| {
+            when.method(GET);
+            then.status(407).header("Retry-After", "10").body("");
+        });
+        let other = MockServer::start();
+        other.mock(|when, then| {
+            when.method(GET);
+            then.status(200).body("");
+        });
+
+        client.get(limited.url("/")).send().await.unwrap();
+        client.get(other.url("/")).send().await.unwrap();
+        assert_eq!(sleeper.sleeps(), vec![Duration::from_secs(10)]);
+    }
+
+    #[test]
+    fn mutation() {
+        let clock = ManualClock::new();
+        let middleware = RetryAfterMiddleware::builder()
+            .with_clock(clock.clone())
+            .with_scope(Scope::Host)
+            .build();
+        let a: Url = "https://a.example/".parse().unwrap();
+        let b: Url = "https://b.example/items".parse().unwrap();
+
+        middleware.insert(&a, clock.now() + Duration::from_secs(10));
+        middleware.insert(&b, clock.now() + Duration::from_secs(20));
+        assert_eq!(middleware.wait_remaining(&a), Some(Duration::from_secs(10)));
+        assert!(middleware.is_throttled(&a));
+        assert_eq!(middleware.check(&b), Err(Duration::from_secs(20)));
+
+        assert_eq!(
+            middleware.snapshot(),
+            vec![
+                (
+                    "a.example".to_owned(),
+                    clock.now() + Duration::from_secs(10)
+                ),
+                (
+                    "b.example".to_owned(),
+                    clock.now() + Duration::from_secs(20)
+                ),
+            ]
+        );
+
+        middleware.remove(&a);
+        assert_eq!(middleware.wait_remaining(&a), None);
+        assert!(!middleware.is_throttled(&a));
+        assert_eq!(middleware.check(&a), Ok(()));
+        assert_eq!(middleware.wait_remaining(&b), Some(Duration::from_secs(20)));
+
+        middleware.clear();
+        assert_eq!(middleware.poll_remaining(), None);
+    }
+
+    #[test]
+    fn saved_state() {
+        let clock = ManualClock::new();
+        let middleware = RetryAfterMiddleware::builder()
+            .with_clock(clock.clone())
+            .with_scope(Scope::Host)
+            .build();
+        let a: Url = "https://a.example/".parse().unwrap();
+        let b: Url = "https://b.example/".parse().unwrap();
+        middleware.insert(&a, clock.now() + Duration::from_secs(10));
+        middleware.insert(&b, clock.now() + Duration::from_secs(20));
+        let saved = middleware.save_state();
+
+        #[cfg(feature = "serde")]
+        let saved = {
+            let json = serde_json::to_string(&saved).unwrap();
+            serde_json::from_str(&json).unwrap()
+        };
+
+        clock.advance(Duration::from_secs(15));
+        let restored = RetryAfterMiddleware::builder()
+            .with_clock(clock.clone())
+            .with_scope(Scope::Host)
+            .build();
+        restored.load_state(saved);
+        assert_eq!(restored.wait_remaining(&a), None);
+        assert_eq!(restored.wait_remaining(&b), Some(Duration::from_secs(5)));
+        assert_eq!(restored.snapshot().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn store() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "10").body("");
+        });
+        server.mock(|when, then| {
+            when.method(GET).path("/ok");
+            then.status(200).body("");
+        });
+
+        let clock = ManualClock::new();
+        let store = Arc::new(MemoryStore::new());
+        let client_with = |sleeper: &InstantSleeper| {
+            let middleware = RetryAfterMiddleware::builder()
+                .with_clock(clock.clone())
+                .with_sleeper(sleeper.clone())
+                .with_store(store.clone())
+                .build();
+            ClientBuilder::new(reqwest::Client::new())
+                .with(middleware)
+                .build()
+        };
+        let (first, second) = (InstantSleeper::new(), InstantSleeper::new());
+        client_with(&first)
+            .get(server.url("/"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(
+            store.get("").await,
+            Some(clock.now() + Duration::from_secs(10))
+        );
+
+        client_with(&second)
+            .get(server.url("/ok"))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(second.sleeps(), vec![Duration::from_secs(10)]);
+        assert_eq!(store.get("").await, None);
+    }
+
+    #[tokio::test]
+    async fn mock_store() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "10").body("");
+        });
+
+        let clock = ManualClock::new();
+        let sleeper = InstantSleeper::new();
+        let store = Arc::new(MockStore::new());
+        store.push_get("", Some(clock.now() + Duration::from_secs(5)));
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(
+                RetryAfterMiddleware::builder()
+                    .with_clock(clock.clone())
+                    .with_sleeper(sleeper.clone())
+                    .with_store(store.clone())
+                    .build(),
+            )
+            .build();
+
+        client.get(server.url("/")).send().await.unwrap();
+        assert_eq!(sleeper.sleeps(), vec![Duration::from_secs(5)]);
+        assert_eq!(
+            store.calls(),
+            vec![
+                StoreCall::Get("".to_owned()),
+                StoreCall::Set("".to_owned(), clock.now() + Duration::from_secs(10)),
+            ]
+        );
+    }
+
+    #[test]
+    fn max_entries() {
+        let clock = ManualClock::new();
+        let middleware = RetryAfterMiddleware::builder()
+            .with_clock(clock.clone())
+            .with_scope(Scope::Url)
+            .with_max_entries(2)
+            .build();
+        let url =
+            |page: u64| -> Url { format!("https://example.com/?page={page}").parse().unwrap() };
+        for (page, secs) in [(1, 30), (2, 10), (3, 20), (4, 5)] {
+            middleware.insert(&url(page), clock.now() + Duration::from_secs(secs));
+        }
+
+        let keys: Vec<_> = middleware
+            .snapshot()
+            .into_iter()
+            .map(|(key, _)| key)
+            .collect();
+        assert_eq!(keys, [url(1).to_string(), url(4).to_string()]);
+    }
+
+    #[tokio::test]
+    async fn eviction_task() {
+        let clock = ManualClock::new();
+        let middleware = RetryAfterMiddleware::builder()
+            .with_clock(clock.clone())
+            .with_sleeper(|_| -> BoxFuture<'static, ()> { Box::pin(tokio::task::yield_now()) })
+            .with_scope(Scope::Url)
+            .build();
+        for (page, secs) in [(1, 10), (2, 30)] {
+            let url = format!("https://example.com/?page={page}").parse().unwrap();
+            middleware.insert(&url, clock.now() + Duration::from_secs(secs));
+        }
+        assert_eq!(middleware.evict_expired(), 0);
+
+        let (task, eviction) = middleware.eviction_task(Duration::from_secs(20));
+        let task = tokio::spawn(task);
+        clock.advance(Duration::from_secs(20));
+        while middleware.snapshot().len() > 1 {
+            tokio::task::yield_now().await;
+        }
+        eviction.shutdown();
+        task.await.unwrap();
+        assert_eq!(middleware.snapshot().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn subscribe() {
+        let server = MockServer::start();
+        let mut limited = server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "10").body("");
+        });
+
+        let clock = ManualClock::new();
+        let middleware = Arc::new(
+            RetryAfterMiddleware::builder()
+                .with_clock(clock.clone())
+                .with_sleeper(InstantSleeper::advancing(&clock))
+                .build(),
+        );
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with_arc(middleware.clone())
+            .build();
+        let mut events = middleware.subscribe();
+
+        client.get(server.url("/")).send().await.unwrap();
+        let until = clock.now() + Duration::from_secs(10);
+        limited.delete();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(200).body("");
+        });
+        client.get(server.url("/")).send().await.unwrap();
+        middleware.insert(&Url::parse(&server.url("/")).unwrap(), clock.now());
+        assert_eq!(middleware.evict_expired(), 1);
+
+        let key = String::new;
+        let received: Vec<_> = std::iter::from_fn(|| events.try_recv().ok()).collect();
+        assert_eq!(
+            received,
+            [
+                RetryAfterEvent::Recorded { key: key(), until },
+                RetryAfterEvent::WaitStarted {
+                    key: key(),
+                    duration: Duration::from_secs(10),
+                },
+                RetryAfterEvent::WaitFinished {
+                    key: key(),
+                    waited: Duration::from_secs(10),
+                },
+                RetryAfterEvent::Cleared { key: key() },
+                RetryAfterEvent::Expired { key: key() },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn subscribe_shared_state() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "10").body("");
+        });
+
+        let clock = ManualClock::new();
+        let state = RetryAfterState::new();
+        let recording = RetryAfterMiddleware::builder()
+            .with_state(state.clone())
+            .with_clock(clock.clone())
+            .build();
+        let listening = RetryAfterMiddleware::builder().with_state(state).build();
+        let mut events = listening.subscribe();
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(recording)
+            .build();
+
+        client.get(server.url("/")).send().await.unwrap();
+        assert_eq!(
+            events.try_recv().unwrap(),
+            RetryAfterEvent::Recorded {
+                key: String::new(),
+                until: clock.now() + Duration::from_secs(10),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn key_extractor() {
+        let clock = ManualClock::new();
+        let sleeper = InstantSleeper::advancing(&clock);
+        let middleware = Arc::new(
+            RetryAfterMiddleware::builder()
+                .with_clock(clock.clone())
+                .with_sleeper(sleeper.clone())
+                .with_key_extractor(|req: &Request| {
+                    let bucket = req.headers()["x-bucket"].to_str().unwrap();
+                    format!("{}/{bucket}", req.url().host_str().unwrap())
+                })
+                .build(),
+        );
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with_arc(middleware.clone())
+            .build();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).header("x-bucket", "search");
+            then.status(429).header("Retry-After", "10").body("");
+        });
+        server.mock(|when, then| {
+            when.method(GET).header("x-bucket", "core");
+            then.status(200).body("");
+        });
+
+        let send = |bucket: &'static str| {
+            client
+                .get(server.url("/"))
+                .header("x-bucket", bucket)
+                .send()
+        };
+        send("search").await.unwrap();
+        send("core").await.unwrap();
+        assert!(sleeper.sleeps().is_empty());
+        assert!(!middleware.is_host_available(&server.host()));
+
+        send("search").await.unwrap();
+        assert_eq!(sleeper.sleeps(), vec![Duration::from_secs(10)]);
+    }
+
+    #[tokio::test]
+    async fn preflight() {
+        let clock = ManualClock::new();
+        let sleeper = InstantSleeper::advancing(&clock);
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(
+                RetryAfterMiddleware::builder()
+                    .with_clock(clock.clone())
+                    .with_sleeper(sleeper.clone())
+                    .with_key_extractor(|req: &Request| format!("{} {}", req.method(), req.url()))
+                    .build(),
+            )
+            .build();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(OPTIONS);
+            then.status(429).header("Retry-After", "10").body("");
+        });
+        server.mock(|when, then| {
+            when.any_request();
+            then.status(200).body("");
+        });
+
+        client
+            .request(http::Method::OPTIONS, server.url("/items"))
+            .header("Access-Control-Request-Method", "PUT")
+            .send()
+            .await
+            .unwrap();
+        client.get(server.url("/items")).send().await.unwrap();
+        assert!(sleeper.sleeps().is_empty());
+        client.put(server.url("/items")).send().await.unwrap();
+        assert_eq!(sleeper.sleeps(), vec![Duration::from_secs(10)]);
+    }
+
+    #[test]
+    fn key_for_url() {
+        let url: Url = "https://example.com:8443/a?page=2".parse().unwrap();
+        let req = Request::new(http::Method::GET, url.clone());
+        for scope in [
+            Scope::Global,
+            Scope::Url,
+            Scope::Origin,
+            Scope::Host,
+            Scope::Prefix(1),
+        ] {
+            assert_eq!(scope.key_for_url(&url), scope.key(&req));
+        }
+        // extractors without their own `key_for_url` see a `GET` request to the URL
+        let keys = |req: &Request| format!("{} {}", req.method(), req.url());
+        assert_eq!(
+            keys.key_for_url(&url),
+            "GET https://example.com:8443/a?page=2"
+        );
+    }
+
+    #[test]
+    fn retry_after_key() {
+        let clock = ManualClock::new();
+        let middleware = RetryAfterMiddleware::builder()
+            .with_clock(clock.clone())
+            .with_scope(Scope::Origin)
+            .build();
+        let url: Url = "https://example.com:8443/a?page=2".parse().unwrap();
+        let until = clock.now() + Duration::from_secs(10);
+        middleware.insert(&url, until);
+
+        let key = RetryAfterKey::from_url_scoped(&url, Scope::Origin);
+        assert_eq!(
+            middleware.snapshot(),
+            vec![(key.clone().into_string(), until)]
+        );
+        let req = Request::new(http::Method::POST, url);
+        assert_eq!(RetryAfterKey::from_request(&req, &Scope::Origin), key);
+        assert_eq!(key.to_string(), "https://example.com:8443");
+    }
+
+    #[tokio::test]
+    async fn poll_remaining() {
+        let clock = ManualClock::new();
+        let middleware = Arc::new(
+            RetryAfterMiddleware::builder()
+                .with_clock(clock.clone())
+                .build(),
+        );
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with_arc(middleware.clone())
+            .build();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "10").body("");
+        });
+
+        assert_eq!(middleware.poll_remaining(), None);
+        client.get(server.url("/")).send().await.unwrap();
+        assert_eq!(middleware.poll_remaining(), Some(Duration::from_secs(10)));
+
+        clock.advance(Duration::from_secs(4));
+        assert_eq!(middleware.poll_remaining(), Some(Duration::from_secs(6)));
+        let reader = middleware.reader().clone();
+        assert_eq!(reader.poll_remaining(), Some(Duration::from_secs(6)));
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(middleware.poll_remaining(), None);
+    }
+
+    #[tokio::test]
+    async fn poll_remaining_for() {
+        let throttled = MockServer::start();
+        throttled.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "10").body("");
+        });
+        let other = MockServer::start();
+
+        let clock = ManualClock::new();
+        let middleware = Arc::new(
+            RetryAfterMiddleware::builder()
+                .with_clock(clock.clone())
+                .with_scope(Scope::Origin)
+                .build(),
+        );
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with_arc(middleware.clone())
+            .build();
+        client.get(throttled.url("/")).send().await.unwrap();
+
+        let throttled = throttled.url("/").parse().unwrap();
+        let other = other.url("/").parse().unwrap();
+        assert_eq!(
+            middleware.poll_remaining_for(&throttled),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(middleware.poll_remaining_for(&other), None);
+        assert_eq!(middleware.poll_remaining(), Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn wait_all() {
+        let clock = ManualClock::new();
+        let middleware = RetryAfterMiddleware::builder()
+            .with_clock(clock.clone())
+            .with_scope(Scope::Host)
+            .build();
+        let url = |host: &str| -> Url { format!("https://{host}/").parse().unwrap() };
+        let (a, b, c) = (
+            url("a.example.com"),
+            url("b.example.com"),
+            url("c.example.com"),
+        );
+        middleware.insert(&a, clock.now() + Duration::from_secs(5));
+        middleware.insert(&b, clock.now() + Duration::from_secs(20));
+
+        assert_eq!(
+            middleware.wait_all([&a, &b, &c]),
+            Some(Duration::from_secs(20))
+        );
+        assert_eq!(middleware.wait_all([&a, &c]), Some(Duration::from_secs(5)));
+        assert_eq!(middleware.wait_all([&c]), None);
+        clock.advance(Duration::from_secs(20));
+        assert_eq!(middleware.wait_all([&a, &b]), None);
+    }
+
+    #[test]
+    fn expiring_within() {
+        let clock = ManualClock::new();
+        let middleware = RetryAfterMiddleware::builder()
+            .with_clock(clock.clone())
+            .with_scope(Scope::Host)
+            .build();
+        let url = |host: &str| -> Url { format!("https://{host}/").parse().unwrap() };
+        let now = clock.now();
+        middleware.insert(&url("a.example.com"), now + Duration::from_secs(5));
+        middleware.insert(&url("b.example.com"), now + Duration::from_secs(60));
+        middleware.insert(&url("c.example.com"), now + Duration::from_secs(10));
+
+        assert_eq!(
+            middleware.expiring_within(Duration::from_secs(10)),
+            vec![
+                ("a.example.com".to_owned(), now + Duration::from_secs(5)),
+                ("c.example.com".to_owned(), now + Duration::from_secs(10)),
+            ]
+        );
+        // values that have passed are no longer expiring
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(
+            middleware.expiring_within(Duration::from_secs(5)),
+            vec![("c.example.com".to_owned(), now + Duration::from_secs(10))]
+        );
+    }
+
+    #[tokio::test]
+    async fn is_host_available() {
+        let clock = ManualClock::new();
+        let middleware = Arc::new(
+            RetryAfterMiddleware::builder()
+                .with_clock(clock.clone())
+                .build(),
+        );
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with_arc(middleware.clone())
+            .build();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "10").body("");
+        });
+
+        let host = Url::parse(&server.base_url()).unwrap();
+        let host = host.host_str().unwrap();
+        assert!(middleware.is_host_available(host));
+
+        client.get(server.url("/")).send().await.unwrap();
+        assert!(!middleware.is_host_available(host));
+        assert_eq!(
+            middleware.throttled_until(host),
+            Some(clock.now() + Duration::from_secs(10))
+        );
+
+        clock.advance(Duration::from_secs(10));
+        assert!(middleware.is_host_available(host));
+    }
+
+    #[tokio::test]
+    async fn block_until_idle() {
+        // a sleeper that only wakes up when told to
+        #[derive(Clone, Default)]
+        struct GateSleeper(Arc<Notify>);
+
+        #[async_trait::async_trait]
+        impl Sleeper for GateSleeper {
+            async fn sleep(&self, _: Duration) {
+                self.0.notified().await
+            }
+        }
+
+        let gate = GateSleeper::default();
+        let middleware = Arc::new(
+            RetryAfterMiddleware::builder()
+                .with_sleeper(gate.clone())
+                .build(),
+        );
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with_arc(middleware.clone())
+            .build();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "10").body("");
+        });
+
+        middleware.block_until_idle().await;
+        client.get(server.url("/")).send().await.unwrap();
+
+        let url = server.url("/");
+        let pending = tokio::spawn(async move { client.get(url).send().await });
+        while *middleware.sleeping.borrow() == 0 {
+            tokio::task::yield_now().await;
+        }
+
+        let idle = tokio::time::timeout(Duration::from_millis(50), middleware.block_until_idle());
+        assert!(idle.await.is_err());
+
+        gate.0.notify_one();
+        middleware.block_until_idle().await;
+        pending.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn persistence_callbacks() {
+        let persisted = Arc::new(Mutex::new(Vec::new()));
+        let unpersisted = Arc::new(Mutex::new(Vec::new()));
+
+        let clock = ManualClock::new();
+        let middleware = {
+            let persisted = persisted.clone();
+            let unpersisted = unpersisted.clone();
+            RetryAfterMiddleware::builder()
+                .with_clock(clock.clone())
+                .with_sleeper(InstantSleeper::advancing(&clock))
+                .on_persist(move |url, time| persisted.lock().unwrap().push((url.clone(), time)))
+                .on_unpersist(move |url| unpersisted.lock().unwrap().push(url.clone()))
+                .build()
+        };
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(middleware)
+            .build();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "5").body("");
+        });
+        server.mock(|when, then| {
+            when.method(GET).path("/normal");
+            then.status(200).body("");
+        });
+
+        let limited = Url::parse(&server.url("/")).unwrap();
+        let normal = Url::parse(&server.url("/normal")).unwrap();
+        let until = clock.now() + Duration::from_secs(5);
+
+        client.get(limited.clone()).send().await.unwrap();
+        client.get(normal.clone()).send().await.unwrap();
+        // nothing is stored anymore, so there is nothing to remove
+        client.get(normal.clone()).send().await.unwrap();
+
+        assert_eq!(*persisted.lock().unwrap(), vec![(limited, until)]);
+        assert_eq!(*unpersisted.lock().unwrap(), vec![normal]);
+    }
+
+    #[tokio::test]
+    async fn on_limited() {
+        let limited = Arc::new(Mutex::new(Vec::new()));
+
+        let middleware = {
+            let limited = limited.clone();
+            RetryAfterMiddleware::builder()
+                .with_sleeper(InstantSleeper::new())
+                .on_limited("127.0.0.1", move |url, _| {
+                    limited.lock().unwrap().push(url.clone())
+                })
+                .on_limited("example.com", |url, _| panic!("{url} is not example.com"))
+                .build()
+        };
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(middleware)
+            .build();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "5").body("");
+        });
+
+        let url = Url::parse(&server.url("/")).unwrap();
+        client.get(url.clone()).send().await.unwrap();
+
+        assert_eq!(*limited.lock().unwrap(), vec![url]);
+    }
+
+    #[tokio::test]
+    async fn on_long_window() {
+        let windows = Arc::new(Mutex::new(Vec::new()));
+
+        let middleware = {
+            let windows = windows.clone();
+            RetryAfterMiddleware::builder()
+                .with_clock(ManualClock::new())
+                .with_sleeper(InstantSleeper::new())
+                .on_long_window(Duration::from_secs(60), move |_, window| {
+                    windows.lock().unwrap().push(window)
+                })
+                .build()
+        };
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(middleware)
+            .build();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/short");
+            then.status(429).header("Retry-After", "5").body("");
+        });
+        server.mock(|when, then| {
+            when.method(GET).path("/long");
+            then.status(429).header("Retry-After", "300").body("");
+        });
+
+        client.get(server.url("/short")).send().await.unwrap();
+        client.get(server.url("/long")).send().await.unwrap();
+
+        assert_eq!(*windows.lock().unwrap(), vec![Duration::from_secs(300)]);
+    }
+
+    #[test]
+    fn conflict_resolution() {
+        let url: Url = "https://api.example/".parse().unwrap();
+        let retry_after = |secs: &str| {
+            let mut headers = http::HeaderMap::new();
+            headers.insert("Retry-After", secs.parse().unwrap());
+            headers
+        };
+
+        for (on_conflict, expected) in [
+            (OnConflict::KeepLatest, 10),
+            (OnConflict::KeepLongest, 60),
+            (OnConflict::KeepShortest, 10),
+        ] {
+            let clock = ManualClock::new();
+            let middleware = RetryAfterMiddleware::builder()
+                .with_clock(clock.clone())
+                .with_conflict_resolution(on_conflict)
+                .build();
+            let tracker = middleware.tracker();
+            let key = tracker.key(&url);
+            for secs in ["60", "10"] {
+                tracker
+                    .observe(
+                        &key,
+                        StatusCode::TOO_MANY_REQUESTS,
+                        &retry_after(secs),
+                        clock.now(),
+                    )
+                    .unwrap();
+            }
+            assert_eq!(
+                middleware.wait_remaining(&url),
+                Some(Duration::from_secs(expected))
+            );
+
+            // passed values give way to any new one
+            clock.advance(Duration::from_secs(expected));
+            tracker
+                .observe(
+                    &key,
+                    StatusCode::TOO_MANY_REQUESTS,
+                    &retry_after("30"),
+                    clock.now(),
+                )
+                .unwrap();
+            assert_eq!(
+                middleware.wait_remaining(&url),
+                Some(Duration::from_secs(30))
+            );
+        }
+    }
+
+    #[test]
+    fn multiple_fields() {
+        let url: Url = "https://api.example/".parse().unwrap();
+        let mut headers = http::HeaderMap::new();
+        for val in ["10", "not a delay", "60", "30"] {
+            headers.append("Retry-After", val.parse().unwrap());
+        }
+
+        for (on_multiple_fields, expected) in [
+            (OnMultipleFields::First, 10),
+            (OnMultipleFields::Longest, 60),
+            (OnMultipleFields::Shortest, 10),
+        ] {
+            let clock = ManualClock::new();
+            let middleware = RetryAfterMiddleware::builder()
+                .with_clock(clock.clone())
+                .with_multiple_fields(on_multiple_fields)
+                .build();
+            let tracker = middleware.tracker();
+            tracker
+                .observe(
+                    &tracker.key(&url),
+                    StatusCode::TOO_MANY_REQUESTS,
+                    &headers,
+                    clock.now(),
+                )
+                .unwrap();
+            assert_eq!(
+                middleware.wait_remaining(&url),
+                Some(Duration::from_secs(expected))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn strict_validation() {
+        let violations = Arc::new(Mutex::new(Vec::new()));
+        let middleware = Arc::new(
+            RetryAfterMiddleware::builder()
+                .with_strict_validation(OnViolation::Ignore)
+                .on_violation({
+                    let violations = violations.clone();
+                    move |_, violation| violations.lock().unwrap().push(violation.kind)
+                })
+                .build(),
+        );
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with_arc(middleware.clone())
+            .build();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "-5").body("");
+        });
+
+        client.get(server.url("/")).send().await.unwrap();
+        assert_eq!(*violations.lock().unwrap(), vec![ViolationKind::Negative]);
+        assert_eq!(middleware.tracker.state.until(""), None);
+
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(
+                RetryAfterMiddleware::builder()
+                    .with_strict_validation(OnViolation::Error)
+                    .build(),
+            )
+            .build();
+        assert!(client.get(server.url("/")).send().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn clamped_negative() {
+        let limited = Arc::new(Mutex::new(0));
+        let store = Arc::new(MemoryStore::new());
+        let middleware = Arc::new(
+            RetryAfterMiddleware::builder()
+                .with_strict_validation(OnViolation::Clamp)
+                .with_store(store.clone())
+                .on_limited("127.0.0.1", {
+                    let limited = limited.clone();
+                    move |_, _| *limited.lock().unwrap() += 1
+                })
+                .build(),
+        );
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with_arc(middleware.clone())
+            .build();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "-5").body("");
+        });
+
+        client.get(server.url("/")).send().await.unwrap();
+        assert_eq!(*limited.lock().unwrap(), 0);
+        assert_eq!(store.get("").await, None);
+        assert_eq!(middleware.tracker.state.until(""), None);
+        assert!(middleware.stats().is_empty());
+    }
+
+    #[tokio::test]
+    async fn max_ttl() {
+        let clock = ManualClock::new();
+        let middleware = Arc::new(
+            RetryAfterMiddleware::builder()
+                .with_clock(clock.clone())
+                .with_max_ttl(Duration::from_secs(60))
+                .build(),
+        );
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with_arc(middleware.clone())
+            .build();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(503).header("Retry-After", "86400").body("");
+        });
+
+        client.get(server.url("/")).send().await.unwrap();
+        assert_eq!(middleware.poll_remaining(), Some(Duration::from_secs(60)));
+    }
+
+    #[tokio::test]
+    async fn stats() {
+        let middleware = Arc::new(
+            RetryAfterMiddleware::builder()
+                .with_clock(ManualClock::new())
+                .with_sleeper(InstantSleeper::new())
+                .build(),
+        );
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with_arc(middleware.clone())
+            .build();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "10").body("");
+        });
+
+        client.get(server.url("/")).send().await.unwrap();
+        client.get(server.url("/")).send().await.unwrap();
+
+        let stats = middleware.stats();
+        let stats = &stats["127.0.0.1"];
+        let (bound, count) = stats
+            .delays
+            .buckets()
+            .find(|(_, count)| *count > 0)
+            .unwrap();
+        assert_eq!((bound, count), (Some(Duration::from_secs(15)), 2));
+        assert_eq!(stats.times_limited, 2);
+        assert_eq!(stats.total_waited, Duration::from_secs(10));
+        assert_eq!(stats.longest_window, Duration::from_secs(10));
+    }
+
+    #[tokio::test]
+    async fn named() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "10").body("");
+        });
+
+        let first = ClientBuilder::new(reqwest::Client::new())
+            .with(RetryAfterMiddleware::named("test::named"))
+            .build();
+        first.get(server.url("/")).send().await.unwrap();
+
+        let shared = RetryAfterMiddleware::named("test::named");
+        let other = RetryAfterMiddleware::named("test::named::other");
+        assert!(shared.poll_remaining().is_some());
+        assert!(other.poll_remaining().is_none());
+    }
+
+    #[tokio::test]
+    async fn shared_state() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "10").body("");
+        });
+
+        let state = RetryAfterState::new();
+        let first = ClientBuilder::new(reqwest::Client::new())
+            .with(RetryAfterMiddleware::with_state(state.clone()))
+            .build();
+        first.get(server.url("/")).send().await.unwrap();
+
+        let shared = RetryAfterMiddleware::builder()
+            .with_state(state)
+            .with_scope(Scope::Global)
+            .build();
+        assert!(shared.poll_remaining().is_some());
+        let again = RetryAfterMiddleware::with_state(shared.state());
+        assert!(again.poll_remaining().is_some());
+        assert!(RetryAfterMiddleware::new().poll_remaining().is_none());
+    }
+
+    #[cfg(feature = "global")]
+    #[test]
+    fn global() {
+        let url: Url = "https://example.com/".parse().unwrap();
+        RetryAfterMiddleware::global().insert(&url, SystemTime::now() + Duration::from_secs(10));
+        assert!(RetryAfterMiddleware::global().is_throttled(&url));
+        assert!(!RetryAfterMiddleware::new().is_throttled(&url));
+    }
+
+    #[tokio::test]
+    async fn clone() {
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "10").body("");
+        });
+
+        let middleware = RetryAfterMiddleware::builder()
+            .with_clock(ManualClock::new())
+            .build();
+        let handle = middleware.clone();
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(middleware)
+            .build();
+        client.get(server.url("/")).send().await.unwrap();
+
+        assert_eq!(handle.poll_remaining(), Some(Duration::from_secs(10)));
+        handle.clear();
+        assert_eq!(handle.clone().poll_remaining(), None);
+    }
+
+    #[tokio::test]
+    async fn recent_events() {
+        let clock = ManualClock::new();
+        let middleware = Arc::new(
+            RetryAfterMiddleware::builder()
+                .with_clock(clock.clone())
+                .with_sleeper(InstantSleeper::advancing(&clock))
+                .with_event_history(2)
+                .build(),
+        );
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with_arc(middleware.clone())
+            .build();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/limit");
+            then.status(429).header("Retry-After", "10").body("");
+        });
+        server.mock(|when, then| {
+            when.method(GET).path("/ok");
+            then.status(200).body("");
+        });
+
+        client.get(server.url("/limit")).send().await.unwrap();
+        assert_eq!(middleware.recent_events().len(), 1);
+        client.get(server.url("/ok")).send().await.unwrap();
+
+        // the oldest event, storing the value, fell out of the history
+        let kinds: Vec<_> = middleware
+            .recent_events()
+            .into_iter()
+            .map(|event| event.kind)
+            .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                EventKind::Waited(Duration::from_secs(10)),
+                EventKind::Cleared
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn dry_run() {
+        let clock = ManualClock::new();
+        let sleeper = InstantSleeper::new();
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(
+                RetryAfterMiddleware::builder()
+                    .with_clock(clock.clone())
+                    .with_sleeper(sleeper.clone())
+                    .dry_run(true)
+                    .build(),
+            )
+            .build();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "60").body("");
+        });
+
+        let res = client.get(server.url("/")).send().await.unwrap();
+        assert!(res.extensions().get::<DryRunOutcome>().is_none());
+
+        clock.advance(Duration::from_secs(15));
+        let res = client.get(server.url("/")).send().await.unwrap();
+        assert_eq!(
+            res.extensions().get::<DryRunOutcome>(),
+            Some(&DryRunOutcome::Wait(Duration::from_secs(45)))
+        );
+        assert!(sleeper.sleeps().is_empty());
+    }
+
+    #[tokio::test]
+    async fn config_handle() {
+        let clock = ManualClock::new();
+        let sleeper = InstantSleeper::new();
+        let middleware = RetryAfterMiddleware::builder()
+            .with_clock(clock.clone())
+            .with_sleeper(sleeper.clone())
+            .dry_run(true)
+            .build();
+        let config = middleware.config();
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(middleware)
+            .build();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(429).header("Retry-After", "60").body("");
+        });
+        client.get(server.url("/")).send().await.unwrap();
+        client.get(server.url("/")).send().await.unwrap();
+        assert!(sleeper.sleeps().is_empty());
+
+        config.set_dry_run(false);
+        config.set_max_wait(Duration::from_secs(10), OnExcessiveDelay::Truncate);
+        client.get(server.url("/")).send().await.unwrap();
+        assert_eq!(sleeper.sleeps(), vec![Duration::from_secs(10)]);
+
+        config.set_max_wait(Duration::from_secs(10), OnExcessiveDelay::Error);
+        assert!(client.get(server.url("/")).send().await.is_err());
+        config.clear_max_wait();
+        client.get(server.url("/")).send().await.unwrap();
+        assert_eq!(
+            sleeper.sleeps(),
+            vec![Duration::from_secs(10), Duration::from_secs(60)]
+        );
+        assert_eq!(config.max_wait(), None);
+        assert!(!config.is_dry_run());
+    }
+
+    #[tokio::test]
+    async fn rate_limit_policies() {
+        let middleware = Arc::new(RetryAfterMiddleware::new());
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with_arc(middleware.clone())
+            .build();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(200)
+                .header("RateLimit-Policy", "100;w=60")
+                .body("");
+        });
+        server.mock(|when, then| {
+            when.method(GET).path("/normal");
+            then.status(200).body("");
+        });
+
+        let url = Url::parse(&server.url("/")).unwrap();
+        assert!(middleware.rate_limit_policies(&url).is_empty());
+
+        let expected = vec![RateLimitPolicy {
+            name: None,
+            quota: 100,
+            window: Some(Duration::from_secs(60)),
+        }];
+        client.get(server.url("/")).send().await.unwrap();
+        assert_eq!(middleware.rate_limit_policies(&url), expected);
+
+        // responses without the header keep the advertised policy
+        client.get(server.url("/normal")).send().await.unwrap();
+        assert_eq!(middleware.rate_limit_policies(&url), expected);
+    }
+
+    #[tokio::test]
+    async fn rate_limit_policies_per_key() {
+        let middleware = Arc::new(
+            RetryAfterMiddleware::builder()
+                .with_scope(Scope::Origin)
+                .build(),
+        );
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with_arc(middleware.clone())
+            .build();
+
+        let servers = [("100;w=60", 100, 60), ("10;w=1", 10, 1)].map(|(policy, quota, window)| {
+            let server = MockServer::start();
+            server.mock(|when, then| {
+                when.method(GET).path("/");
+                then.status(200).header("RateLimit-Policy", policy).body("");
+            });
+            let expected = vec![RateLimitPolicy {
+                name: None,
+                quota,
+                window: Some(Duration::from_secs(window)),
+            }];
+            (server, expected)
+        });
+        for (server, _) in &servers {
+            client.get(server.url("/")).send().await.unwrap();
+        }
+        for (server, expected) in &servers {
+            let url = Url::parse(&server.url("/")).unwrap();
+            assert_eq!(&middleware.rate_limit_policies(&url), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn body_detector() {
+        let clock = ManualClock::new();
+        let middleware = Arc::new(
+            RetryAfterMiddleware::builder()
+                .with_clock(clock.clone())
+                .with_detector(AwsThrottling::new(Duration::from_secs(3)).with_max_body_len(32))
+                .build(),
+        );
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with_arc(middleware.clone())
+            .build();
+
+        let body = r#"{"__type":"ThrottlingException","message":"Rate exceeded"}"#;
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(400).body(body);
+        });
+
+        let url = Url::parse(&server.url("/")).unwrap();
+        let res = client.get(url.clone()).send().await.unwrap();
+
+        assert_eq!(res.url(), &url);
+        assert_eq!(res.text().await.unwrap(), body);
+        assert_eq!(
+            middleware.tracker.state.until(""),
+            Some(clock.now() + Duration::from_secs(3))
+        );
+    }
+
+    #[tokio::test]
+    async fn throttle_status() {
+        let clock = ManualClock::new();
+        let middleware = Arc::new(
+            RetryAfterMiddleware::builder()
+                .with_clock(clock.clone())
+                .with_detector(AwsThrottling::new(Duration::from_secs(3)))
+                .with_throttle_status(StatusCode::from_u16(420).unwrap())
+                .build(),
+        );
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with_arc(middleware.clone())
+            .build();
+
+        let server = MockServer::start();
+        server.mock(|when, then| {
+            when.method(GET).path("/");
+            then.status(420).body(r#"{"__type":"Throttling"}"#);
+        });
+
+        client.get(server.url("/")).send().await.unwrap();
+        assert_eq!(middleware.poll_remaining(), Some(Duration::from_secs(3)));
     }
 
     async fn test_valid_retry_after(
@@ -158,7 +4641,7 @@ mod test {
         now: SystemTime,
         ra_dur: u32,
     ) {
-        let time = *middleware.retry_after.read().await;
+        let time = middleware.tracker.state.until("");
         assert!(time.is_some());
         let time = time.unwrap();
         let duration = time.duration_since(now);
@@ -168,10 +4651,10 @@ mod test {
     }
 
     async fn test_some_retry_after(middleware: &Arc<RetryAfterMiddleware>) {
-        assert!(middleware.retry_after.read().await.is_some());
+        assert!(middleware.tracker.state.until("").is_some());
     }
 
     async fn test_empty_retry_after(middleware: &Arc<RetryAfterMiddleware>) {
-        assert!(middleware.retry_after.read().await.is_none());
+        assert!(middleware.tracker.state.until("").is_none());
     }
 }
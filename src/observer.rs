@@ -0,0 +1,72 @@
+//! Hooking into the lifecycle of throttles.
+//!
+//! A [`RetryAfterObserver`] is told whenever a middleware records a throttle, and whenever a
+//! request starts and finishes waiting for one, for alerting and logging without wrapping the
+//! middleware.
+//!
+//! ```
+//! use std::time::{Duration, SystemTime};
+//! use http::StatusCode;
+//! use reqwest_retry_after::{observer::RetryAfterObserver, RetryAfterMiddleware};
+//!
+//! struct MaintenanceAlert;
+//!
+//! #[async_trait::async_trait]
+//! impl RetryAfterObserver for MaintenanceAlert {
+//!     async fn on_throttle_recorded(&self, key: &str, until: SystemTime, status: StatusCode) {
+//!         if status == StatusCode::SERVICE_UNAVAILABLE {
+//!             eprintln!("{key:?} is down for maintenance until {until:?}");
+//!         }
+//!     }
+//! }
+//!
+//! let middleware = RetryAfterMiddleware::builder()
+//!     .with_observer(MaintenanceAlert)
+//!     .build();
+//! ```
+
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use http::StatusCode;
+
+/// Receives the lifecycle events of throttles.
+///
+/// Every method does nothing by default, so implementations only override the events they are
+/// interested in. The middleware awaits every call before going on, so they should be quick.
+#[async_trait::async_trait]
+pub trait RetryAfterObserver: Send + Sync {
+    /// Called when a response with `status` made requests with `key` wait until `until`.
+    async fn on_throttle_recorded(&self, key: &str, until: SystemTime, status: StatusCode) {
+        let _ = (key, until, status);
+    }
+
+    /// Called when a request with `key` starts waiting for up to `duration`.
+    async fn on_wait_start(&self, key: &str, duration: Duration) {
+        let _ = (key, duration);
+    }
+
+    /// Called when a request with `key` is done waiting.
+    ///
+    /// It isn't called for a request that is dropped while waiting.
+    async fn on_wait_end(&self, key: &str) {
+        let _ = key;
+    }
+}
+
+#[async_trait::async_trait]
+impl<T: RetryAfterObserver + ?Sized> RetryAfterObserver for Arc<T> {
+    async fn on_throttle_recorded(&self, key: &str, until: SystemTime, status: StatusCode) {
+        (**self).on_throttle_recorded(key, until, status).await
+    }
+
+    async fn on_wait_start(&self, key: &str, duration: Duration) {
+        (**self).on_wait_start(key, duration).await
+    }
+
+    async fn on_wait_end(&self, key: &str) {
+        (**self).on_wait_end(key).await
+    }
+}
@@ -0,0 +1,321 @@
+use std::time::{Duration, SystemTime};
+
+use http::{header::RETRY_AFTER, HeaderMap, HeaderName};
+#[cfg(feature = "http-date")]
+use time::{format_description::well_known::Rfc2822, Date, Month, OffsetDateTime};
+
+use crate::{DeprecationNotice, ViolationKind};
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+pub(crate) fn parse_retry_value(val: &str, now: SystemTime) -> Option<SystemTime> {
+    if let Some(delay) = parse_delay(val) {
+        return Some(now + delay);
+    }
+    parse_http_date(val, now)
+}
+
+/// Parses the `Retry-After-Ms` header, or Azure's `x-ms-retry-after-ms`, which give the delay
+/// in milliseconds.
+pub(crate) fn parse_retry_after_ms(headers: &HeaderMap, now: SystemTime) -> Option<SystemTime> {
+    static RETRY_AFTER_MS: HeaderName = HeaderName::from_static("retry-after-ms");
+    static X_MS_RETRY_AFTER_MS: HeaderName = HeaderName::from_static("x-ms-retry-after-ms");
+
+    [&RETRY_AFTER_MS, &X_MS_RETRY_AFTER_MS]
+        .into_iter()
+        .find_map(|name| headers.get(name)?.to_str().ok())
+        .and_then(|val| parse_delay(val.trim()))
+        .map(|millis| now + millis / 1000)
+}
+
+/// Parses a whole or fractional number of seconds, like `120` or `0.25`.
+pub(crate) fn parse_delay(val: &str) -> Option<Duration> {
+    if let Ok(secs) = val.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    // `f64` would also take signs, exponents and `inf`
+    let (secs, fraction) = val.split_once('.')?;
+    let is_digits = |part: &str| part.bytes().all(|b| b.is_ascii_digit());
+    if secs.is_empty() || fraction.is_empty() || !is_digits(secs) || !is_digits(fraction) {
+        return None;
+    }
+    Duration::try_from_secs_f64(val.parse().ok()?).ok()
+}
+
+/// Parses an HTTP date received at `now`.
+#[cfg(feature = "http-date")]
+pub(crate) fn parse_http_date(val: &str, now: SystemTime) -> Option<SystemTime> {
+    OffsetDateTime::parse(val, &Rfc2822)
+        .ok()
+        .map(Into::into)
+        .or_else(|| parse_obsolete_date(val, now))
+}
+
+/// Parses the obsolete RFC 850 and asctime formats, which RFC 9110 still requires recipients
+/// to accept.
+#[cfg(feature = "http-date")]
+fn parse_obsolete_date(val: &str, now: SystemTime) -> Option<SystemTime> {
+    let parts: Vec<_> = val.split_ascii_whitespace().collect();
+    let (day, month, year, time) = match parts[..] {
+        // `Sunday, 06-Nov-94 08:49:37 GMT`
+        [weekday, date, time, "GMT"] if weekday.ends_with(',') => {
+            let mut date = date.split('-');
+            let (day, month, year) = (date.next()?, date.next()?, date.next()?);
+            let year = match year.parse::<i32>().ok()? {
+                // RFC 9110, section 5.6.7: a two-digit year more than 50 years in the future
+                // is in the past century
+                year @ 0..=99 => {
+                    let current = OffsetDateTime::from(now).year();
+                    let year = current - current.rem_euclid(100) + year;
+                    if year > current + 50 {
+                        year - 100
+                    } else {
+                        year
+                    }
+                }
+                year => year,
+            };
+            (day, month, year, time)
+        }
+        // `Sun Nov  6 08:49:37 1994`
+        [_, month, day, time, year] => (day, month, year.parse().ok()?, time),
+        _ => return None,
+    };
+
+    let month = MONTHS.iter().position(|name| *name == month)?;
+    let month = Month::try_from(u8::try_from(month).ok()? + 1).ok()?;
+    let mut time = time.split(':').map(|part| part.parse().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+    if time.next().is_some() {
+        return None;
+    }
+
+    let date = Date::from_calendar_date(year, month, day.parse().ok()?).ok()?;
+    let date = date.with_hms(hour, minute, second).ok()?.assume_utc();
+    Some(date.into())
+}
+
+#[cfg(not(feature = "http-date"))]
+pub(crate) fn parse_http_date(_: &str, _: SystemTime) -> Option<SystemTime> {
+    None
+}
+
+/// Checks the `Retry-After` fields of `headers` against the grammar of RFC 9110, section
+/// 10.2.3, for a response received at `now`.
+pub(crate) fn validate_retry_after(headers: &HeaderMap, now: SystemTime) -> Option<ViolationKind> {
+    let mut values = headers.get_all(RETRY_AFTER).iter();
+    let val = values.next()?;
+    if values.next().is_some() {
+        return Some(ViolationKind::MultipleFields);
+    }
+
+    let Ok(val) = val.to_str() else {
+        return Some(ViolationKind::Malformed);
+    };
+    let is_digits = |val: &str| !val.is_empty() && val.bytes().all(|b| b.is_ascii_digit());
+    if is_digits(val) || is_imf_fixdate(val) {
+        None
+    } else if val.strip_prefix('-').is_some_and(is_digits) {
+        Some(ViolationKind::Negative)
+    } else if parse_http_date(val, now).is_some() {
+        Some(ViolationKind::ObsoleteDate)
+    } else {
+        Some(ViolationKind::Malformed)
+    }
+}
+
+/// Whether `val` has the shape of an IMF-fixdate, like `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn is_imf_fixdate(val: &str) -> bool {
+    const DAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+    let digits = |from: usize, len: usize| {
+        val.get(from..from + len)
+            .is_some_and(|part| part.bytes().all(|b| b.is_ascii_digit()))
+    };
+    let literal = |from: usize, lit: &str| val.get(from..from + lit.len()) == Some(lit);
+
+    val.len() == 29
+        && DAYS.iter().any(|day| literal(0, day))
+        && literal(3, ", ")
+        && digits(5, 2)
+        && literal(7, " ")
+        && MONTHS.iter().any(|month| literal(8, month))
+        && literal(11, " ")
+        && digits(12, 4)
+        && literal(16, " ")
+        && digits(17, 2)
+        && literal(19, ":")
+        && digits(20, 2)
+        && literal(22, ":")
+        && digits(23, 2)
+        && literal(25, " GMT")
+}
+
+pub(crate) fn parse_deprecation(headers: &HeaderMap, now: SystemTime) -> Option<DeprecationNotice> {
+    static DEPRECATION: HeaderName = HeaderName::from_static("deprecation");
+    static SUNSET: HeaderName = HeaderName::from_static("sunset");
+
+    let deprecation = headers
+        .get(&DEPRECATION)
+        .map(|val| val.to_str().unwrap_or_default());
+    let sunset = headers
+        .get(&SUNSET)
+        .and_then(|val| val.to_str().ok())
+        .and_then(|val| parse_http_date(val, now));
+
+    if deprecation.is_none() && sunset.is_none() {
+        return None;
+    }
+
+    Some(DeprecationNotice {
+        deprecated: deprecation.is_some(),
+        deprecated_at: deprecation.and_then(|val| match val.strip_prefix('@') {
+            // RFC 9745 structured date
+            Some(secs) => secs
+                .parse::<u64>()
+                .ok()
+                .map(|secs| SystemTime::UNIX_EPOCH + Duration::from_secs(secs)),
+            // earlier drafts used an HTTP-date, or just `true`
+            None => parse_http_date(val, now),
+        }),
+        sunset,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use http::HeaderMap;
+
+    use super::{parse_deprecation, parse_retry_after_ms, parse_retry_value, validate_retry_after};
+    use crate::{DeprecationNotice, ViolationKind};
+
+    #[test]
+    fn parse_retry_value_date() {
+        let now = SystemTime::UNIX_EPOCH;
+        let parsed = parse_retry_value("Thu, 01 Jan 1970 00:01:00 GMT", now);
+
+        if cfg!(feature = "http-date") {
+            assert_eq!(parsed, Some(now + Duration::from_secs(60)));
+        } else {
+            assert_eq!(parsed, None);
+        }
+    }
+
+    #[test]
+    fn parse_fractional_delays() {
+        let now = SystemTime::UNIX_EPOCH;
+        let parsed = |val| parse_retry_value(val, now);
+        assert_eq!(parsed("1.5"), Some(now + Duration::from_millis(1500)));
+        assert_eq!(parsed("0.25"), Some(now + Duration::from_millis(250)));
+        assert_eq!(parsed("1e3"), None);
+        assert_eq!(parsed(".5"), None);
+        assert_eq!(parsed("-1.5"), None);
+
+        let mut headers = HeaderMap::new();
+        assert_eq!(parse_retry_after_ms(&headers, now), None);
+        headers.insert("x-ms-retry-after-ms", "250".parse().unwrap());
+        assert_eq!(
+            parse_retry_after_ms(&headers, now),
+            Some(now + Duration::from_millis(250))
+        );
+        headers.insert("retry-after-ms", "12.5".parse().unwrap());
+        assert_eq!(
+            parse_retry_after_ms(&headers, now),
+            Some(now + Duration::from_micros(12_500))
+        );
+    }
+
+    #[test]
+    fn parse_obsolete_dates() {
+        let expected = cfg!(feature = "http-date")
+            .then(|| SystemTime::UNIX_EPOCH + Duration::from_secs(784_111_777));
+        let now = SystemTime::UNIX_EPOCH;
+        assert_eq!(
+            parse_retry_value("Sun, 06 Nov 1994 08:49:37 GMT", now),
+            expected
+        );
+        assert_eq!(
+            parse_retry_value("Sunday, 06-Nov-94 08:49:37 GMT", now),
+            expected
+        );
+        assert_eq!(parse_retry_value("Sun Nov  6 08:49:37 1994", now), expected);
+        assert_eq!(parse_retry_value("Sun Nov  6 08:49 1994", now), None);
+        assert_eq!(
+            parse_retry_value("Sunday, 06-Nov-94 08:49:37 PST", now),
+            None
+        );
+    }
+
+    #[cfg(feature = "http-date")]
+    #[test]
+    fn parse_two_digit_years() {
+        // 2026-01-01
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_767_225_600);
+        let year = |val| {
+            let parsed = parse_retry_value(val, now).unwrap();
+            time::OffsetDateTime::from(parsed).year()
+        };
+        assert_eq!(year("Friday, 01-Jan-76 00:00:00 GMT"), 2076);
+        assert_eq!(year("Friday, 01-Jan-77 00:00:00 GMT"), 1977);
+        assert_eq!(year("Thursday, 01-Jan-70 00:00:00 GMT"), 2070);
+        assert_eq!(year("Thursday, 01-Jan-26 00:00:00 GMT"), 2026);
+    }
+
+    #[test]
+    fn parse_deprecation_headers() {
+        let mut headers = HeaderMap::new();
+        assert_eq!(parse_deprecation(&headers, SystemTime::UNIX_EPOCH), None);
+
+        headers.insert("deprecation", "@86400".parse().unwrap());
+        assert_eq!(
+            parse_deprecation(&headers, SystemTime::UNIX_EPOCH),
+            Some(DeprecationNotice {
+                deprecated: true,
+                deprecated_at: Some(SystemTime::UNIX_EPOCH + Duration::from_secs(86400)),
+                sunset: None,
+            })
+        );
+
+        headers.insert("deprecation", "true".parse().unwrap());
+        headers.insert("sunset", "Fri, 02 Jan 1970 00:00:00 GMT".parse().unwrap());
+        assert_eq!(
+            parse_deprecation(&headers, SystemTime::UNIX_EPOCH),
+            Some(DeprecationNotice {
+                deprecated: true,
+                deprecated_at: None,
+                sunset: cfg!(feature = "http-date")
+                    .then(|| SystemTime::UNIX_EPOCH + Duration::from_secs(86400)),
+            })
+        );
+    }
+
+    #[test]
+    fn validate_strictly() {
+        let validate = |vals: &[&str]| {
+            let mut headers = HeaderMap::new();
+            for val in vals {
+                headers.append("retry-after", val.parse().unwrap());
+            }
+            validate_retry_after(&headers, SystemTime::UNIX_EPOCH)
+        };
+
+        assert_eq!(validate(&[]), None);
+        assert_eq!(validate(&["120"]), None);
+        assert_eq!(validate(&["Sun, 06 Nov 1994 08:49:37 GMT"]), None);
+        assert_eq!(validate(&["1", "2"]), Some(ViolationKind::MultipleFields));
+        assert_eq!(validate(&["-5"]), Some(ViolationKind::Negative));
+        assert_eq!(validate(&["1.5"]), Some(ViolationKind::Malformed));
+        assert_eq!(
+            validate(&["Sun, 06 Nov 1994 08:49:37 +0000"]),
+            Some(if cfg!(feature = "http-date") {
+                ViolationKind::ObsoleteDate
+            } else {
+                ViolationKind::Malformed
+            })
+        );
+    }
+}
@@ -0,0 +1,315 @@
+//! Typed views of the IETF `RateLimit` header fields, and of the other rate limit headers
+//! servers commonly send.
+
+use std::time::{Duration, SystemTime};
+
+use http::{header::RETRY_AFTER, HeaderMap, HeaderName};
+
+use crate::parse::{parse_delay, parse_retry_value};
+
+static RATELIMIT_POLICY: HeaderName = HeaderName::from_static("ratelimit-policy");
+static RATELIMIT: HeaderName = HeaderName::from_static("ratelimit");
+static RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("ratelimit-limit");
+static RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("ratelimit-remaining");
+static RATELIMIT_RESET: HeaderName = HeaderName::from_static("ratelimit-reset");
+static X_RATELIMIT_LIMIT: HeaderName = HeaderName::from_static("x-ratelimit-limit");
+static X_RATELIMIT_REMAINING: HeaderName = HeaderName::from_static("x-ratelimit-remaining");
+static X_RATELIMIT_RESET: HeaderName = HeaderName::from_static("x-ratelimit-reset");
+static X_RATELIMIT_RESET_AFTER: HeaderName = HeaderName::from_static("x-ratelimit-reset-after");
+
+/// Reset values at least this large are Unix timestamps rather than delays.
+const EPOCH_THRESHOLD: u64 = 1_000_000_000;
+
+/// A quota advertised by a server through the `RateLimit-Policy` header.
+///
+/// Both the early draft syntax (`100;w=60`) and the later structured syntax
+/// (`"default";q=100;w=60`) are understood.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimitPolicy {
+    /// The policy name, if the server gave one.
+    pub name: Option<String>,
+    /// The number of quota units, usually requests, allowed per window.
+    pub quota: u64,
+    /// The length of the window the quota applies to, if the server gave one.
+    pub window: Option<Duration>,
+}
+
+impl RateLimitPolicy {
+    /// Parses every policy from a `RateLimit-Policy` header value.
+    ///
+    /// Items that can't be parsed are skipped.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use reqwest_retry_after::rate_limit::RateLimitPolicy;
+    ///
+    /// let policies = RateLimitPolicy::parse("100;w=60");
+    /// assert_eq!(policies[0].quota, 100);
+    /// assert_eq!(policies[0].window, Some(Duration::from_secs(60)));
+    /// ```
+    pub fn parse(val: &str) -> Vec<Self> {
+        val.split(',').filter_map(Self::parse_item).collect()
+    }
+
+    /// Parses every policy from the `RateLimit-Policy` headers in `headers`.
+    pub fn from_headers(headers: &HeaderMap) -> Vec<Self> {
+        headers
+            .get_all(&RATELIMIT_POLICY)
+            .iter()
+            .filter_map(|val| val.to_str().ok())
+            .flat_map(Self::parse)
+            .collect()
+    }
+
+    fn parse_item(item: &str) -> Option<Self> {
+        let mut parts = item.split(';').map(str::trim);
+        let first = parts.next()?;
+
+        let mut policy = match first.strip_prefix('"') {
+            Some(name) => Self {
+                name: Some(name.strip_suffix('"')?.to_owned()),
+                quota: 0,
+                window: None,
+            },
+            None => Self {
+                name: None,
+                quota: first.parse().ok()?,
+                window: None,
+            },
+        };
+        let mut has_quota = policy.name.is_none();
+
+        for (key, val) in parts.filter_map(|param| param.split_once('=')) {
+            match key.trim() {
+                "q" => {
+                    policy.quota = val.trim().parse().ok()?;
+                    has_quota = true;
+                }
+                "w" => policy.window = Some(Duration::from_secs(val.trim().parse().ok()?)),
+                _ => {}
+            }
+        }
+
+        has_quota.then_some(policy)
+    }
+}
+
+/// The header family a [`RateLimitInfo`] was read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RateLimitSource {
+    /// Only a `Retry-After` header.
+    RetryAfter,
+    /// The IETF `RateLimit` header, or the separate `RateLimit-Limit`, `RateLimit-Remaining`
+    /// and `RateLimit-Reset` headers of earlier drafts.
+    RateLimit,
+    /// The `X-RateLimit-Limit`, `X-RateLimit-Remaining` and `X-RateLimit-Reset` headers, or
+    /// `X-RateLimit-Reset-After` in place of the latter.
+    XRateLimit,
+}
+
+/// Everything a response says about the rate limit it was subject to.
+///
+/// The IETF headers win over the `X-RateLimit-*` ones when a response sends both. Reset values
+/// of at least a billion seconds are read as Unix timestamps, as GitHub and GitLab send them,
+/// and smaller ones as delays.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RateLimitInfo {
+    /// When the `Retry-After` header allows requests again.
+    pub retry_after: Option<SystemTime>,
+    /// The number of quota units allowed in the current window.
+    pub limit: Option<u64>,
+    /// The number of quota units left in the current window.
+    pub remaining: Option<u64>,
+    /// When the current window resets.
+    pub reset: Option<SystemTime>,
+    /// The header family `limit`, `remaining` and `reset` were read from.
+    pub source: RateLimitSource,
+}
+
+impl RateLimitInfo {
+    /// Reads the rate limit headers in `headers`, resolving relative values against `now`.
+    ///
+    /// Returns `None` if there are none. This doesn't depend on the middleware, so it can be
+    /// used on any response.
+    ///
+    /// ```
+    /// use std::time::{Duration, SystemTime};
+    /// use http::HeaderMap;
+    /// use reqwest_retry_after::rate_limit::{RateLimitInfo, RateLimitSource};
+    ///
+    /// let mut headers = HeaderMap::new();
+    /// headers.insert("x-ratelimit-remaining", "0".parse().unwrap());
+    /// headers.insert("x-ratelimit-reset", "30".parse().unwrap());
+    ///
+    /// let now = SystemTime::now();
+    /// let info = RateLimitInfo::from_headers(&headers, now).unwrap();
+    /// assert_eq!(info.remaining, Some(0));
+    /// assert_eq!(info.reset, Some(now + Duration::from_secs(30)));
+    /// assert_eq!(info.source, RateLimitSource::XRateLimit);
+    /// ```
+    pub fn from_headers(headers: &HeaderMap, now: SystemTime) -> Option<Self> {
+        let retry_after =
+            header_str(headers, &RETRY_AFTER).and_then(|val| parse_retry_value(val.trim(), now));
+
+        let mut info = Self {
+            retry_after,
+            limit: None,
+            remaining: None,
+            reset: None,
+            source: RateLimitSource::RetryAfter,
+        };
+        if info.read_ietf(headers, now) {
+            info.source = RateLimitSource::RateLimit;
+        } else if info.read_x_ratelimit(headers, now) {
+            info.source = RateLimitSource::XRateLimit;
+        } else if retry_after.is_none() {
+            return None;
+        }
+        Some(info)
+    }
+
+    fn read_ietf(&mut self, headers: &HeaderMap, now: SystemTime) -> bool {
+        self.limit = header_u64(headers, &RATELIMIT_LIMIT);
+        self.remaining = header_u64(headers, &RATELIMIT_REMAINING);
+        self.reset = header_u64(headers, &RATELIMIT_RESET)
+            .map(|secs| reset_at(Duration::from_secs(secs), now));
+
+        // `limit=100, remaining=50, reset=30` in some drafts, `"default";r=50;t=30` in later ones
+        if let Some(val) = header_str(headers, &RATELIMIT) {
+            let params = val
+                .split([',', ';'])
+                .filter_map(|param| param.split_once('='));
+            for (key, val) in params {
+                let Ok(val) = val.trim().parse::<u64>() else {
+                    continue;
+                };
+                match key.trim() {
+                    "limit" | "q" => self.limit = Some(val),
+                    "remaining" | "r" => self.remaining = Some(val),
+                    "reset" | "t" => self.reset = Some(reset_at(Duration::from_secs(val), now)),
+                    _ => {}
+                }
+            }
+        }
+
+        self.limit.is_some() || self.remaining.is_some() || self.reset.is_some()
+    }
+
+    fn read_x_ratelimit(&mut self, headers: &HeaderMap, now: SystemTime) -> bool {
+        self.limit = header_u64(headers, &X_RATELIMIT_LIMIT);
+        self.remaining = header_u64(headers, &X_RATELIMIT_REMAINING);
+        // Discord sends a fractional delay next to a fractional timestamp, and the delay is
+        // immune to clock skew
+        let reset_after = header_str(headers, &X_RATELIMIT_RESET_AFTER)
+            .and_then(|val| parse_delay(val.trim()))
+            .map(|delay| now + delay);
+        self.reset = reset_after.or_else(|| {
+            let reset = header_str(headers, &X_RATELIMIT_RESET)?;
+            Some(reset_at(parse_delay(reset.trim())?, now))
+        });
+
+        self.limit.is_some() || self.remaining.is_some() || self.reset.is_some()
+    }
+}
+
+/// Resolves a reset value, which GitHub, GitLab and others send as a Unix timestamp, and most
+/// others as a delay.
+fn reset_at(reset: Duration, now: SystemTime) -> SystemTime {
+    match reset.as_secs() {
+        EPOCH_THRESHOLD.. => SystemTime::UNIX_EPOCH + reset,
+        _ => now + reset,
+    }
+}
+
+fn header_str<'a>(headers: &'a HeaderMap, name: &HeaderName) -> Option<&'a str> {
+    headers.get(name).and_then(|val| val.to_str().ok())
+}
+
+fn header_u64(headers: &HeaderMap, name: &HeaderName) -> Option<u64> {
+    header_str(headers, name).and_then(|val| val.trim().parse().ok())
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use http::HeaderMap;
+
+    use super::{RateLimitInfo, RateLimitPolicy, RateLimitSource};
+
+    #[test]
+    fn parse_policies() {
+        assert_eq!(
+            RateLimitPolicy::parse(r#"10, 50;w=60;burst=1000, "daily";q=1000;w=86400, nope"#),
+            vec![
+                RateLimitPolicy {
+                    name: None,
+                    quota: 10,
+                    window: None,
+                },
+                RateLimitPolicy {
+                    name: None,
+                    quota: 50,
+                    window: Some(Duration::from_secs(60)),
+                },
+                RateLimitPolicy {
+                    name: Some("daily".to_owned()),
+                    quota: 1000,
+                    window: Some(Duration::from_secs(86400)),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rate_limit_info() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let mut headers = HeaderMap::new();
+        assert_eq!(RateLimitInfo::from_headers(&headers, now), None);
+
+        headers.insert("retry-after", "5".parse().unwrap());
+        headers.insert("x-ratelimit-limit", "60".parse().unwrap());
+        headers.insert("x-ratelimit-reset", "1700000060".parse().unwrap());
+        assert_eq!(
+            RateLimitInfo::from_headers(&headers, now),
+            Some(RateLimitInfo {
+                retry_after: Some(now + Duration::from_secs(5)),
+                limit: Some(60),
+                remaining: None,
+                reset: Some(now + Duration::from_secs(60)),
+                source: RateLimitSource::XRateLimit,
+            })
+        );
+
+        headers.insert("x-ratelimit-reset-after", "12.5".parse().unwrap());
+        assert_eq!(
+            RateLimitInfo::from_headers(&headers, now).unwrap().reset,
+            Some(now + Duration::from_millis(12_500))
+        );
+        headers.remove("x-ratelimit-reset-after");
+        headers.insert("x-ratelimit-reset", "1700000030.250".parse().unwrap());
+        assert_eq!(
+            RateLimitInfo::from_headers(&headers, now).unwrap().reset,
+            Some(now + Duration::from_millis(30_250))
+        );
+
+        headers.insert("ratelimit-reset", "1700000090".parse().unwrap());
+        assert_eq!(
+            RateLimitInfo::from_headers(&headers, now).unwrap().reset,
+            Some(now + Duration::from_secs(90))
+        );
+
+        headers.insert("ratelimit", r#""default";r=0;t=30"#.parse().unwrap());
+        assert_eq!(
+            RateLimitInfo::from_headers(&headers, now),
+            Some(RateLimitInfo {
+                retry_after: Some(now + Duration::from_secs(5)),
+                limit: None,
+                remaining: Some(0),
+                reset: Some(now + Duration::from_secs(30)),
+                source: RateLimitSource::RateLimit,
+            })
+        );
+    }
+}
@@ -0,0 +1,144 @@
+//! Retry schedules for [`reqwest-retry`](https://docs.rs/reqwest-retry) that respect
+//! `Retry-After`.
+//!
+//! `RetryTransientMiddleware` retries throttled requests on a schedule of its own, which knows
+//! nothing about when the server said to come back. [`RetryAfterPolicy`] wraps the
+//! [`RetryPolicy`] of that schedule and pushes every retry back until the stored
+//! `Retry-After` has passed. Requires the `retry-policies` feature.
+
+use std::time::{Duration, SystemTime};
+
+use reqwest::Url;
+use retry_policies::{RetryDecision, RetryPolicy};
+
+use crate::RetryAfterReader;
+
+/// A [`RetryPolicy`] that retries when another policy says to, but never before the
+/// `Retry-After` stored by a middleware has passed.
+///
+/// The middleware has to come after `RetryTransientMiddleware`, so that it sees every
+/// response and stores its `Retry-After` before the retry is scheduled. As retry policies are
+/// not told which request failed, the policy is given the URL or host whose value to wait
+/// for; with a [`Scope::Global`](crate::Scope::Global) middleware, any URL will do.
+///
+/// ```
+/// use reqwest_middleware::ClientBuilder;
+/// use reqwest_retry::{policies::ExponentialBackoff, RetryTransientMiddleware};
+/// use reqwest_retry_after::{retry::RetryAfterPolicy, RetryAfterMiddleware, Scope};
+///
+/// let middleware = RetryAfterMiddleware::builder().with_scope(Scope::Host).build();
+/// let backoff = ExponentialBackoff::builder().build_with_max_retries(3);
+/// let policy = RetryAfterPolicy::for_host(backoff, middleware.reader(), "api.github.com");
+/// let client = ClientBuilder::new(reqwest::Client::new())
+///     .with(RetryTransientMiddleware::new_with_policy(policy))
+///     .with(middleware)
+///     .build();
+/// ```
+#[derive(Clone)]
+pub struct RetryAfterPolicy<P> {
+    inner: P,
+    reader: RetryAfterReader,
+    target: Target,
+}
+
+#[derive(Clone)]
+enum Target {
+    Url(Url),
+    Host(String),
+}
+
+impl<P: RetryPolicy> RetryAfterPolicy<P> {
+    /// Wraps `inner`, and waits for the value stored for `GET` requests to `url` in the
+    /// middleware `reader` reads from.
+    pub fn for_url(inner: P, reader: RetryAfterReader, url: Url) -> Self {
+        Self {
+            inner,
+            reader,
+            target: Target::Url(url),
+        }
+    }
+
+    /// Wraps `inner`, and waits for the latest value that applies to requests to `host` in
+    /// the middleware `reader` reads from.
+    pub fn for_host(inner: P, reader: RetryAfterReader, host: impl Into<String>) -> Self {
+        Self {
+            inner,
+            reader,
+            target: Target::Host(host.into()),
+        }
+    }
+
+    /// Returns how long the stored value still holds requests back, measured with the
+    /// middleware's clock, which need not tell the same time as the wall clock retry
+    /// policies schedule with.
+    fn remaining(&self) -> Option<Duration> {
+        match &self.target {
+            Target::Url(url) => self.reader.wait_remaining(url),
+            Target::Host(host) => self
+                .reader
+                .throttled_until(host)?
+                .duration_since(self.reader.clock.now())
+                .ok(),
+        }
+    }
+}
+
+impl<P: RetryPolicy> RetryPolicy for RetryAfterPolicy<P> {
+    fn should_retry(&self, request_start_time: SystemTime, n_past_retries: u32) -> RetryDecision {
+        match self.inner.should_retry(request_start_time, n_past_retries) {
+            RetryDecision::Retry { execute_after } => RetryDecision::Retry {
+                execute_after: self.remaining().map_or(execute_after, |remaining| {
+                    execute_after.max(SystemTime::now() + remaining)
+                }),
+            },
+            RetryDecision::DoNotRetry => RetryDecision::DoNotRetry,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use reqwest::Url;
+    use retry_policies::{policies::ExponentialBackoff, RetryDecision, RetryPolicy};
+
+    use super::RetryAfterPolicy;
+    use crate::{clock::Clock, testing::ManualClock, RetryAfterMiddleware, Scope};
+
+    #[test]
+    fn retry_after_policy() {
+        // the middleware's clock is far from the wall clock retry policies go by
+        let clock = ManualClock::starting_at(SystemTime::UNIX_EPOCH);
+        let middleware = RetryAfterMiddleware::builder()
+            .with_clock(clock.clone())
+            .with_scope(Scope::Host)
+            .build();
+        let backoff = ExponentialBackoff::builder()
+            .retry_bounds(Duration::from_secs(1), Duration::from_secs(1))
+            .build_with_max_retries(1);
+        let url: Url = "https://api.example/".parse().unwrap();
+        let by_url = RetryAfterPolicy::for_url(backoff, middleware.reader(), url.clone());
+        let by_host = RetryAfterPolicy::for_host(backoff, middleware.reader(), "api.example");
+        let other = RetryAfterPolicy::for_host(backoff, middleware.reader(), "other.example");
+
+        middleware.insert(&url, clock.now() + Duration::from_secs(60));
+        let start = SystemTime::now();
+        for policy in [&by_url, &by_host] {
+            let RetryDecision::Retry { execute_after } = policy.should_retry(start, 0) else {
+                panic!("no retry");
+            };
+            let wait = execute_after.duration_since(start).unwrap();
+            assert!(wait >= Duration::from_secs(60) && wait < Duration::from_secs(61));
+        }
+        assert!(matches!(
+            other.should_retry(start, 0),
+            RetryDecision::Retry { execute_after }
+                if execute_after < start + Duration::from_secs(2)
+        ));
+        assert!(matches!(
+            by_url.should_retry(start, 1),
+            RetryDecision::DoNotRetry
+        ));
+    }
+}
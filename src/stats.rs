@@ -0,0 +1,304 @@
+//! Statistics about the throttling the middleware has observed.
+//!
+//! With the `prometheus` feature, `RetryAfterMetrics` exposes them as Prometheus metrics.
+
+use std::{
+    fmt,
+    time::{Duration, SystemTime},
+};
+
+use reqwest::Url;
+
+#[cfg(feature = "prometheus")]
+mod prometheus;
+#[cfg(feature = "prometheus")]
+pub use self::prometheus::RetryAfterMetrics;
+
+/// Throttling statistics for a single host.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct HostStats {
+    /// The number of times the host has sent a `Retry-After`.
+    pub times_limited: u64,
+    /// The total time requests to the host spent waiting.
+    pub total_waited: Duration,
+    /// The longest delay the host has demanded.
+    pub longest_window: Duration,
+    /// When the host first sent a `Retry-After`.
+    pub first_limited: Option<SystemTime>,
+    /// When the host last sent a `Retry-After`.
+    pub last_limited: Option<SystemTime>,
+    /// The delays the host has demanded, over the lifetime of the state.
+    pub delays: DelayHistogram,
+    /// An exponentially weighted average of the delays the host has demanded.
+    pub smoothed_window: Option<Duration>,
+    /// An exponentially weighted average of the time between two `Retry-After`s from the host,
+    /// once it has sent at least two.
+    pub smoothed_interval: Option<Duration>,
+}
+
+impl HostStats {
+    /// The weight of the newest sample in the smoothed averages.
+    const SMOOTHING: f64 = 0.2;
+
+    pub(crate) fn limited(&mut self, now: SystemTime, delay: Duration) {
+        if let Some(interval) = self
+            .last_limited
+            .and_then(|last| now.duration_since(last).ok())
+        {
+            self.smoothed_interval = Some(smooth(self.smoothed_interval, interval));
+        }
+        self.smoothed_window = Some(smooth(self.smoothed_window, delay));
+
+        self.times_limited += 1;
+        self.longest_window = self.longest_window.max(delay);
+        self.first_limited.get_or_insert(now);
+        self.last_limited = Some(now);
+        self.delays.record(delay);
+    }
+}
+
+fn smooth(average: Option<Duration>, sample: Duration) -> Duration {
+    match average {
+        Some(average) => {
+            average.mul_f64(1.0 - HostStats::SMOOTHING) + sample.mul_f64(HostStats::SMOOTHING)
+        }
+        None => sample,
+    }
+}
+
+/// A summary of the throttling observed by a middleware, as returned by
+/// [`RetryAfterMiddleware::report`](crate::RetryAfterMiddleware::report).
+///
+/// Its [`Display`](fmt::Display) implementation prints a table with one row per host, suitable
+/// for the end of a batch job:
+///
+/// ```text
+/// host            limited  waited  longest  first     last
+/// api.github.com  3        12s     10s      521s ago  4s ago
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Report {
+    /// When the report was generated.
+    pub generated_at: SystemTime,
+    /// The statistics of every host, sorted by host name.
+    pub hosts: Vec<(String, HostStats)>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ago = |time: Option<SystemTime>| match time {
+            Some(time) => {
+                let elapsed = self.generated_at.duration_since(time).unwrap_or_default();
+                format!("{:?} ago", Duration::from_secs(elapsed.as_secs()))
+            }
+            None => "-".to_owned(),
+        };
+        let rows: Vec<[String; 6]> = self
+            .hosts
+            .iter()
+            .map(|(host, stats)| {
+                [
+                    host.clone(),
+                    stats.times_limited.to_string(),
+                    format!("{:?}", Duration::from_secs(stats.total_waited.as_secs())),
+                    format!("{:?}", Duration::from_secs(stats.longest_window.as_secs())),
+                    ago(stats.first_limited),
+                    ago(stats.last_limited),
+                ]
+            })
+            .collect();
+
+        let header = ["host", "limited", "waited", "longest", "first", "last"].map(String::from);
+        let mut widths = header.clone().map(|cell| cell.len());
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.len());
+            }
+        }
+
+        for row in [header].iter().chain(&rows) {
+            let line: Vec<_> = row
+                .iter()
+                .zip(widths)
+                .map(|(cell, width)| format!("{cell:width$}"))
+                .collect();
+            writeln!(f, "{}", line.join("  ").trim_end())?;
+        }
+        Ok(())
+    }
+}
+
+/// Something the middleware did about throttling, as returned by
+/// [`RetryAfterMiddleware::recent_events`](crate::RetryAfterMiddleware::recent_events).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Event {
+    /// When it happened.
+    pub at: SystemTime,
+    /// The URL of the request or response involved.
+    pub url: Url,
+    /// What happened.
+    pub kind: EventKind,
+}
+
+/// The kind of an [`Event`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum EventKind {
+    /// A `Retry-After` until the given instant was stored.
+    Stored(SystemTime),
+    /// A request waited for the given duration before being sent.
+    Waited(Duration),
+    /// A response cleared the stored `Retry-After`.
+    Cleared,
+    /// Sending a request failed with the given error.
+    Errored(String),
+}
+
+/// A throttle event, sent to the receivers returned by
+/// [`RetryAfterMiddleware::subscribe`](crate::RetryAfterMiddleware::subscribe).
+///
+/// Every event names the key of the value involved, as determined by the middleware's
+/// [`Scope`](crate::Scope).
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RetryAfterEvent {
+    /// A response made requests with `key` wait until `until`.
+    Recorded {
+        /// The key of the value.
+        key: String,
+        /// The instant requests must wait until.
+        until: SystemTime,
+    },
+    /// A request with `key` started waiting for up to `duration`.
+    WaitStarted {
+        /// The key of the value waited for.
+        key: String,
+        /// How long the request means to wait.
+        duration: Duration,
+    },
+    /// A request with `key` finished waiting, after `waited`.
+    WaitFinished {
+        /// The key of the value waited for.
+        key: String,
+        /// How long the request waited.
+        waited: Duration,
+    },
+    /// The value for `key` passed, and was evicted.
+    Expired {
+        /// The key of the value.
+        key: String,
+    },
+    /// A response cleared the value for `key`.
+    Cleared {
+        /// The key of the value.
+        key: String,
+    },
+}
+
+/// A histogram of delays, bucketed by [`DelayHistogram::BOUNDS`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DelayHistogram {
+    counts: [u64; DelayHistogram::BOUNDS.len() + 1],
+}
+
+impl DelayHistogram {
+    /// The inclusive upper bounds of every bucket but the last, which holds everything longer.
+    pub const BOUNDS: [Duration; 8] = [
+        Duration::from_secs(1),
+        Duration::from_secs(5),
+        Duration::from_secs(15),
+        Duration::from_secs(30),
+        Duration::from_secs(60),
+        Duration::from_secs(5 * 60),
+        Duration::from_secs(15 * 60),
+        Duration::from_secs(60 * 60),
+    ];
+
+    /// Counts `delay` in its bucket.
+    pub fn record(&mut self, delay: Duration) {
+        let bucket = Self::BOUNDS.partition_point(|bound| *bound < delay);
+        self.counts[bucket] += 1;
+    }
+
+    /// Returns every bucket's upper bound and count, in ascending order. The last bucket has
+    /// no upper bound.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use reqwest_retry_after::stats::DelayHistogram;
+    ///
+    /// let mut histogram = DelayHistogram::default();
+    /// histogram.record(Duration::from_secs(3));
+    ///
+    /// let (bound, count) = histogram.buckets().find(|(_, count)| *count > 0).unwrap();
+    /// assert_eq!(bound, Some(Duration::from_secs(5)));
+    /// ```
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        Self::BOUNDS
+            .iter()
+            .copied()
+            .map(Some)
+            .chain([None])
+            .zip(self.counts.iter().copied())
+    }
+
+    /// Returns the number of recorded delays.
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use super::{DelayHistogram, HostStats, Report};
+
+    #[test]
+    fn buckets() {
+        let mut histogram = DelayHistogram::default();
+        for secs in [0, 1, 2, 60, 7200] {
+            histogram.record(Duration::from_secs(secs));
+        }
+
+        let counts: Vec<_> = histogram.buckets().map(|(_, count)| count).collect();
+        assert_eq!(counts, [2, 1, 0, 0, 1, 0, 0, 0, 1]);
+        assert_eq!(histogram.count(), 5);
+    }
+
+    #[test]
+    fn smoothing() {
+        let start = SystemTime::UNIX_EPOCH;
+        let mut stats = HostStats::default();
+        stats.limited(start, Duration::from_secs(10));
+        assert_eq!(stats.smoothed_window, Some(Duration::from_secs(10)));
+        assert_eq!(stats.smoothed_interval, None);
+
+        stats.limited(start + Duration::from_secs(100), Duration::from_secs(20));
+        stats.limited(start + Duration::from_secs(150), Duration::from_secs(20));
+        let millis = |average: Option<Duration>| average.unwrap().as_millis();
+        assert_eq!(millis(stats.smoothed_window), 13_600);
+        assert_eq!(millis(stats.smoothed_interval), 90_000);
+    }
+
+    #[test]
+    fn report() {
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        let mut stats = HostStats::default();
+        stats.limited(now - Duration::from_secs(600), Duration::from_secs(10));
+        stats.limited(now - Duration::from_secs(5), Duration::from_secs(2));
+        stats.total_waited = Duration::from_millis(12_500);
+
+        let report = Report {
+            generated_at: now,
+            hosts: vec![("api.github.com".to_owned(), stats)],
+        };
+        assert_eq!(
+            report.to_string(),
+            "host            limited  waited  longest  first     last\n\
+             api.github.com  2        12s     10s      600s ago  5s ago\n"
+        );
+    }
+}
@@ -0,0 +1,140 @@
+use prometheus::{
+    core::{Collector, Desc},
+    proto::MetricFamily,
+    CounterVec, IntCounterVec, IntGauge, Opts,
+};
+
+use crate::RetryAfterReader;
+
+/// Prometheus collectors over the state of a middleware, to register with a
+/// [`prometheus::Registry`].
+///
+/// The metrics are read from the middleware's [statistics](crate::RetryAfterMiddleware::stats)
+/// whenever the registry is gathered:
+///
+/// - `retry_after_throttled_keys`: the number of keys that currently have to wait.
+/// - `retry_after_throttled_total`: the number of `Retry-After`s received, by `host`.
+/// - `retry_after_wait_seconds_total`: the time requests spent waiting, by `host`.
+///
+/// Requires the `prometheus` feature.
+///
+/// ```
+/// use reqwest_retry_after::{stats::RetryAfterMetrics, RetryAfterMiddleware};
+///
+/// let middleware = RetryAfterMiddleware::new();
+/// let registry = prometheus::Registry::new();
+/// registry
+///     .register(Box::new(RetryAfterMetrics::new(middleware.reader())))
+///     .unwrap();
+/// ```
+pub struct RetryAfterMetrics {
+    reader: RetryAfterReader,
+    throttled_keys: IntGauge,
+    throttled: IntCounterVec,
+    waited: CounterVec,
+}
+
+impl RetryAfterMetrics {
+    /// Creates collectors over the state `reader` reads.
+    pub fn new(reader: RetryAfterReader) -> Self {
+        Self {
+            reader,
+            throttled_keys: throttled_keys(),
+            throttled: throttled(),
+            waited: waited(),
+        }
+    }
+}
+
+fn throttled_keys() -> IntGauge {
+    IntGauge::new(
+        "retry_after_throttled_keys",
+        "The number of keys that currently have to wait for a Retry-After.",
+    )
+    .unwrap()
+}
+
+fn throttled() -> IntCounterVec {
+    let opts = Opts::new(
+        "retry_after_throttled_total",
+        "The number of Retry-After values received.",
+    );
+    IntCounterVec::new(opts, &["host"]).unwrap()
+}
+
+fn waited() -> CounterVec {
+    let opts = Opts::new(
+        "retry_after_wait_seconds_total",
+        "The time requests spent waiting for Retry-After values to elapse.",
+    );
+    CounterVec::new(opts, &["host"]).unwrap()
+}
+
+impl Collector for RetryAfterMetrics {
+    fn desc(&self) -> Vec<&Desc> {
+        let mut descs = self.throttled_keys.desc();
+        descs.extend(self.throttled.desc());
+        descs.extend(self.waited.desc());
+        descs
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        // counters only go up, so the values are set on fresh collectors every time
+        let (throttled_keys, throttled, waited) = (throttled_keys(), throttled(), waited());
+
+        let now = self.reader.clock.now();
+        let snapshot = self.reader.snapshot();
+        let active = snapshot.iter().filter(|(_, until)| *until > now).count();
+        throttled_keys.set(i64::try_from(active).unwrap_or(i64::MAX));
+        for (host, stats) in self.reader.stats() {
+            throttled
+                .with_label_values(&[&host])
+                .inc_by(stats.times_limited);
+            waited
+                .with_label_values(&[&host])
+                .inc_by(stats.total_waited.as_secs_f64());
+        }
+
+        let mut families = throttled_keys.collect();
+        families.extend(throttled.collect());
+        families.extend(waited.collect());
+        families
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::{clock::Clock, testing::ManualClock, RetryAfterMiddleware, Scope};
+
+    use super::RetryAfterMetrics;
+
+    #[test]
+    fn prometheus_metrics() {
+        let clock = ManualClock::new();
+        let middleware = RetryAfterMiddleware::builder()
+            .with_clock(clock.clone())
+            .with_scope(Scope::Host)
+            .build();
+        middleware.insert(
+            &"https://a.example/".parse().unwrap(),
+            clock.now() + Duration::from_secs(10),
+        );
+        middleware.insert(
+            &"https://b.example/".parse().unwrap(),
+            clock.now() - Duration::from_secs(10),
+        );
+
+        let registry = prometheus::Registry::new();
+        registry
+            .register(Box::new(RetryAfterMetrics::new(middleware.reader())))
+            .unwrap();
+        let families = registry.gather();
+        let keys = families
+            .iter()
+            .find(|family| family.get_name() == "retry_after_throttled_keys")
+            .unwrap();
+        assert_eq!(keys.get_metric()[0].get_gauge().get_value(), 1.0);
+    }
+}
@@ -0,0 +1,267 @@
+//! Keeping `Retry-After` state outside of the middleware.
+//!
+//! A [`RetryAfterStore`] backs the values a middleware stores, so that they can live in a
+//! shared cache or database. The middleware still keeps its own copy of every value, which
+//! its synchronous queries such as
+//! [`RetryAfterMiddleware::poll_remaining`](crate::RetryAfterMiddleware::poll_remaining) read,
+//! and writes through to the store.
+//!
+//! [`MemoryStore`] keeps its values in memory, like a middleware without a store does. It is
+//! mostly useful as a starting point for other implementations, and in tests. [`MockStore`]
+//! answers with scripted values and records every call, to test how a middleware uses its
+//! store without a database. [`FileStore`]
+//! shares values between processes on the same machine through a locked file. With the
+//! `redis` feature, `RedisStore` shares values through a Redis server, and with the `sled`
+//! feature, `SledStore` keeps them in an embedded database.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+mod file;
+#[cfg(feature = "redis")]
+mod redis;
+#[cfg(feature = "redis")]
+pub use self::redis::RedisStore;
+#[cfg(unix)]
+pub(crate) use file::unblock;
+pub use file::FileStore;
+#[cfg(feature = "sled")]
+mod sled;
+#[cfg(feature = "sled")]
+pub use self::sled::SledStore;
+
+/// Stores `Retry-After` instants by key.
+///
+/// Every instant belongs to the key it was stored under, as determined by the middleware's
+/// [`Scope`](crate::Scope).
+#[async_trait::async_trait]
+pub trait RetryAfterStore: Send + Sync {
+    /// Returns the instant requests with `key` must wait until, if one is stored.
+    async fn get(&self, key: &str) -> Option<SystemTime>;
+
+    /// Stores that requests with `key` must wait until `until`, replacing any stored instant.
+    async fn set(&self, key: &str, until: SystemTime);
+
+    /// Forgets the instant stored for `key`.
+    async fn remove(&self, key: &str);
+
+    /// Forgets every instant up to `now`, which no request has to wait for anymore.
+    async fn purge(&self, now: SystemTime);
+}
+
+#[async_trait::async_trait]
+impl<T: RetryAfterStore + ?Sized> RetryAfterStore for Arc<T> {
+    async fn get(&self, key: &str) -> Option<SystemTime> {
+        (**self).get(key).await
+    }
+
+    async fn set(&self, key: &str, until: SystemTime) {
+        (**self).set(key, until).await
+    }
+
+    async fn remove(&self, key: &str) {
+        (**self).remove(key).await
+    }
+
+    async fn purge(&self, now: SystemTime) {
+        (**self).purge(now).await
+    }
+}
+
+/// A [`RetryAfterStore`] that keeps its values in a map in memory.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    values: Mutex<HashMap<String, SystemTime>>,
+}
+
+impl MemoryStore {
+    /// Creates an empty store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait::async_trait]
+impl RetryAfterStore for MemoryStore {
+    async fn get(&self, key: &str) -> Option<SystemTime> {
+        self.values.lock().unwrap().get(key).copied()
+    }
+
+    async fn set(&self, key: &str, until: SystemTime) {
+        self.values.lock().unwrap().insert(key.to_owned(), until);
+    }
+
+    async fn remove(&self, key: &str) {
+        self.values.lock().unwrap().remove(key);
+    }
+
+    async fn purge(&self, now: SystemTime) {
+        self.values.lock().unwrap().retain(|_, until| *until > now);
+    }
+}
+
+/// A call made to a [`MockStore`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum StoreCall {
+    /// [`RetryAfterStore::get`] was called with this key.
+    Get(String),
+    /// [`RetryAfterStore::set`] was called with this key and instant.
+    Set(String, SystemTime),
+    /// [`RetryAfterStore::remove`] was called with this key.
+    Remove(String),
+    /// [`RetryAfterStore::purge`] was called with this instant.
+    Purge(SystemTime),
+}
+
+/// A [`RetryAfterStore`] for tests, which answers with scripted values and records every call.
+///
+/// Each [`get`](RetryAfterStore::get) of a key returns the next value scripted for it with
+/// [`push_get`](Self::push_get), and once those run out, the value last set for it, like a
+/// [`MemoryStore`]. Sets, removals and purges apply to those values unless
+/// [ignored](Self::ignore_writes), as if another process undid them.
+///
+/// ```
+/// use std::{sync::Arc, time::{Duration, SystemTime}};
+/// use reqwest_retry_after::{
+///     store::{MockStore, StoreCall},
+///     RetryAfterMiddleware,
+/// };
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// // the first request looks its key up in the store and waits 10 seconds
+/// let store = Arc::new(MockStore::new());
+/// store.push_get("", Some(SystemTime::now() + Duration::from_secs(10)));
+/// let middleware = RetryAfterMiddleware::builder().with_store(store.clone()).build();
+/// # drop(middleware);
+///
+/// // ... send requests, then check what the middleware asked of the store
+/// assert!(store.calls().iter().all(|call| matches!(call, StoreCall::Get(_))));
+/// # }
+/// ```
+#[derive(Debug, Default)]
+pub struct MockStore {
+    values: MemoryStore,
+    scripted: Mutex<HashMap<String, VecDeque<Option<SystemTime>>>>,
+    ignore_writes: Mutex<bool>,
+    calls: Mutex<Vec<StoreCall>>,
+}
+
+impl MockStore {
+    /// Creates an empty store, with nothing scripted.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Scripts the next unscripted [`get`](RetryAfterStore::get) of `key` to return `value`.
+    pub fn push_get(&self, key: &str, value: Option<SystemTime>) {
+        let mut scripted = self.scripted.lock().unwrap();
+        scripted.entry(key.to_owned()).or_default().push_back(value);
+    }
+
+    /// Sets whether sets, removals and purges are only recorded, leaving the values alone.
+    pub fn ignore_writes(&self, ignore: bool) {
+        *self.ignore_writes.lock().unwrap() = ignore;
+    }
+
+    /// Returns every call made so far, oldest first.
+    pub fn calls(&self) -> Vec<StoreCall> {
+        self.calls.lock().unwrap().clone()
+    }
+
+    /// Forgets the calls made so far.
+    pub fn clear_calls(&self) {
+        self.calls.lock().unwrap().clear();
+    }
+
+    fn record(&self, call: StoreCall) -> bool {
+        self.calls.lock().unwrap().push(call);
+        !*self.ignore_writes.lock().unwrap()
+    }
+}
+
+#[async_trait::async_trait]
+impl RetryAfterStore for MockStore {
+    async fn get(&self, key: &str) -> Option<SystemTime> {
+        self.record(StoreCall::Get(key.to_owned()));
+        let scripted = self
+            .scripted
+            .lock()
+            .unwrap()
+            .get_mut(key)
+            .and_then(VecDeque::pop_front);
+        match scripted {
+            Some(value) => value,
+            None => self.values.get(key).await,
+        }
+    }
+
+    async fn set(&self, key: &str, until: SystemTime) {
+        if self.record(StoreCall::Set(key.to_owned(), until)) {
+            self.values.set(key, until).await;
+        }
+    }
+
+    async fn remove(&self, key: &str) {
+        if self.record(StoreCall::Remove(key.to_owned())) {
+            self.values.remove(key).await;
+        }
+    }
+
+    async fn purge(&self, now: SystemTime) {
+        if self.record(StoreCall::Purge(now)) {
+            self.values.purge(now).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use super::{MemoryStore, MockStore, RetryAfterStore, StoreCall};
+
+    #[tokio::test]
+    async fn memory_store() {
+        let store = MemoryStore::new();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        store.set("a", now - Duration::from_secs(1)).await;
+        store.set("b", now + Duration::from_secs(1)).await;
+        store.set("c", now + Duration::from_secs(2)).await;
+        assert_eq!(store.get("c").await, Some(now + Duration::from_secs(2)));
+
+        store.remove("c").await;
+        store.purge(now).await;
+        assert_eq!(store.get("a").await, None);
+        assert_eq!(store.get("b").await, Some(now + Duration::from_secs(1)));
+        assert_eq!(store.get("c").await, None);
+    }
+
+    #[tokio::test]
+    async fn mock_store() {
+        let store = MockStore::new();
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        store.push_get("a", None);
+        store.set("a", now).await;
+        assert_eq!(store.get("a").await, None);
+        assert_eq!(store.get("a").await, Some(now));
+
+        store.ignore_writes(true);
+        store.remove("a").await;
+        assert_eq!(store.get("a").await, Some(now));
+        assert_eq!(
+            store.calls(),
+            vec![
+                StoreCall::Set("a".to_owned(), now),
+                StoreCall::Get("a".to_owned()),
+                StoreCall::Get("a".to_owned()),
+                StoreCall::Remove("a".to_owned()),
+                StoreCall::Get("a".to_owned()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,197 @@
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, Write},
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use super::RetryAfterStore;
+
+/// A [`RetryAfterStore`] backed by a file guarded by advisory locks, for processes on the same
+/// machine.
+///
+/// Short-lived invocations of a CLI, say from cron, see and respect each other's
+/// `Retry-After`s. Every operation locks the file, shared for reading and exclusive for
+/// writing, so concurrent processes never see a partial update. The file holds one
+/// `<milliseconds since the Unix epoch> <key>` line per value. Errors are ignored, so that an
+/// unreadable file only costs the sharing.
+///
+/// Taking a lock blocks until the process holding it lets go, so the file is read and written
+/// on tokio's blocking thread pool, where waiting stalls no runtime. Outside of a tokio
+/// runtime, and on `wasm32` targets, it is read and written in place, blocking the task.
+///
+/// ```no_run
+/// use reqwest_retry_after::{store::FileStore, RetryAfterMiddleware};
+///
+/// let store = FileStore::new("/tmp/my-app-retry-after");
+/// let middleware = RetryAfterMiddleware::builder().with_store(store).build();
+/// ```
+#[derive(Debug, Clone)]
+pub struct FileStore {
+    path: PathBuf,
+}
+
+impl FileStore {
+    /// Creates a store in the file at `path`, which is created when first written to.
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Reads every value, on the blocking thread pool.
+    async fn read_values(&self) -> Option<HashMap<String, SystemTime>> {
+        let store = self.clone();
+        unblock(move || store.read().ok()).await.flatten()
+    }
+
+    /// Updates the values with `f`, on the blocking thread pool.
+    async fn update_values(
+        &self,
+        f: impl FnOnce(&mut HashMap<String, SystemTime>) + Send + 'static,
+    ) {
+        let store = self.clone();
+        let _ = unblock(move || store.update(f)).await;
+    }
+
+    fn read(&self) -> io::Result<HashMap<String, SystemTime>> {
+        let mut file = File::open(&self.path)?;
+        file.lock_shared()?;
+        read_values(&mut file)
+    }
+
+    fn update(&self, f: impl FnOnce(&mut HashMap<String, SystemTime>)) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)?;
+        file.lock()?;
+
+        let mut values = read_values(&mut file)?;
+        f(&mut values);
+
+        let mut contents = String::new();
+        for (key, until) in values {
+            if let Ok(since_epoch) = until.duration_since(SystemTime::UNIX_EPOCH) {
+                contents += &format!("{} {key}\n", since_epoch.as_millis());
+            }
+        }
+        file.rewind()?;
+        file.set_len(0)?;
+        file.write_all(contents.as_bytes())
+    }
+}
+
+/// Runs `f` on tokio's blocking thread pool, if there is a runtime to spawn it on.
+#[cfg(not(target_arch = "wasm32"))]
+pub(crate) async fn unblock<T: Send + 'static>(
+    f: impl FnOnce() -> T + Send + 'static,
+) -> Option<T> {
+    match tokio::runtime::Handle::try_current() {
+        Ok(runtime) => runtime.spawn_blocking(f).await.ok(),
+        Err(_) => Some(f()),
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+pub(crate) async fn unblock<T>(f: impl FnOnce() -> T) -> Option<T> {
+    Some(f())
+}
+
+fn read_values(file: &mut File) -> io::Result<HashMap<String, SystemTime>> {
+    let mut contents = String::new();
+    file.read_to_string(&mut contents)?;
+    let values = contents.lines().filter_map(|line| {
+        let (millis, key) = line.split_once(' ')?;
+        let until = SystemTime::UNIX_EPOCH + Duration::from_millis(millis.parse().ok()?);
+        Some((key.to_owned(), until))
+    });
+    Ok(values.collect())
+}
+
+#[async_trait::async_trait]
+impl RetryAfterStore for FileStore {
+    async fn get(&self, key: &str) -> Option<SystemTime> {
+        self.read_values().await?.get(key).copied()
+    }
+
+    async fn set(&self, key: &str, until: SystemTime) {
+        // a key with a line break would corrupt the file
+        if !key.contains('\n') {
+            let key = key.to_owned();
+            self.update_values(move |values| {
+                values.insert(key, until);
+            })
+            .await;
+        }
+    }
+
+    async fn remove(&self, key: &str) {
+        let key = key.to_owned();
+        self.update_values(move |values| {
+            values.remove(&key);
+        })
+        .await;
+    }
+
+    async fn purge(&self, now: SystemTime) {
+        self.update_values(move |values| values.retain(|_, until| *until > now))
+            .await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs::File,
+        time::{Duration, SystemTime},
+    };
+
+    use super::FileStore;
+    use crate::store::RetryAfterStore;
+
+    #[tokio::test]
+    async fn file_store() {
+        let path = std::env::temp_dir().join(format!("retry-after-{}", std::process::id()));
+        let (store, other) = (FileStore::new(&path), FileStore::new(&path));
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        assert_eq!(store.get("a").await, None);
+
+        store.set("a", now - Duration::from_secs(1)).await;
+        store
+            .set("api.example/v1 items", now + Duration::from_secs(1))
+            .await;
+        assert_eq!(
+            other.get("api.example/v1 items").await,
+            Some(now + Duration::from_secs(1))
+        );
+
+        other.purge(now).await;
+        assert_eq!(store.get("a").await, None);
+        store.remove("api.example/v1 items").await;
+        assert_eq!(other.get("api.example/v1 items").await, None);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn locked_file() {
+        let path = std::env::temp_dir().join(format!("retry-after-lock-{}", std::process::id()));
+        let store = FileStore::new(&path);
+        let until = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        store.set("a", until).await;
+
+        // another process holding the lock must not stall this single-threaded runtime
+        let held = File::open(&path).unwrap();
+        held.lock().unwrap();
+        let get = tokio::spawn(async move { store.get("a").await });
+        tokio::task::yield_now().await;
+        assert!(!get.is_finished());
+
+        held.unlock().unwrap();
+        assert_eq!(get.await.unwrap(), Some(until));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,91 @@
+use std::time::{Duration, SystemTime};
+
+use redis::{aio::ConnectionManager, Client, RedisResult};
+
+use super::RetryAfterStore;
+
+/// A [`RetryAfterStore`] backed by Redis, for fleets of workers sharing one API quota.
+///
+/// Every value is stored as the number of milliseconds since the Unix epoch, under the key
+/// prefixed with `retry-after:`. Redis expires values on its own once they pass, so
+/// [`purge`](RetryAfterStore::purge) does nothing. Errors talking to Redis are ignored, so
+/// that an unavailable server only costs the sharing; the connection is re-established in
+/// the background. Requires Redis 6.2 or later and the `redis` feature.
+///
+/// ```no_run
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> redis::RedisResult<()> {
+/// use reqwest_retry_after::{store::RedisStore, RetryAfterMiddleware};
+///
+/// let store = RedisStore::connect("redis://127.0.0.1/").await?.with_prefix("my-app:");
+/// let middleware = RetryAfterMiddleware::builder().with_store(store).build();
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct RedisStore {
+    connection: ConnectionManager,
+    prefix: String,
+}
+
+impl RedisStore {
+    /// Connects to the Redis server at `url`.
+    pub async fn connect(url: &str) -> RedisResult<Self> {
+        let connection = Client::open(url)?.get_connection_manager().await?;
+        Ok(Self::new(connection))
+    }
+
+    /// Creates a store over an existing connection.
+    pub fn new(connection: ConnectionManager) -> Self {
+        Self {
+            connection,
+            prefix: "retry-after:".to_owned(),
+        }
+    }
+
+    /// Prefixes keys with `prefix` instead of `retry-after:`, to keep apart applications
+    /// sharing a server.
+    pub fn with_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.prefix = prefix.into();
+        self
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{key}", self.prefix)
+    }
+}
+
+#[async_trait::async_trait]
+impl RetryAfterStore for RedisStore {
+    async fn get(&self, key: &str) -> Option<SystemTime> {
+        let millis: Option<u64> = redis::cmd("GET")
+            .arg(self.key(key))
+            .query_async(&mut self.connection.clone())
+            .await
+            .ok()?;
+        Some(SystemTime::UNIX_EPOCH + Duration::from_millis(millis?))
+    }
+
+    async fn set(&self, key: &str, until: SystemTime) {
+        let Ok(millis) = until.duration_since(SystemTime::UNIX_EPOCH) else {
+            return;
+        };
+        let millis = u64::try_from(millis.as_millis()).unwrap_or(u64::MAX);
+        let _: RedisResult<()> = redis::cmd("SET")
+            .arg(self.key(key))
+            .arg(millis)
+            .arg("PXAT")
+            .arg(millis)
+            .query_async(&mut self.connection.clone())
+            .await;
+    }
+
+    async fn remove(&self, key: &str) {
+        let _: RedisResult<()> = redis::cmd("DEL")
+            .arg(self.key(key))
+            .query_async(&mut self.connection.clone())
+            .await;
+    }
+
+    async fn purge(&self, _: SystemTime) {}
+}
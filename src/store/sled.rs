@@ -0,0 +1,103 @@
+use std::{
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+use sled::{Db, Tree};
+
+use super::RetryAfterStore;
+
+/// A [`RetryAfterStore`] backed by an embedded sled database, for durable state without an
+/// external service.
+///
+/// CLIs and desktop apps that are restarted often, possibly in the middle of a `Retry-After`,
+/// pick up where they left off. Every value is stored as the number of milliseconds since the
+/// Unix epoch, in big-endian order. Errors are ignored, so that a broken database only costs
+/// the durability. Requires the `sled` feature.
+///
+/// ```no_run
+/// use reqwest_retry_after::{store::SledStore, RetryAfterMiddleware};
+///
+/// let store = SledStore::open("/var/lib/my-app/retry-after").unwrap();
+/// let middleware = RetryAfterMiddleware::builder().with_store(store).build();
+/// ```
+#[derive(Debug, Clone)]
+pub struct SledStore {
+    tree: Tree,
+}
+
+impl SledStore {
+    /// Opens, or creates, the database at `path`, and stores values in its default tree.
+    pub fn open(path: impl AsRef<Path>) -> sled::Result<Self> {
+        let db = sled::open(path)?;
+        Ok(Self::new(&db))
+    }
+
+    /// Creates a store over the default tree of an open database.
+    pub fn new(db: &Db) -> Self {
+        Self::with_tree((**db).clone())
+    }
+
+    /// Creates a store over `tree`, to keep values apart from the rest of a database.
+    pub fn with_tree(tree: Tree) -> Self {
+        Self { tree }
+    }
+}
+
+fn decode(bytes: &[u8]) -> Option<SystemTime> {
+    let millis = u64::from_be_bytes(bytes.try_into().ok()?);
+    Some(SystemTime::UNIX_EPOCH + Duration::from_millis(millis))
+}
+
+#[async_trait::async_trait]
+impl RetryAfterStore for SledStore {
+    async fn get(&self, key: &str) -> Option<SystemTime> {
+        decode(&self.tree.get(key).ok()??)
+    }
+
+    async fn set(&self, key: &str, until: SystemTime) {
+        let Ok(millis) = until.duration_since(SystemTime::UNIX_EPOCH) else {
+            return;
+        };
+        let millis = u64::try_from(millis.as_millis()).unwrap_or(u64::MAX);
+        let _ = self.tree.insert(key, &millis.to_be_bytes());
+    }
+
+    async fn remove(&self, key: &str) {
+        let _ = self.tree.remove(key);
+    }
+
+    async fn purge(&self, now: SystemTime) {
+        for (key, val) in self.tree.iter().flatten() {
+            if decode(&val).is_none_or(|until| until <= now) {
+                let _ = self.tree.remove(key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use super::SledStore;
+    use crate::store::RetryAfterStore;
+
+    #[tokio::test]
+    async fn sled_store() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let store = SledStore::new(&db);
+        let now = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        store.set("a", now - Duration::from_secs(1)).await;
+        store.set("b", now + Duration::from_millis(1500)).await;
+        assert_eq!(
+            store.get("b").await,
+            Some(now + Duration::from_millis(1500))
+        );
+
+        store.purge(now).await;
+        assert_eq!(store.get("a").await, None);
+        store.remove("b").await;
+        assert_eq!(store.get("b").await, None);
+    }
+}
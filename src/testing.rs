@@ -0,0 +1,133 @@
+//! Deterministic [`Clock`] and [`Sleeper`] implementations for tests.
+//!
+//! Pair a [`ManualClock`] with an [`InstantSleeper`] to exercise waiting logic without
+//! actually waiting:
+//!
+//! ```
+//! use reqwest_middleware::ClientBuilder;
+//! use reqwest_retry_after::{
+//!     testing::{InstantSleeper, ManualClock},
+//!     RetryAfterMiddleware,
+//! };
+//!
+//! let clock = ManualClock::new();
+//! let sleeper = InstantSleeper::advancing(&clock);
+//!
+//! let client = ClientBuilder::new(reqwest::Client::new())
+//!     .with(
+//!         RetryAfterMiddleware::builder()
+//!             .with_clock(clock.clone())
+//!             .with_sleeper(sleeper.clone())
+//!             .build(),
+//!     )
+//!     .build();
+//! ```
+//!
+//! Test suites running with tokio's time paused can use
+//! [`TokioClock`](crate::clock::TokioClock) instead, to skip through waits along with the
+//! rest of their timers.
+//!
+//! With the `mock-server` feature, `ThrottlingServer` simulates a rate-limited API to run
+//! clients against end to end.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
+};
+
+use crate::clock::{self, Clock, Sleeper};
+
+#[cfg(feature = "mock-server")]
+mod server;
+#[cfg(feature = "mock-server")]
+pub use server::{RetryAfterFormat, ThrottlingServer, ThrottlingServerBuilder};
+
+/// A [`Clock`] whose time only moves when told to.
+///
+/// Clones share the same time, so a clone can be handed to the middleware while the test keeps
+/// another to advance it.
+#[derive(Debug, Clone)]
+pub struct ManualClock {
+    now: Arc<Mutex<SystemTime>>,
+}
+
+impl ManualClock {
+    /// Creates a `ManualClock` frozen at the current wall-clock time.
+    pub fn new() -> Self {
+        Self::starting_at(clock::wall_clock())
+    }
+
+    /// Creates a `ManualClock` frozen at `time`.
+    pub fn starting_at(time: SystemTime) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(time)),
+        }
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+
+    /// Sets the clock to `time`.
+    pub fn set(&self, time: SystemTime) {
+        *self.now.lock().unwrap() = time;
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+}
+
+/// A [`Sleeper`] that returns immediately and records every requested sleep.
+///
+/// When created with [`InstantSleeper::advancing`], each sleep also advances the given
+/// [`ManualClock`], so the middleware observes time passing exactly as it would in production.
+#[derive(Debug, Clone, Default)]
+pub struct InstantSleeper {
+    clock: Option<ManualClock>,
+    sleeps: Arc<Mutex<Vec<Duration>>>,
+}
+
+impl InstantSleeper {
+    /// Creates an `InstantSleeper` that only records sleeps.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates an `InstantSleeper` that advances `clock` by each requested duration.
+    pub fn advancing(clock: &ManualClock) -> Self {
+        Self {
+            clock: Some(clock.clone()),
+            sleeps: Default::default(),
+        }
+    }
+
+    /// Returns every duration that was slept, in order.
+    pub fn sleeps(&self) -> Vec<Duration> {
+        self.sleeps.lock().unwrap().clone()
+    }
+
+    /// Returns the sum of every duration that was slept.
+    pub fn total(&self) -> Duration {
+        self.sleeps.lock().unwrap().iter().sum()
+    }
+}
+
+#[async_trait::async_trait]
+impl Sleeper for InstantSleeper {
+    async fn sleep(&self, duration: Duration) {
+        self.sleeps.lock().unwrap().push(duration);
+        if let Some(clock) = &self.clock {
+            clock.advance(duration);
+        }
+    }
+}
@@ -0,0 +1,314 @@
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+
+use http::StatusCode;
+use time::OffsetDateTime;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinHandle,
+};
+
+use crate::clock::{Clock, SystemClock};
+
+/// How a [`ThrottlingServer`] writes its `Retry-After` values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RetryAfterFormat {
+    /// The number of seconds until the window resets, like `Retry-After: 30`.
+    Seconds,
+    /// The instant the window resets, like `Retry-After: Thu, 01 Jan 1970 00:00:30 GMT`.
+    HttpDate,
+}
+
+/// Configures a [`ThrottlingServer`], created with [`ThrottlingServer::builder`].
+pub struct ThrottlingServerBuilder {
+    allow: u64,
+    window: Duration,
+    status: StatusCode,
+    format: RetryAfterFormat,
+    clock: Arc<dyn Clock>,
+}
+
+impl ThrottlingServerBuilder {
+    /// Accepts `requests` requests per window before throttling. Defaults to 1.
+    pub fn allowing(mut self, requests: u64) -> Self {
+        self.allow = requests;
+        self
+    }
+
+    /// Sets the length of a window. Defaults to one second.
+    pub fn per(mut self, window: Duration) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Throttles with `status` instead of `429 Too Many Requests`.
+    pub fn with_status(mut self, status: StatusCode) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Writes `Retry-After` values in `format`. Defaults to [`RetryAfterFormat::Seconds`].
+    pub fn with_format(mut self, format: RetryAfterFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    /// Uses `clock` to track windows, instead of [`SystemClock`].
+    ///
+    /// Sharing a [`ManualClock`](super::ManualClock) with the middleware keeps the server and
+    /// the client in step without any real waiting.
+    pub fn with_clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Starts the server on a free port of the loopback interface.
+    ///
+    /// The server runs on the current tokio runtime until it is dropped.
+    pub async fn start(self) -> io::Result<ThrottlingServer> {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).await?;
+        let addr = listener.local_addr()?;
+
+        let state = Arc::new(ServerState {
+            config: self,
+            window_start: Mutex::new(None),
+            requests: AtomicU64::new(0),
+            throttled: AtomicU64::new(0),
+        });
+        let task = tokio::spawn({
+            let state = state.clone();
+            async move {
+                while let Ok((stream, _)) = listener.accept().await {
+                    tokio::spawn(state.clone().serve(stream));
+                }
+            }
+        });
+
+        Ok(ThrottlingServer { addr, state, task })
+    }
+}
+
+/// A local HTTP server that throttles like a real API, for end-to-end tests of throttling
+/// handling.
+///
+/// The server accepts a fixed number of requests per window, on any path. Every further
+/// request in the window gets a `429 Too Many Requests` with a `Retry-After` pointing at the
+/// end of the window.
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> std::io::Result<()> {
+/// use std::time::Duration;
+/// use reqwest_retry_after::testing::ThrottlingServer;
+///
+/// let server = ThrottlingServer::builder()
+///     .allowing(10)
+///     .per(Duration::from_secs(60))
+///     .start()
+///     .await?;
+///
+/// let res = reqwest::get(server.url("/items")).await.unwrap();
+/// assert!(res.status().is_success());
+/// # Ok(())
+/// # }
+/// ```
+pub struct ThrottlingServer {
+    addr: SocketAddr,
+    state: Arc<ServerState>,
+    task: JoinHandle<()>,
+}
+
+impl ThrottlingServer {
+    /// Returns a builder for a server that accepts one request per second.
+    pub fn builder() -> ThrottlingServerBuilder {
+        ThrottlingServerBuilder {
+            allow: 1,
+            window: Duration::from_secs(1),
+            status: StatusCode::TOO_MANY_REQUESTS,
+            format: RetryAfterFormat::Seconds,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Returns the URL of `path` on this server.
+    pub fn url(&self, path: &str) -> String {
+        format!("http://{}{path}", self.addr)
+    }
+
+    /// Returns the number of requests received so far.
+    pub fn requests(&self) -> u64 {
+        self.state.requests.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of requests that were throttled so far.
+    pub fn throttled(&self) -> u64 {
+        self.state.throttled.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for ThrottlingServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+struct ServerState {
+    config: ThrottlingServerBuilder,
+    window_start: Mutex<Option<(SystemTime, u64)>>,
+    requests: AtomicU64,
+    throttled: AtomicU64,
+}
+
+impl ServerState {
+    async fn serve(self: Arc<Self>, mut stream: TcpStream) {
+        // only the request head matters, so any body is left unread
+        let mut head = Vec::new();
+        let mut buf = [0; 1024];
+        while !head.windows(4).any(|window| window == b"\r\n\r\n") {
+            match stream.read(&mut buf).await {
+                Ok(0) | Err(_) => return,
+                Ok(len) => head.extend_from_slice(&buf[..len]),
+            }
+        }
+
+        let response = self.respond();
+        let _ = stream.write_all(response.as_bytes()).await;
+        let _ = stream.shutdown().await;
+    }
+
+    fn respond(&self) -> String {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        let config = &self.config;
+        let now = config.clock.now();
+
+        let reset = {
+            let mut window = self.window_start.lock().unwrap();
+            let (start, count) = match *window {
+                Some((start, count)) if now < start + config.window => (start, count + 1),
+                _ => (now, 1),
+            };
+            *window = Some((start, count));
+            (count > config.allow).then_some(start + config.window)
+        };
+
+        let Some(reset) = reset else {
+            return response(StatusCode::OK, "");
+        };
+        self.throttled.fetch_add(1, Ordering::Relaxed);
+
+        let retry_after = match config.format {
+            RetryAfterFormat::Seconds => {
+                let remaining = reset.duration_since(now).unwrap_or_default();
+                // round up, so clients never come back before the window resets
+                let secs = remaining.as_secs() + u64::from(remaining.subsec_nanos() > 0);
+                secs.to_string()
+            }
+            RetryAfterFormat::HttpDate => http_date(reset),
+        };
+        response(config.status, &format!("retry-after: {retry_after}\r\n"))
+    }
+}
+
+fn response(status: StatusCode, headers: &str) -> String {
+    format!(
+        "HTTP/1.1 {} {}\r\ncontent-length: 0\r\nconnection: close\r\n{headers}\r\n",
+        status.as_str(),
+        status.canonical_reason().unwrap_or_default()
+    )
+}
+
+fn http_date(time: SystemTime) -> String {
+    // round up to the next second, which is all an HTTP date can express
+    let time = OffsetDateTime::from(time);
+    let time = match time.nanosecond() {
+        0 => time,
+        nanos => time + Duration::from_nanos(u64::from(1_000_000_000 - nanos)),
+    };
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        &time.weekday().to_string()[..3],
+        time.day(),
+        &time.month().to_string()[..3],
+        time.year(),
+        time.hour(),
+        time.minute(),
+        time.second()
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, SystemTime};
+
+    use super::{http_date, RetryAfterFormat, ThrottlingServer};
+    use crate::{
+        testing::{InstantSleeper, ManualClock},
+        RetryAfterMiddleware,
+    };
+    use reqwest_middleware::ClientBuilder;
+
+    #[test]
+    fn format_http_date() {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_millis(86_400_500);
+        assert_eq!(http_date(time), "Fri, 02 Jan 1970 00:00:01 GMT");
+    }
+
+    #[tokio::test]
+    async fn throttles() {
+        let clock = ManualClock::new();
+        let server = ThrottlingServer::builder()
+            .allowing(2)
+            .per(Duration::from_secs(30))
+            .with_clock(clock.clone())
+            .start()
+            .await
+            .unwrap();
+
+        for status in [200, 200, 429] {
+            let res = reqwest::get(server.url("/")).await.unwrap();
+            assert_eq!(res.status(), status);
+        }
+        clock.advance(Duration::from_secs(10));
+        let res = reqwest::get(server.url("/")).await.unwrap();
+        assert_eq!(res.headers()["retry-after"], "20");
+
+        clock.advance(Duration::from_secs(20));
+        let res = reqwest::get(server.url("/")).await.unwrap();
+        assert_eq!(res.status(), 200);
+        assert_eq!((server.requests(), server.throttled()), (5, 2));
+    }
+
+    #[tokio::test]
+    async fn with_middleware() {
+        let clock = ManualClock::starting_at(SystemTime::UNIX_EPOCH + Duration::from_secs(1 << 30));
+        let sleeper = InstantSleeper::advancing(&clock);
+        let server = ThrottlingServer::builder()
+            .with_format(RetryAfterFormat::HttpDate)
+            .with_clock(clock.clone())
+            .start()
+            .await
+            .unwrap();
+        let client = ClientBuilder::new(reqwest::Client::new())
+            .with(
+                RetryAfterMiddleware::builder()
+                    .with_clock(clock.clone())
+                    .with_sleeper(sleeper.clone())
+                    .build(),
+            )
+            .build();
+
+        for status in [200, 429, 200] {
+            let res = client.get(server.url("/")).send().await.unwrap();
+            assert_eq!(res.status(), status);
+        }
+        assert_eq!(sleeper.sleeps(), vec![Duration::from_secs(1)]);
+    }
+}
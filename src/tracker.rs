@@ -0,0 +1,538 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use http::{header::RETRY_AFTER, HeaderMap, HeaderName, StatusCode};
+use reqwest::{Request, Url};
+
+use crate::{
+    clock::{Clock, SystemClock},
+    parse::{self, parse_retry_value},
+    rate_limit::{RateLimitInfo, RateLimitSource},
+    stats::RetryAfterEvent,
+    Backoff, ConfigHandle, ExcessiveDelay, HeaderFormat, Jitter, KeyExtractor, OnConflict,
+    OnExcessiveDelay, OnMultipleFields, OnViolation, RetryAfterViolation, Scope, State,
+    StatusFilter, ViolationKind,
+};
+
+/// The bookkeeping of a [`RetryAfterMiddleware`](crate::RetryAfterMiddleware), without
+/// sending or waiting, for custom clients.
+///
+/// A tracker reads `Retry-After`, and the other headers the middleware honors, off the
+/// responses it is shown, and tells how long requests have to wait, leaving it to the caller
+/// to wait and to send them. The middleware and the
+/// [blocking client](crate::blocking::RetryAfterClient) do their bookkeeping with one too; see
+/// [`RetryAfterMiddleware::tracker`](crate::RetryAfterMiddleware::tracker) to get theirs, which
+/// shares its configuration and state.
+///
+/// A tracker goes by keys, not URLs, so the per-host statistics, the event history and the
+/// callbacks, which are about URLs, are left to the middleware, as are stores, coordinators,
+/// detectors, observers, the sleeper, retries, recovery probes and dry-run mode.
+///
+/// ```
+/// use std::time::{Duration, SystemTime};
+/// use http::{header::RETRY_AFTER, HeaderMap, StatusCode};
+/// use reqwest_retry_after::RetryAfterTracker;
+///
+/// let tracker = RetryAfterTracker::new();
+/// let url = "https://api.example.com/items".parse().unwrap();
+/// let key = tracker.key(&url);
+///
+/// let mut headers = HeaderMap::new();
+/// headers.insert(RETRY_AFTER, "30".parse().unwrap());
+/// let now = SystemTime::now();
+/// tracker.observe(&key, StatusCode::TOO_MANY_REQUESTS, &headers, now).unwrap();
+///
+/// let delay = tracker.delay_for(&key, now);
+/// assert_eq!(delay, Some(Duration::from_secs(30)));
+/// ```
+#[derive(Clone)]
+pub struct RetryAfterTracker {
+    pub(crate) state: Arc<State>,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) keys: Arc<dyn KeyExtractor>,
+    pub(crate) strict: Option<OnViolation>,
+    pub(crate) on_conflict: OnConflict,
+    pub(crate) on_multiple_fields: OnMultipleFields,
+    pub(crate) config: ConfigHandle,
+    pub(crate) fallback: Option<Backoff>,
+    pub(crate) retry_after_statuses: Option<StatusFilter>,
+    pub(crate) rate_limit_sources: Vec<RateLimitSource>,
+    pub(crate) retry_after_headers: Vec<(HeaderName, HeaderFormat)>,
+    pub(crate) throttle_statuses: Vec<StatusCode>,
+    pub(crate) safety_margin: Duration,
+    pub(crate) delay_factor: f64,
+    pub(crate) max_ttl: Option<Duration>,
+    pub(crate) max_entries: Option<usize>,
+}
+
+/// What a response means for the value stored for its key, as far as its headers tell.
+pub(crate) enum Verdict<'a> {
+    /// Store the value, or clear the stored one, read from the named source.
+    Record(Option<SystemTime>, &'a str),
+    /// Clear the stored value, as a `Retry-After` that has passed would.
+    Clear,
+    /// Leave the stored value as it is.
+    Keep,
+    /// Fail the request.
+    Reject(RetryAfterViolation),
+    /// Nothing in the headers, so body detection and the fallback decide.
+    Undecided,
+}
+
+/// What recording a value did to the one stored for a key.
+pub(crate) enum Recorded<'a> {
+    /// The value was stored, as read from the named source.
+    Stored(SystemTime, &'a str),
+    Cleared,
+    Unchanged,
+}
+
+impl Default for RetryAfterTracker {
+    fn default() -> Self {
+        Self::from_state(Arc::default())
+    }
+}
+
+impl RetryAfterTracker {
+    /// Creates a tracker with the default configuration, and a state of its own.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn from_state(state: Arc<State>) -> Self {
+        Self {
+            state,
+            clock: Arc::new(SystemClock),
+            keys: Arc::new(Scope::default()),
+            strict: None,
+            on_conflict: OnConflict::KeepLatest,
+            on_multiple_fields: OnMultipleFields::Longest,
+            config: ConfigHandle::default(),
+            fallback: None,
+            retry_after_statuses: None,
+            rate_limit_sources: Vec::new(),
+            retry_after_headers: Vec::new(),
+            throttle_statuses: Vec::new(),
+            safety_margin: Duration::ZERO,
+            delay_factor: 1.0,
+            max_ttl: None,
+            max_entries: None,
+        }
+    }
+
+    /// Returns the key a `GET` request to `url` is stored under, see
+    /// [`RetryAfterMiddlewareBuilder::with_scope`](crate::RetryAfterMiddlewareBuilder::with_scope).
+    pub fn key(&self, url: &Url) -> String {
+        self.keys.key_for_url(url)
+    }
+
+    /// Returns the key `req` is stored under, for
+    /// [key extractors](crate::RetryAfterMiddlewareBuilder::with_key_extractor) that look at
+    /// more than its URL.
+    pub fn key_for(&self, req: &Request) -> String {
+        crate::request_key(&*self.keys, req)
+    }
+
+    /// Records what a response with `status` and `headers`, received at `now`, says about
+    /// requests with `key`, storing a value for them or clearing the stored one.
+    ///
+    /// Fails if the response breaks [strict parsing] set to fail.
+    ///
+    /// [strict parsing]: crate::RetryAfterMiddlewareBuilder::with_strict_validation
+    pub fn observe(
+        &self,
+        key: &str,
+        status: StatusCode,
+        headers: &HeaderMap,
+        now: SystemTime,
+    ) -> Result<(), RetryAfterViolation> {
+        self.observe_headers(key, status, headers, now, |_| {})
+            .map(drop)
+    }
+
+    /// Returns how long a request with `key` still has to wait at `now`, if at all.
+    pub fn delay_for(&self, key: &str, now: SystemTime) -> Option<Duration> {
+        self.state
+            .until(key)?
+            .duration_since(now)
+            .ok()
+            .filter(|delay| !delay.is_zero())
+    }
+
+    /// Returns how long the next request with `key` has to wait at `now` before being sent,
+    /// after jitter and within the configured bound.
+    ///
+    /// Fails if the wait is longer than a bound set to fail.
+    pub fn next_wait(
+        &self,
+        key: &str,
+        now: SystemTime,
+    ) -> Result<Option<Duration>, ExcessiveDelay> {
+        Ok(self
+            .bounded_wait(key, now, None)?
+            .map(|(duration, _)| duration))
+    }
+
+    /// Records what a response says about requests with `key`, without looking at its body,
+    /// telling `violated` of any breach of strict parsing.
+    pub(crate) fn observe_headers(
+        &self,
+        key: &str,
+        status: StatusCode,
+        headers: &HeaderMap,
+        now: SystemTime,
+        violated: impl FnOnce(&RetryAfterViolation),
+    ) -> Result<Recorded<'_>, RetryAfterViolation> {
+        let (retry_after, source) = match self.verdict(status, headers, now, violated) {
+            Verdict::Record(retry_after, source) => (retry_after, source),
+            Verdict::Clear => (None, "Retry-After"),
+            Verdict::Keep => return Ok(Recorded::Unchanged),
+            Verdict::Reject(violation) => return Err(violation),
+            Verdict::Undecided => {
+                let delay = self.fallback_delay(key, status);
+                (delay.map(|delay| now + delay), "fallback")
+            }
+        };
+        Ok(self.record_locally(key, retry_after, source, now))
+    }
+
+    /// Decides what a response with `status` and `headers`, received at `now`, means for the
+    /// value stored for its key, as far as its headers tell, telling `violated` of any breach
+    /// of strict parsing.
+    pub(crate) fn verdict(
+        &self,
+        status: StatusCode,
+        headers: &HeaderMap,
+        now: SystemTime,
+        violated: impl FnOnce(&RetryAfterViolation),
+    ) -> Verdict<'_> {
+        let honored = match &self.retry_after_statuses {
+            Some(filter) => filter(status),
+            None => self.is_throttling(status),
+        };
+
+        if let (Some(policy), true) = (self.strict, honored) {
+            if let Some(kind) = parse::validate_retry_after(headers, now) {
+                let value = &headers[RETRY_AFTER];
+                let violation = RetryAfterViolation {
+                    kind,
+                    value: String::from_utf8_lossy(value.as_bytes()).into_owned(),
+                };
+                violated(&violation);
+
+                match policy {
+                    OnViolation::Ignore => return Verdict::Keep,
+                    OnViolation::Error => return Verdict::Reject(violation),
+                    // a delay clamped to zero throttles nothing
+                    OnViolation::Clamp if kind == ViolationKind::Negative => return Verdict::Clear,
+                    OnViolation::Clamp => {}
+                }
+            }
+        }
+
+        // configured headers come first, and milliseconds are more precise than a
+        // `Retry-After` the server may also send
+        let preferred = honored
+            .then(|| {
+                self.custom_retry_after(headers, now).or_else(|| {
+                    let retry_after = parse::parse_retry_after_ms(headers, now);
+                    retry_after.map(|retry_after| (retry_after, "Retry-After-Ms"))
+                })
+            })
+            .flatten();
+        if let Some((retry_after, source)) = preferred {
+            return Verdict::Record(Some(retry_after), source);
+        }
+
+        if honored && headers.contains_key(RETRY_AFTER) {
+            return match self.retry_after(headers, now) {
+                Some(retry_after) => Verdict::Record(retry_after, "Retry-After"),
+                None => Verdict::Keep,
+            };
+        }
+        match self.rate_limit_reset(headers, now) {
+            Some((reset, source)) => Verdict::Record(Some(reset), source),
+            None => Verdict::Undecided,
+        }
+    }
+
+    /// Returns the `Retry-After` of a response with `headers`, combining several fields as
+    /// configured, or `None` if no field that should be read is text.
+    fn retry_after(&self, headers: &HeaderMap, now: SystemTime) -> Option<Option<SystemTime>> {
+        if self.on_multiple_fields == OnMultipleFields::First {
+            let val = headers.get(RETRY_AFTER)?.to_str().ok()?;
+            return Some(parse_retry_value(val, now));
+        }
+
+        let mut values = headers
+            .get_all(RETRY_AFTER)
+            .iter()
+            .filter_map(|val| val.to_str().ok())
+            .map(|val| parse_retry_value(val, now));
+        let first = values.next()?;
+        // fields that can't be parsed only count when no other can
+        Some(
+            values.fold(first, |combined, value| match (combined, value) {
+                (Some(combined), Some(value)) => {
+                    Some(self.on_multiple_fields.combine(combined, value))
+                }
+                (combined, value) => combined.or(value),
+            }),
+        )
+    }
+
+    /// Returns the value of the first header configured with [`with_retry_after_header`] that
+    /// can be parsed.
+    ///
+    /// [`with_retry_after_header`]: crate::RetryAfterMiddlewareBuilder::with_retry_after_header
+    fn custom_retry_after(
+        &self,
+        headers: &HeaderMap,
+        now: SystemTime,
+    ) -> Option<(SystemTime, &str)> {
+        self.retry_after_headers.iter().find_map(|(name, format)| {
+            let val = headers.get(name)?.to_str().ok()?;
+            Some((format.parse(val.trim(), now)?, name.as_str()))
+        })
+    }
+
+    /// Returns when the exhausted quota of a response with `headers` resets, if it says so in
+    /// headers the middleware was told to honor, along with the header saying so.
+    fn rate_limit_reset(
+        &self,
+        headers: &HeaderMap,
+        now: SystemTime,
+    ) -> Option<(SystemTime, &'static str)> {
+        if self.rate_limit_sources.is_empty() {
+            return None;
+        }
+        let info = RateLimitInfo::from_headers(headers, now)?;
+        if !self.rate_limit_sources.contains(&info.source) || info.remaining != Some(0) {
+            return None;
+        }
+        let source = match info.source {
+            RateLimitSource::XRateLimit => "X-RateLimit-Reset",
+            _ => "RateLimit-Reset",
+        };
+        Some((info.reset?, source))
+    }
+
+    /// Returns how long a request with `key` has to wait at `now`, after jitter and within
+    /// the configured bound, along with the bound it was truncated to, if any.
+    ///
+    /// A `max` taken from the request takes the place of the configured bound, and truncates
+    /// unless the configured policy is to fail.
+    pub(crate) fn bounded_wait(
+        &self,
+        key: &str,
+        now: SystemTime,
+        max: Option<Duration>,
+    ) -> Result<Option<(Duration, Option<Duration>)>, ExcessiveDelay> {
+        let Some(mut duration) = self
+            .state
+            .until(key)
+            .and_then(|retry_after| retry_after.duration_since(now).ok())
+        else {
+            return Ok(None);
+        };
+
+        let bound = match (max, self.config.max_wait()) {
+            (Some(max), Some((_, OnExcessiveDelay::Error))) => Some((max, OnExcessiveDelay::Error)),
+            (Some(max), _) => Some((max, OnExcessiveDelay::Truncate)),
+            (None, bound) => bound,
+        };
+        let max = match bound {
+            Some((max, OnExcessiveDelay::Error)) if duration > max => {
+                return Err(ExcessiveDelay {
+                    delay: duration,
+                    max,
+                });
+            }
+            Some((max, OnExcessiveDelay::Truncate)) => Some(max),
+            _ => None,
+        };
+        duration = self.jitter(key, duration);
+        if let Some(max) = max {
+            duration = duration.min(max);
+        }
+        Ok(Some((duration, max)))
+    }
+
+    pub(crate) fn is_throttling(&self, status: StatusCode) -> bool {
+        status == StatusCode::TOO_MANY_REQUESTS
+            || status == StatusCode::SERVICE_UNAVAILABLE
+            || self.throttle_statuses.contains(&status)
+    }
+
+    /// Returns the fallback delay for a response with `status` and no `Retry-After`, counting
+    /// the attempt against `key`.
+    pub(crate) fn fallback_delay(&self, key: &str, status: StatusCode) -> Option<Duration> {
+        let backoff = self.fallback.as_ref()?;
+        let mut attempts = self.state.attempts.lock().unwrap();
+        if !self.is_throttling(status) {
+            attempts.remove(key);
+            return None;
+        }
+
+        let attempt = attempts.entry(key.to_owned()).or_default();
+        let delay = backoff.delay(*attempt);
+        *attempt = attempt.saturating_add(1);
+        Some(delay)
+    }
+
+    /// Applies the jitter to a wait of `delay` for `key`.
+    pub(crate) fn jitter(&self, key: &str, delay: Duration) -> Duration {
+        let jitter = self.config.jitter();
+        if jitter == Jitter::None {
+            return delay;
+        }
+        let mut jittered = self.state.jittered.lock().unwrap();
+        let delay = jitter.apply(delay, jittered.get(key).copied());
+        jittered.insert(key.to_owned(), delay);
+        delay
+    }
+
+    /// Stretches the delay from `now` until `retry_after` by the delay factor and safety
+    /// margin.
+    fn pad(&self, retry_after: SystemTime, now: SystemTime) -> SystemTime {
+        let delay = retry_after.duration_since(now).unwrap_or_default();
+        now + delay.mul_f64(self.delay_factor) + self.safety_margin
+    }
+
+    pub(crate) fn clamp_ttl(&self, retry_after: SystemTime, now: SystemTime) -> SystemTime {
+        match self.max_ttl {
+            Some(ttl) => retry_after.min(now + ttl),
+            None => retry_after,
+        }
+    }
+
+    /// Evicts values from `stored` until it holds no more than the maximum number of entries,
+    /// passed ones first and then those expiring earliest, but never the one for `keep`.
+    pub(crate) fn bound_entries(
+        &self,
+        stored: &mut HashMap<String, SystemTime>,
+        keep: Option<&str>,
+        now: SystemTime,
+    ) {
+        let Some(max) = self.max_entries else {
+            return;
+        };
+        if stored.len() <= max {
+            return;
+        }
+
+        stored.retain(|key, until| *until > now || Some(key.as_str()) == keep);
+        while stored.len() > max {
+            let earliest = stored
+                .iter()
+                .filter(|(key, _)| Some(key.as_str()) != keep)
+                .min_by_key(|(_, until)| **until)
+                .map(|(key, _)| key.clone());
+            let Some(earliest) = earliest else {
+                return;
+            };
+            stored.remove(&earliest);
+        }
+    }
+
+    /// Stores `retry_after`, read at `now` from `source`, for `key`, or clears the value
+    /// stored for it.
+    pub(crate) fn record_locally<'a>(
+        &self,
+        key: &str,
+        retry_after: Option<SystemTime>,
+        source: &'a str,
+        now: SystemTime,
+    ) -> Recorded<'a> {
+        let retry_after = retry_after.map(|time| self.clamp_ttl(self.pad(time, now), now));
+        let (retry_after, previous) = {
+            let mut stored = self.state.retry_after.lock().unwrap();
+            match retry_after {
+                Some(time) => {
+                    // a passed value is no cool-down to keep
+                    let time = match stored.get(key) {
+                        Some(&current) if current > now => self.on_conflict.resolve(current, time),
+                        _ => time,
+                    };
+                    let previous = stored.insert(key.to_owned(), time);
+                    self.bound_entries(&mut stored, Some(key), now);
+                    (Some(time), previous)
+                }
+                None => (None, stored.remove(key)),
+            }
+        };
+
+        match retry_after {
+            Some(time) => {
+                #[cfg(feature = "tracing")]
+                {
+                    let delay = time.duration_since(now).unwrap_or_default();
+                    tracing::debug!(key, source, wait = ?delay, "throttle recorded");
+                    tracing::Span::current().record("retry_after.source_header", source);
+                }
+                let _ = self.state.subscribers.send(RetryAfterEvent::Recorded {
+                    key: key.to_owned(),
+                    until: time,
+                });
+                Recorded::Stored(time, source)
+            }
+            None if previous.is_some() => {
+                #[cfg(feature = "tracing")]
+                tracing::debug!(key, "throttle cleared");
+                let _ = self.state.subscribers.send(RetryAfterEvent::Cleared {
+                    key: key.to_owned(),
+                });
+                Recorded::Cleared
+            }
+            None => Recorded::Unchanged,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use http::{header::RETRY_AFTER, HeaderMap, StatusCode};
+
+    use crate::{clock::Clock, testing::ManualClock, OnExcessiveDelay, RetryAfterMiddleware};
+
+    #[test]
+    fn tracker() {
+        let clock = ManualClock::new();
+        let middleware = RetryAfterMiddleware::builder()
+            .with_clock(clock.clone())
+            .with_max_wait(Duration::from_secs(10), OnExcessiveDelay::Truncate)
+            .build();
+        let tracker = middleware.tracker();
+        let url = "https://api.example.com/".parse().unwrap();
+        let key = tracker.key(&url);
+
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, "30".parse().unwrap());
+        tracker
+            .observe(&key, StatusCode::TOO_MANY_REQUESTS, &headers, clock.now())
+            .unwrap();
+        assert_eq!(
+            tracker.delay_for(&key, clock.now() + Duration::from_secs(20)),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            tracker.next_wait(&key, clock.now()).unwrap(),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(
+            middleware.wait_remaining(&url),
+            Some(Duration::from_secs(30))
+        );
+
+        tracker
+            .observe(&key, StatusCode::OK, &HeaderMap::new(), clock.now())
+            .unwrap();
+        assert_eq!(tracker.delay_for(&key, clock.now()), None);
+        assert_eq!(tracker.next_wait(&key, clock.now()).unwrap(), None);
+    }
+}